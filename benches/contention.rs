@@ -0,0 +1,59 @@
+//! Measures lock throughput under varying contention, with and without
+//! spinning before blocking, and with both ways of blocking.
+//!
+//! Run with `cargo bench --bench contention`.
+
+extern crate shared_mutex;
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use shared_mutex::{SharedMutex, Blocking, DEFAULT_BACKOFF_CAP};
+
+const OPERATIONS: usize = 20000;
+
+// Every thread performs OPERATIONS lock acquisitions, one in `write_every`
+// of which are writes.
+fn run(threads: usize, write_every: usize, backoff_cap: u32, blocking: Blocking) -> Duration {
+    let mut mutex = SharedMutex::new(0usize);
+    mutex.set_backoff_cap(backoff_cap);
+    mutex.set_blocking(blocking);
+    let mutex = Arc::new(mutex);
+
+    let start = Instant::now();
+
+    let handles: Vec<_> = (0..threads).map(|_| {
+        let mutex = mutex.clone();
+
+        thread::spawn(move || {
+            for i in 0..OPERATIONS {
+                if i % write_every == 0 {
+                    *mutex.write().unwrap() += 1;
+                } else {
+                    let _ = *mutex.read().unwrap();
+                }
+            }
+        })
+    }).collect();
+
+    for handle in handles { handle.join().unwrap() }
+
+    start.elapsed()
+}
+
+fn main() {
+    println!("{:>8} {:>12} {:>14} {:>14} {:>14}",
+             "threads", "write every", "no backoff", "backoff", "backoff, park");
+
+    for &threads in &[1, 2, 4, 8, 16] {
+        for &write_every in &[1, 10, 100] {
+            let blocked = run(threads, write_every, 0, Blocking::Condvar);
+            let spun = run(threads, write_every, DEFAULT_BACKOFF_CAP, Blocking::Condvar);
+            let parked = run(threads, write_every, DEFAULT_BACKOFF_CAP, Blocking::Park);
+
+            println!("{:>8} {:>12} {:>14?} {:>14?} {:>14?}",
+                     threads, write_every, blocked, spun, parked);
+        }
+    }
+}
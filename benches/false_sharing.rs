@@ -0,0 +1,59 @@
+//! Measures how much threads writing to neighbouring locks in an array slow
+//! each other down, with and without padding each lock to a cache line.
+//!
+//! Run with `cargo bench --bench false_sharing`.
+
+extern crate shared_mutex;
+
+use std::sync::Arc;
+use std::ops::Deref;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use shared_mutex::{SharedMutex, PaddedSharedMutex};
+
+const OPERATIONS: usize = 200000;
+
+// A plain SharedMutex, so that both layouts can be used through `Deref`.
+#[derive(Default)]
+struct Unpadded(SharedMutex<u64>);
+
+impl Deref for Unpadded {
+    type Target = SharedMutex<u64>;
+
+    fn deref(&self) -> &SharedMutex<u64> { &self.0 }
+}
+
+// Every thread performs OPERATIONS writes to its own lock in `locks`, so any
+// slowdown with more threads comes from the locks sharing cache lines.
+fn run<L>(threads: usize) -> Duration
+where L: Deref<Target = SharedMutex<u64>> + Default + Send + Sync + 'static {
+    let locks: Arc<Vec<L>> = Arc::new((0..threads).map(|_| L::default()).collect());
+
+    let start = Instant::now();
+
+    let handles: Vec<_> = (0..threads).map(|i| {
+        let locks = locks.clone();
+
+        thread::spawn(move || {
+            for _ in 0..OPERATIONS {
+                *locks[i].write().unwrap() += 1;
+            }
+        })
+    }).collect();
+
+    for handle in handles { handle.join().unwrap() }
+
+    start.elapsed()
+}
+
+fn main() {
+    println!("{:>8} {:>14} {:>14}", "threads", "unpadded", "padded");
+
+    for &threads in &[1, 2, 4, 8] {
+        let unpadded = run::<Unpadded>(threads);
+        let padded = run::<PaddedSharedMutex<u64>>(threads);
+
+        println!("{:>8} {:>14?} {:>14?}", threads, unpadded, padded);
+    }
+}
@@ -0,0 +1,35 @@
+//! Measures the latency of uncontended lock acquisitions, the cost paid by
+//! every lock operation regardless of contention.
+//!
+//! Run with `cargo bench --bench uncontended`.
+
+extern crate shared_mutex;
+
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use shared_mutex::SharedMutex;
+
+const OPERATIONS: u32 = 1000000;
+
+// The average time taken by `action`, which is run OPERATIONS times.
+fn per_operation<F: FnMut()>(mut action: F) -> Duration {
+    let start = Instant::now();
+    for _ in 0..OPERATIONS { action() }
+    start.elapsed() / OPERATIONS
+}
+
+fn main() {
+    let mutex = SharedMutex::new(0usize);
+    let std = RwLock::new(0usize);
+
+    println!("{:>8} {:>14} {:>14}", "", "SharedMutex", "std RwLock");
+
+    let shared = per_operation(|| { let _ = *mutex.read().unwrap(); });
+    let baseline = per_operation(|| { let _ = *std.read().unwrap(); });
+    println!("{:>8} {:>14?} {:>14?}", "read", shared, baseline);
+
+    let shared = per_operation(|| *mutex.write().unwrap() += 1);
+    let baseline = per_operation(|| *std.write().unwrap() += 1);
+    println!("{:>8} {:>14?} {:>14?}", "write", shared, baseline);
+}
@@ -0,0 +1,65 @@
+//! Traits for writing code which is generic over the lock protecting some data.
+//!
+//! `ReadAccess` and `WriteAccess` are implemented by the guards in this crate
+//! and by the guards of `std::sync::RwLock` and `std::sync::Mutex`, so a
+//! library can accept any of them without committing to a lock implementation.
+//!
+//! ```
+//! use std::sync::RwLock;
+//! use shared_mutex::SharedMutex;
+//! use shared_mutex::access::{ReadAccess, WriteAccess};
+//!
+//! fn total<G: ReadAccess<Vec<u32>>>(guard: G) -> u32 {
+//!     guard.iter().sum()
+//! }
+//!
+//! fn push<G: WriteAccess<Vec<u32>>>(mut guard: G, value: u32) {
+//!     guard.push(value)
+//! }
+//!
+//! let shared = SharedMutex::new(vec![1, 2]);
+//! let std = RwLock::new(vec![1, 2]);
+//!
+//! push(shared.write().unwrap(), 3);
+//! push(std.write().unwrap(), 3);
+//!
+//! assert_eq!(total(shared.read().unwrap()), total(std.read().unwrap()));
+//! ```
+
+use std::ops::{Deref, DerefMut};
+use std::sync::{MutexGuard, RwLockReadGuard, RwLockWriteGuard};
+
+use {SharedMutexReadGuard, SharedMutexUpgradableReadGuard, SharedMutexWriteGuard,
+     MappedSharedMutexReadGuard, MappedSharedMutexWriteGuard,
+     OwnedSharedMutexReadGuard, OwnedSharedMutexWriteGuard,
+     OwnedMappedSharedMutexReadGuard, OwnedMappedSharedMutexWriteGuard};
+
+/// A guard providing shared access to a `T`.
+pub trait ReadAccess<T: ?Sized>: Deref<Target = T> {}
+
+/// A guard providing exclusive access to a `T`.
+pub trait WriteAccess<T: ?Sized>: ReadAccess<T> + DerefMut {}
+
+impl<'mutex, T: ?Sized> ReadAccess<T> for SharedMutexReadGuard<'mutex, T> {}
+impl<'mutex, T: ?Sized> ReadAccess<T> for SharedMutexUpgradableReadGuard<'mutex, T> {}
+impl<'mutex, T: ?Sized> ReadAccess<T> for SharedMutexWriteGuard<'mutex, T> {}
+impl<'mutex, T: ?Sized> WriteAccess<T> for SharedMutexWriteGuard<'mutex, T> {}
+
+impl<'mutex, T: ?Sized> ReadAccess<T> for MappedSharedMutexReadGuard<'mutex, T> {}
+impl<'mutex, T: ?Sized> ReadAccess<T> for MappedSharedMutexWriteGuard<'mutex, T> {}
+impl<'mutex, T: ?Sized> WriteAccess<T> for MappedSharedMutexWriteGuard<'mutex, T> {}
+
+impl<T: ?Sized> ReadAccess<T> for OwnedSharedMutexReadGuard<T> {}
+impl<T: ?Sized> ReadAccess<T> for OwnedSharedMutexWriteGuard<T> {}
+impl<T: ?Sized> WriteAccess<T> for OwnedSharedMutexWriteGuard<T> {}
+
+impl<T: ?Sized, U: ?Sized> ReadAccess<U> for OwnedMappedSharedMutexReadGuard<T, U> {}
+impl<T: ?Sized, U: ?Sized> ReadAccess<U> for OwnedMappedSharedMutexWriteGuard<T, U> {}
+impl<T: ?Sized, U: ?Sized> WriteAccess<U> for OwnedMappedSharedMutexWriteGuard<T, U> {}
+
+impl<'lock, T: ?Sized> ReadAccess<T> for RwLockReadGuard<'lock, T> {}
+impl<'lock, T: ?Sized> ReadAccess<T> for RwLockWriteGuard<'lock, T> {}
+impl<'lock, T: ?Sized> WriteAccess<T> for RwLockWriteGuard<'lock, T> {}
+
+impl<'lock, T: ?Sized> ReadAccess<T> for MutexGuard<'lock, T> {}
+impl<'lock, T: ?Sized> WriteAccess<T> for MutexGuard<'lock, T> {}
@@ -0,0 +1,94 @@
+//! A SharedMutex protecting borrowed data.
+//!
+//! `SharedMutexRef` locks data it doesn't own, such as a large buffer in a
+//! `static` or on the stack of a parent thread, without moving it into the
+//! lock. Its guards are mapped guards borrowing from the lock, so no access
+//! can outlive the borrowed data.
+
+use std::sync::{LockResult, TryLockResult, TryLockError, PoisonError};
+use std::fmt;
+
+use poison;
+
+use {SharedMutex, MappedSharedMutexReadGuard, MappedSharedMutexWriteGuard};
+
+/// A SharedMutex protecting a mutable borrow of some data for `'a`.
+pub struct SharedMutexRef<'a, T: ?Sized + 'a> {
+    mutex: SharedMutex<&'a mut T>
+}
+
+impl<'a, T: ?Sized> SharedMutexRef<'a, T> {
+    /// Create a new SharedMutexRef protecting the borrowed data.
+    #[inline]
+    pub fn new(data: &'a mut T) -> Self {
+        SharedMutexRef { mutex: SharedMutex::new(data) }
+    }
+
+    /// Release the borrow of the data and destroy the lock.
+    #[inline]
+    pub fn into_inner(self) -> LockResult<&'a mut T> {
+        self.mutex.into_inner()
+    }
+
+    /// Acquire a shared Read lock on the data.
+    #[inline]
+    pub fn read(&self) -> LockResult<MappedSharedMutexReadGuard<T>> {
+        poison::map_result(self.mutex.read(), |guard| guard.into_mapped().map(|data| &**data))
+    }
+
+    /// Acquire an exclusive Write lock on the data.
+    #[inline]
+    pub fn write(&self) -> LockResult<MappedSharedMutexWriteGuard<T>> {
+        poison::map_result(self.mutex.write(), |guard| guard.into_mapped().map(|data| &mut **data))
+    }
+
+    /// Attempt to acquire a shared Read lock on the data.
+    ///
+    /// If acquiring the lock would block, returns `TryLockError::WouldBlock`.
+    #[inline]
+    pub fn try_read(&self) -> TryLockResult<MappedSharedMutexReadGuard<T>> {
+        match self.mutex.try_read() {
+            Ok(guard) => Ok(guard.into_mapped().map(|data| &**data)),
+            Err(TryLockError::Poisoned(poisoned)) => Err(TryLockError::Poisoned(PoisonError::new(
+                poisoned.into_inner().into_mapped().map(|data| &**data)))),
+            Err(TryLockError::WouldBlock) => Err(TryLockError::WouldBlock)
+        }
+    }
+
+    /// Attempt to acquire an exclusive Write lock on the data.
+    ///
+    /// If acquiring the lock would block, returns `TryLockError::WouldBlock`.
+    #[inline]
+    pub fn try_write(&self) -> TryLockResult<MappedSharedMutexWriteGuard<T>> {
+        match self.mutex.try_write() {
+            Ok(guard) => Ok(guard.into_mapped().map(|data| &mut **data)),
+            Err(TryLockError::Poisoned(poisoned)) => Err(TryLockError::Poisoned(PoisonError::new(
+                poisoned.into_inner().into_mapped().map(|data| &mut **data)))),
+            Err(TryLockError::WouldBlock) => Err(TryLockError::WouldBlock)
+        }
+    }
+
+    /// Get a mutable reference to the data without locking.
+    ///
+    /// Safe since it requires a unique reference to the lock.
+    #[inline]
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        poison::map_result(self.mutex.get_mut(), |data| &mut **data)
+    }
+
+    /// Get the underlying SharedMutex, for example to wait on a `Condvar`
+    /// with its guards.
+    #[inline]
+    pub fn as_shared_mutex(&self) -> &SharedMutex<&'a mut T> { &self.mutex }
+}
+
+impl<'a, T: ?Sized> From<&'a mut T> for SharedMutexRef<'a, T> {
+    #[inline]
+    fn from(data: &'a mut T) -> Self { SharedMutexRef::new(data) }
+}
+
+impl<'a, T: ?Sized + fmt::Debug> fmt::Debug for SharedMutexRef<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SharedMutexRef").field("mutex", &self.mutex).finish()
+    }
+}
@@ -0,0 +1,63 @@
+//! Futures which acquire a SharedMutex without blocking the thread.
+//!
+//! Only available with the `async` feature. The futures are created by
+//! `SharedMutex::read_async` and `SharedMutex::write_async`, and resolve to
+//! the usual guards.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::LockResult;
+use std::task::{Context, Poll};
+
+use {SharedMutex, SharedMutexReadGuard, SharedMutexWriteGuard};
+
+/// A future resolving to a shared read guard on a SharedMutex.
+pub struct ReadFuture<'mutex, T: ?Sized + 'mutex> {
+    mutex: &'mutex SharedMutex<T>
+}
+
+/// A future resolving to an exclusive write guard on a SharedMutex.
+///
+/// Pending write futures do not block incoming readers, see
+/// `RawSharedMutex::poll_write`.
+pub struct WriteFuture<'mutex, T: ?Sized + 'mutex> {
+    mutex: &'mutex SharedMutex<T>
+}
+
+impl<'mutex, T: ?Sized> ReadFuture<'mutex, T> {
+    #[inline]
+    pub(crate) fn new(mutex: &'mutex SharedMutex<T>) -> Self {
+        ReadFuture { mutex: mutex }
+    }
+}
+
+impl<'mutex, T: ?Sized> WriteFuture<'mutex, T> {
+    #[inline]
+    pub(crate) fn new(mutex: &'mutex SharedMutex<T>) -> Self {
+        WriteFuture { mutex: mutex }
+    }
+}
+
+impl<'mutex, T: ?Sized> Future for ReadFuture<'mutex, T> {
+    type Output = LockResult<SharedMutexReadGuard<'mutex, T>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if self.mutex.raw.poll_read(cx.waker()) {
+            Poll::Ready(unsafe { SharedMutexReadGuard::new(self.mutex) })
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<'mutex, T: ?Sized> Future for WriteFuture<'mutex, T> {
+    type Output = LockResult<SharedMutexWriteGuard<'mutex, T>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if self.mutex.raw.poll_write(cx.waker()) {
+            Poll::Ready(unsafe { SharedMutexWriteGuard::new(self.mutex) })
+        } else {
+            Poll::Pending
+        }
+    }
+}
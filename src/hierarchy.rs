@@ -0,0 +1,73 @@
+//! Lock hierarchy checks for leveled mutexes, in debug builds only.
+//!
+//! Each thread has a set of the leveled locks it holds. Blocking on a lock
+//! with a lower level than one of those is a bug, since another thread taking
+//! the same locks in the documented order could deadlock with it.
+//!
+//! The sets live in one registry rather than in thread locals, since guards
+//! can be released on a different thread than the one which acquired them,
+//! and the lock has to leave the acquiring thread's set then.
+
+use std::sync::Mutex;
+use std::thread::{self, ThreadId};
+
+// A leveled lock held by a thread.
+struct Held {
+    thread: ThreadId,
+    lock: usize,
+    level: u32,
+    name: Option<&'static str>
+}
+
+static HELD: Mutex<Vec<Held>> = Mutex::new(Vec::new());
+
+// Lock the registry. A panic while it is locked can't leave it inconsistent,
+// so poisoning is ignored.
+fn held() -> ::std::sync::MutexGuard<'static, Vec<Held>> {
+    HELD.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// Find a lock held by the current thread with a higher level than `level`,
+// returning its level and name.
+pub fn violation(level: u32) -> Option<(u32, Option<&'static str>)> {
+    let current = thread::current().id();
+
+    held().iter()
+        .find(|held| held.thread == current && held.level > level)
+        .map(|held| (held.level, held.name))
+}
+
+// Note that the current thread acquired `lock`.
+pub fn push(lock: usize, level: u32, name: Option<&'static str>) {
+    held().push(Held { thread: thread::current().id(), lock: lock, level: level, name: name })
+}
+
+// Note that `lock` was released by the current thread.
+//
+// Locks held more than once, by recursive readers, are removed one at a time.
+// A lock the current thread doesn't hold was acquired on another thread, and
+// its guard sent here. If a single thread holds it, that one is removed.
+// If several readers on different threads hold it, there is no telling whose
+// guard this was, so all of them are forgotten: missing a violation later is
+// better than reporting one for a thread which holds nothing.
+pub fn pop(lock: usize) {
+    let current = thread::current().id();
+    let mut held = held();
+
+    if let Some(index) = held.iter().rposition(|held| held.thread == current && held.lock == lock) {
+        held.remove(index);
+        return
+    }
+
+    let owner = match held.iter().rposition(|held| held.lock == lock) {
+        Some(index) => index,
+        None => return
+    };
+
+    let thread = held[owner].thread;
+    if held.iter().all(|held| held.lock != lock || held.thread == thread) {
+        held.remove(owner);
+    } else {
+        held.retain(|held| held.lock != lock);
+    }
+}
@@ -10,17 +10,52 @@ extern crate scoped_pool;
 
 extern crate poison;
 
-use std::sync::{Condvar, LockResult, TryLockResult, TryLockError};
+#[cfg(feature = "metrics")]
+extern crate metrics;
+
+#[cfg(feature = "stable_deref_trait")]
+extern crate stable_deref_trait;
+
+#[cfg(feature = "serde")]
+extern crate serde;
+
+use std::sync::{Arc, Mutex, Condvar, LockResult, TryLockResult, TryLockError};
+use std::any::Any;
+use std::error::Error;
+use std::cmp::Ordering;
 use std::cell::UnsafeCell;
-use std::ops::{Deref, DerefMut};
-use std::{mem, ptr, fmt};
+use std::ops::{Deref, DerefMut, Index, IndexMut};
+use std::time::{Duration, Instant};
+use std::mem::{self, ManuallyDrop};
+use std::{ptr, fmt, thread};
+#[cfg(feature = "poison-backtrace")]
+use std::backtrace::Backtrace;
 
 use poison::{Poison, PoisonGuard, RawPoisonGuard};
 
-pub use raw::RawSharedMutex;
+pub use raw::{RawSharedMutex, LockPolicy, Blocking, DEFAULT_BACKOFF_CAP};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use nonpoison::{NonPoisonSharedMutex, NonPoisonReadGuard, NonPoisonWriteGuard};
+pub use striped::{SharedMutexVec, PaddedSharedMutexVec};
+pub use padded::{CachePadded, PaddedSharedMutex};
+pub use borrowed::SharedMutexRef;
+pub use owned::{OwnedSharedMutexReadGuard, OwnedSharedMutexWriteGuard,
+                OwnedMappedSharedMutexReadGuard, OwnedMappedSharedMutexWriteGuard};
 
+pub mod access;
+pub mod borrowed;
+#[cfg(feature = "async")]
+pub mod future;
 pub mod monitor;
+pub mod mailbox;
+pub mod nonpoison;
+pub mod padded;
+pub mod rwlock;
+pub mod striped;
+mod owned;
 mod raw;
+#[cfg(debug_assertions)]
+mod hierarchy;
 
 /// A lock providing both shared read locks and exclusive write locks.
 ///
@@ -47,6 +82,62 @@ impl<T> SharedMutex<T> {
         }
     }
 
+    /// Create a new SharedMutex protecting the given value, using the given
+    /// policy to arbitrate between readers and writers.
+    #[inline]
+    pub fn with_policy(value: T, policy: LockPolicy) -> Self {
+        SharedMutex {
+            raw: RawSharedMutex::with_policy(policy),
+            data: UnsafeCell::new(Poison::new(value))
+        }
+    }
+
+    /// Create a new SharedMutex protecting the given value, on which a
+    /// waiting writer blocks incoming readers.
+    ///
+    /// This is the `LockPolicy::Neutral` policy, which `new` also uses, but
+    /// spelled out so that code relying on writers not being starved by a
+    /// continuous stream of readers says so.
+    #[inline]
+    pub fn new_writer_preferred(value: T) -> Self {
+        SharedMutex::with_policy(value, LockPolicy::Neutral)
+    }
+
+    /// Create a new SharedMutex protecting the given value, on which readers
+    /// and writers take turns.
+    ///
+    /// This is the `LockPolicy::PhaseFair` policy: neither a stream of
+    /// readers nor a stream of writers can starve the other kind.
+    #[inline]
+    pub fn new_phase_fair(value: T) -> Self {
+        SharedMutex::with_policy(value, LockPolicy::PhaseFair)
+    }
+
+    /// Create a new SharedMutex protecting the given value, at the given
+    /// level in the lock hierarchy.
+    ///
+    /// In debug builds, acquiring a leveled lock panics if the current thread
+    /// holds a leveled lock with a higher level. See
+    /// `RawSharedMutex::set_level` for more information.
+    #[inline]
+    pub fn new_leveled(value: T, level: u32) -> Self {
+        let mut mutex = SharedMutex::new(value);
+        mutex.raw.set_level(level);
+        mutex
+    }
+
+    /// Create a new SharedMutex protecting the given value, which lives for
+    /// the rest of the program.
+    ///
+    /// This intentionally leaks the mutex, and is meant for process-lifetime
+    /// globals. Guards on the returned mutex are `'static` without the cost
+    /// of an `Arc`.
+    #[inline]
+    pub fn leak_static(value: T) -> &'static Self
+    where T: 'static {
+        Box::leak(Box::new(SharedMutex::new(value)))
+    }
+
     /// Extract the data from the lock and destroy the lock.
     ///
     /// Safe since it requires ownership of the lock.
@@ -54,6 +145,51 @@ impl<T> SharedMutex<T> {
     pub fn into_inner(self) -> LockResult<T> {
         unsafe { self.data.into_inner().into_inner() }
     }
+
+    /// Extract the data from a shared lock if this is the last reference to it.
+    ///
+    /// If there are other strong references to the lock, the `Arc` is handed
+    /// back unchanged as the error.
+    #[inline]
+    pub fn try_unwrap_arc(this: Arc<Self>) -> Result<LockResult<T>, Arc<Self>> {
+        Arc::try_unwrap(this).map(SharedMutex::into_inner)
+    }
+
+    /// Replace the data with `new` if it is currently equal to `expected`.
+    ///
+    /// Returns whether the data was replaced. The comparison and the store
+    /// happen under a single write lock, so no other thread can observe or
+    /// modify the data in between.
+    pub fn compare_and_set(&self, expected: &T, new: T) -> LockResult<bool>
+    where T: PartialEq {
+        poison::map_result(self.write(), |mut guard| {
+            if *guard == *expected {
+                *guard = new;
+                true
+            } else {
+                false
+            }
+        })
+    }
+
+    /// Take the data out of the mutex, leaving `T::default()` behind.
+    ///
+    /// Meant for draining a collection to process its contents in a batch:
+    /// the write lock is released before returning, so processing the result
+    /// doesn't hold up other threads.
+    pub fn drain_collected(&self) -> LockResult<T>
+    where T: Default {
+        poison::map_result(self.write(), |mut guard| mem::replace(&mut *guard, T::default()))
+    }
+
+    /// Get a copy of the data, taken under a read lock.
+    ///
+    /// The lock is released before returning. If the mutex is poisoned, the
+    /// copy is returned in the poisoned error.
+    pub fn snapshot(&self) -> LockResult<T>
+    where T: Clone {
+        poison::map_result(self.read(), |guard| (*guard).clone())
+    }
 }
 
 impl<T: ?Sized> SharedMutex<T> {
@@ -71,9 +207,159 @@ impl<T: ?Sized> SharedMutex<T> {
         unsafe { SharedMutexReadGuard::new(self) }
     }
 
+    /// Acquire an exclusive Write lock on the data, along with how long it
+    /// took to acquire.
+    ///
+    /// Meant for profiling suspected hot spots. The clock is only read if
+    /// the lock can't be taken right away, so an uncontended acquisition
+    /// reports a zero duration.
+    pub fn write_timed(&self) -> LockResult<(SharedMutexWriteGuard<T>, Duration)> {
+        let waited = if self.raw.try_write() {
+            Duration::from_secs(0)
+        } else {
+            let start = Instant::now();
+            self.raw.write();
+            start.elapsed()
+        };
+
+        poison::map_result(unsafe { SharedMutexWriteGuard::new(self) }, |guard| (guard, waited))
+    }
+
+    /// Acquire a shared Read lock on the data, along with how long it took to
+    /// acquire.
+    ///
+    /// See `write_timed` for more information.
+    pub fn read_timed(&self) -> LockResult<(SharedMutexReadGuard<T>, Duration)> {
+        let waited = if self.raw.try_read() {
+            Duration::from_secs(0)
+        } else {
+            let start = Instant::now();
+            self.raw.read();
+            start.elapsed()
+        };
+
+        poison::map_result(unsafe { SharedMutexReadGuard::new(self) }, |guard| (guard, waited))
+    }
+
+    /// Acquire an upgradable Read lock on the data.
+    ///
+    /// An upgradable read lock can be held alongside plain read locks, but
+    /// not alongside a write lock or another upgradable read lock, so it can
+    /// later be upgraded to a write lock without any other writer getting in
+    /// between.
+    #[inline]
+    pub fn upgradable_read(&self) -> LockResult<SharedMutexUpgradableReadGuard<T>> {
+        self.raw.upgradable_read();
+        unsafe { SharedMutexUpgradableReadGuard::new(self) }
+    }
+
+    /// Run `action` with an upgradable Read lock on the data.
+    ///
+    /// Useful for "check, then maybe mutate" access where most calls only
+    /// read: `action` can upgrade the guard when it needs to write, and the
+    /// lock is always released by the time `with_upgradable` returns.
+    ///
+    /// `action` runs even if the mutex is poisoned, in which case its result
+    /// is returned as a poisoned error.
+    pub fn with_upgradable<R, F>(&self, action: F) -> LockResult<R>
+    where F: FnOnce(SharedMutexUpgradableReadGuard<T>) -> R {
+        poison::map_result(self.upgradable_read(), action)
+    }
+
+    /// Run `action` with a write guard on the data, releasing the lock when
+    /// it returns.
+    ///
+    /// Unlike working with the data directly, `action` can use the methods
+    /// of the guard, while the lock is still guaranteed to be released at
+    /// the end of the scope.
+    ///
+    /// `action` runs even if the mutex is poisoned, in which case its result
+    /// is returned as a poisoned error.
+    pub fn write_scope<R, F>(&self, action: F) -> LockResult<R>
+    where F: FnOnce(&mut SharedMutexWriteGuard<T>) -> R {
+        poison::map_result(self.write(), |mut guard| action(&mut guard))
+    }
+
+    /// Run `action` on the data, and notify all threads waiting on `cond`
+    /// if it returns true to report a change.
+    ///
+    /// The lock is released before notifying, like `release_and_notify`, and
+    /// waiters aren't woken at all if nothing changed. Returns whether the
+    /// data changed.
+    ///
+    /// `action` runs even if the mutex is poisoned, in which case its result
+    /// is returned as a poisoned error.
+    pub fn write_if_changed<F>(&self, action: F, cond: &Condvar) -> LockResult<bool>
+    where F: FnOnce(&mut T) -> bool {
+        poison::map_result(self.write(), |mut guard| {
+            let changed = action(&mut guard);
+            if changed { guard.release_and_notify(cond, NotifyKind::All) }
+            changed
+        })
+    }
+
+    /// Run `action` with a read guard on the data, releasing the lock when it
+    /// returns.
+    ///
+    /// See `write_scope` for more information.
+    pub fn read_scope<R, F>(&self, action: F) -> LockResult<R>
+    where F: FnOnce(&mut SharedMutexReadGuard<T>) -> R {
+        poison::map_result(self.read(), |mut guard| action(&mut guard))
+    }
+
+    /// Run `action` on the data under a write lock, and return its result.
+    ///
+    /// The lock is released as soon as `action` returns, so it can't be held
+    /// for longer than intended. If `action` panics the mutex is poisoned, as
+    /// with a guard. Like `write_scope`, `action` runs even if the mutex is
+    /// already poisoned, in which case its result is returned as a poisoned
+    /// error.
+    pub fn with_write<R, F>(&self, action: F) -> LockResult<R>
+    where F: FnOnce(&mut T) -> R {
+        poison::map_result(self.write(), |mut guard| action(&mut guard))
+    }
+
+    /// Run `action` on the data under a read lock, and return its result.
+    ///
+    /// See `with_write` for more information.
+    pub fn with_read<R, F>(&self, action: F) -> LockResult<R>
+    where F: FnOnce(&T) -> R {
+        poison::map_result(self.read(), |guard| action(&guard))
+    }
+
+    /// Run `action` on the data under a write lock, unless the lock can't be
+    /// acquired within `timeout`.
+    ///
+    /// The lock is released as soon as `action` returns. On timeout `action`
+    /// doesn't run and `TimedOut` is returned. Like `write_scope`, `action`
+    /// runs even if the mutex is poisoned, in which case its result is
+    /// returned as a poisoned error.
+    pub fn with_write_timeout<R, F>(&self, timeout: Duration, action: F) -> Result<LockResult<R>, TimedOut>
+    where F: FnOnce(&mut T) -> R {
+        if !self.raw.try_write_for(timeout) { return Err(TimedOut) }
+
+        let guard = unsafe { SharedMutexWriteGuard::new(self) };
+        Ok(poison::map_result(guard, |mut guard| action(&mut guard)))
+    }
+
+    /// Run `action` on the data under a read lock, unless the lock can't be
+    /// acquired within `timeout`.
+    ///
+    /// See `with_write_timeout` for more information.
+    pub fn with_read_timeout<R, F>(&self, timeout: Duration, action: F) -> Result<LockResult<R>, TimedOut>
+    where F: FnOnce(&T) -> R {
+        if !self.raw.try_read_for(timeout) { return Err(TimedOut) }
+
+        let guard = unsafe { SharedMutexReadGuard::new(self) };
+        Ok(poison::map_result(guard, |guard| action(&guard)))
+    }
+
     /// Attempt to acquire a shared Read lock on the data.
     ///
     /// If acquiring the lock would block, returns `TryLockError::WouldBlock`.
+    /// This never happens spuriously, only when acquiring would genuinely
+    /// block: while a writer holds the lock or, depending on the policy, is
+    /// waiting for it, or while the maximum number of readers hold it.
     #[inline]
     pub fn try_read(&self) -> TryLockResult<SharedMutexReadGuard<T>> {
         if self.raw.try_read() {
@@ -83,6 +369,22 @@ impl<T: ?Sized> SharedMutex<T> {
         }
     }
 
+    /// Acquire a shared Read lock on the data, unless the maximum number of
+    /// readers already hold it.
+    ///
+    /// Blocks like `read` while a writer holds or waits for the lock, but
+    /// returns `TryLockError::WouldBlock` rather than waiting for a reader to
+    /// exit once the reader count is saturated. See
+    /// `RawSharedMutex::try_read_checked` for the limit.
+    #[inline]
+    pub fn try_read_checked(&self) -> TryLockResult<SharedMutexReadGuard<T>> {
+        if self.raw.try_read_checked() {
+            Ok(try!(unsafe { SharedMutexReadGuard::new(self) }))
+        } else {
+            Err(TryLockError::WouldBlock)
+        }
+    }
+
     /// Attempt to acquire an exclusive Write lock on the data.
     ///
     /// If acquiring the lock would block, returns `TryLockError::WouldBlock`.
@@ -95,6 +397,293 @@ impl<T: ?Sized> SharedMutex<T> {
         }
     }
 
+    /// Attempt to acquire an exclusive Write lock on the data, reporting what
+    /// blocked it on failure.
+    ///
+    /// Useful for choosing a backoff: readers are often about to finish,
+    /// while a writer may hold the lock for longer. A poisoned lock is
+    /// released before returning `WriteBlockedBy::Poisoned`; use `try_write`
+    /// to recover the data instead.
+    #[inline]
+    pub fn try_write_detailed(&self) -> Result<SharedMutexWriteGuard<T>, WriteBlockedBy> {
+        match self.raw.try_write_detailed() {
+            Ok(()) => unsafe { SharedMutexWriteGuard::new(self) }
+                .map_err(|_| WriteBlockedBy::Poisoned),
+            Err(0) => Err(WriteBlockedBy::Writer),
+            Err(readers) => Err(WriteBlockedBy::Readers(readers))
+        }
+    }
+
+    /// Attempt to acquire a shared Read lock on the data, blocking for at
+    /// most `timeout`.
+    ///
+    /// Returns `TryLockError::WouldBlock` on timeout, leaving no trace of the
+    /// reader in the lock. A zero timeout behaves exactly like `try_read`.
+    #[inline]
+    pub fn try_read_for(&self, timeout: Duration) -> TryLockResult<SharedMutexReadGuard<T>> {
+        if self.raw.try_read_for(timeout) {
+            Ok(try!(unsafe { SharedMutexReadGuard::new(self) }))
+        } else {
+            Err(TryLockError::WouldBlock)
+        }
+    }
+
+    /// Attempt to acquire an exclusive Write lock on the data, blocking for
+    /// at most `timeout`.
+    ///
+    /// Returns `TryLockError::WouldBlock` on timeout. A zero timeout behaves
+    /// exactly like `try_write`.
+    #[inline]
+    pub fn try_write_for(&self, timeout: Duration) -> TryLockResult<SharedMutexWriteGuard<T>> {
+        if self.raw.try_write_for(timeout) {
+            Ok(try!(unsafe { SharedMutexWriteGuard::new(self) }))
+        } else {
+            Err(TryLockError::WouldBlock)
+        }
+    }
+
+    /// Attempt to acquire a shared Read lock on the data, blocking until
+    /// `deadline` at the latest.
+    ///
+    /// Returns `TryLockError::WouldBlock` once the deadline passes. A deadline
+    /// in the past behaves exactly like `try_read`.
+    #[inline]
+    pub fn try_read_until(&self, deadline: Instant) -> TryLockResult<SharedMutexReadGuard<T>> {
+        if self.raw.try_read_until(deadline) {
+            Ok(try!(unsafe { SharedMutexReadGuard::new(self) }))
+        } else {
+            Err(TryLockError::WouldBlock)
+        }
+    }
+
+    /// Attempt to acquire an exclusive Write lock on the data, blocking
+    /// until `deadline` at the latest.
+    ///
+    /// Returns `TryLockError::WouldBlock` once the deadline passes. A deadline
+    /// in the past behaves exactly like `try_write`.
+    #[inline]
+    pub fn try_write_until(&self, deadline: Instant) -> TryLockResult<SharedMutexWriteGuard<T>> {
+        if self.raw.try_write_until(deadline) {
+            Ok(try!(unsafe { SharedMutexWriteGuard::new(self) }))
+        } else {
+            Err(TryLockError::WouldBlock)
+        }
+    }
+
+    /// Attempt to acquire a shared Read lock on the data, which the current
+    /// thread may already hold, blocking for at most `timeout`.
+    ///
+    /// Succeeds without waiting while there are other readers, even if a
+    /// writer is queued, so recursing code can't deadlock behind a writer
+    /// waiting for its outer read lock. Returns `TryLockError::WouldBlock` on
+    /// timeout. See `RawSharedMutex::try_read_recursive_for` for more
+    /// information.
+    #[inline]
+    pub fn try_read_recursive_for(&self, timeout: Duration) -> TryLockResult<SharedMutexReadGuard<T>> {
+        if self.raw.try_read_recursive_for(timeout) {
+            Ok(try!(unsafe { SharedMutexReadGuard::new(self) }))
+        } else {
+            Err(TryLockError::WouldBlock)
+        }
+    }
+
+    /// Attempt to acquire a shared Read lock on the data, retrying up to
+    /// `attempts` more times while acquiring it would block.
+    ///
+    /// Spins between attempts rather than sleeping, for latency-sensitive
+    /// code which can afford a short bounded spin. See
+    /// `RawSharedMutex::try_read_spin` for more information.
+    #[inline]
+    pub fn try_read_spin(&self, attempts: u32) -> TryLockResult<SharedMutexReadGuard<T>> {
+        if self.raw.try_read_spin(attempts) {
+            Ok(try!(unsafe { SharedMutexReadGuard::new(self) }))
+        } else {
+            Err(TryLockError::WouldBlock)
+        }
+    }
+
+    /// Attempt to acquire an exclusive Write lock on the data, retrying up to
+    /// `attempts` more times while acquiring it would block.
+    ///
+    /// See `try_read_spin` for more information.
+    #[inline]
+    pub fn try_write_spin(&self, attempts: u32) -> TryLockResult<SharedMutexWriteGuard<T>> {
+        if self.raw.try_write_spin(attempts) {
+            Ok(try!(unsafe { SharedMutexWriteGuard::new(self) }))
+        } else {
+            Err(TryLockError::WouldBlock)
+        }
+    }
+
+    /// Peek at the data, if it can be read without blocking and the mutex is
+    /// not poisoned.
+    ///
+    /// A best-effort, non-blocking way to inspect the data for logging or
+    /// debugging, which unlike `try_read` collapses all failures into `None`.
+    #[inline]
+    pub fn try_peek(&self) -> Option<MappedSharedMutexReadGuard<T>> {
+        self.try_read().ok().map(SharedMutexReadGuard::into_mapped)
+    }
+
+    /// Acquire a shared Read lock on the part of the data selected by
+    /// `action`, if it selects anything.
+    ///
+    /// If `action` returns `None`, the lock is released right away and
+    /// `Ok(None)` is returned.
+    #[inline]
+    pub fn read_filtered<U: ?Sized, F>(&self, action: F) -> LockResult<Option<MappedSharedMutexReadGuard<U>>>
+    where F: FnOnce(&T) -> Option<&U> {
+        poison::map_result(self.read(), |guard| guard.into_mapped().option_map(action))
+    }
+
+    /// Acquire a shared Read lock on the data without blocking the thread.
+    ///
+    /// Returns a future which resolves once the lock is acquired. Only
+    /// available with the `async` feature.
+    #[cfg(feature = "async")]
+    #[inline]
+    pub fn read_async(&self) -> future::ReadFuture<T> {
+        future::ReadFuture::new(self)
+    }
+
+    /// Acquire an exclusive Write lock on the data without blocking the thread.
+    ///
+    /// Returns a future which resolves once the lock is acquired. Only
+    /// available with the `async` feature.
+    #[cfg(feature = "async")]
+    #[inline]
+    pub fn write_async(&self) -> future::WriteFuture<T> {
+        future::WriteFuture::new(self)
+    }
+
+    /// Register a callback to run whenever a write lock on this mutex is released.
+    ///
+    /// Callbacks run on the thread releasing the lock, right after the lock is
+    /// released and outside of any critical section, so they may lock this
+    /// mutex again. Another thread may already hold the lock by the time a
+    /// callback runs, and waiting on a `Condvar` from a write guard does not
+    /// run them.
+    ///
+    /// See `RawSharedMutex::on_write_unlock` for more information.
+    #[inline]
+    pub fn on_write_unlock<F>(&self, callback: F)
+    where F: Fn() + Send + Sync + 'static {
+        self.raw.on_write_unlock(callback)
+    }
+
+    /// Set the name used to identify this mutex in diagnostics.
+    #[inline]
+    pub fn set_name(&mut self, name: &'static str) { self.raw.set_name(name) }
+
+    /// Get the name of this mutex, if it has one.
+    #[inline]
+    pub fn name(&self) -> Option<&'static str> { self.raw.name() }
+
+    /// Get the level of this mutex in the lock hierarchy, if it has one.
+    #[inline]
+    pub fn level(&self) -> Option<u32> { self.raw.level() }
+
+    /// Check whether this mutex and `other` are the same mutex.
+    #[inline]
+    pub fn ptr_eq(&self, other: &SharedMutex<T>) -> bool { self.raw.is(&other.raw) }
+
+    /// Panic if this mutex is currently locked.
+    ///
+    /// See `RawSharedMutex::assert_unlocked` for more information. Only
+    /// available with the `test-util` feature.
+    #[cfg(any(test, feature = "test-util"))]
+    #[inline]
+    pub fn assert_unlocked(&self) { self.raw.assert_unlocked() }
+
+    /// Panic if this mutex is currently read-locked.
+    ///
+    /// See `RawSharedMutex::assert_no_readers` for more information. Only
+    /// available with the `test-util` feature.
+    #[cfg(any(test, feature = "test-util"))]
+    #[inline]
+    pub fn assert_no_readers(&self) { self.raw.assert_no_readers() }
+
+    /// Check whether this mutex is poisoned, without acquiring the lock.
+    ///
+    /// Lets supervising code decide whether to rebuild the data before
+    /// contending for the lock. Like `std::sync::RwLock::is_poisoned`, the
+    /// result may be out of date as soon as it is returned. While a writer
+    /// holds the lock the flag can't be read, so the mutex is reported as
+    /// poisoned if any earlier writer was released while panicking.
+    #[inline]
+    pub fn is_poisoned(&self) -> bool {
+        self.raw.check_poisoned(|| unsafe { (*self.data.get()).get().is_err() })
+    }
+
+    /// Clear the poisoned state of this mutex.
+    ///
+    /// Waits for a write lock, so the data can be checked or repaired with a
+    /// recovered guard first, then marks the mutex as healthy so subsequent
+    /// `read` and `write` calls succeed again.
+    pub fn clear_poison(&self) {
+        self.raw.write();
+        unsafe { (*self.data.get()).heal() }
+        self.raw.clear_panicked();
+        self.raw.unlock_write();
+    }
+
+    /// Get a backtrace of where this mutex was poisoned, if it is.
+    ///
+    /// See `RawSharedMutex::poison_backtrace` for more information. Only
+    /// available with the `poison-backtrace` feature.
+    #[cfg(feature = "poison-backtrace")]
+    #[inline]
+    pub fn poison_backtrace(&self) -> Option<Arc<Backtrace>> { self.raw.poison_backtrace() }
+
+    /// Check whether a writer is currently blocked waiting for the lock.
+    ///
+    /// This is only a hint, for example for readers to finish up quickly,
+    /// since it may be out of date by the time it returns.
+    #[inline]
+    pub fn has_waiting_writer(&self) -> bool { self.raw.has_waiting_writer() }
+
+    /// Get the number of readers currently holding the lock.
+    ///
+    /// This is an instantaneous snapshot which can change as soon as it is
+    /// returned, so it is only useful for metrics and heuristics.
+    #[inline]
+    pub fn reader_count(&self) -> usize { self.raw.reader_count() }
+
+    /// Check whether the lock is currently held by a writer.
+    ///
+    /// Like `reader_count`, this is only a snapshot for metrics and
+    /// heuristics, and should never be used to decide whether locking
+    /// is safe.
+    #[inline]
+    pub fn is_write_locked(&self) -> bool { self.raw.is_locked_exclusive() }
+
+    /// Set the maximum number of spins between attempts to acquire a
+    /// contended lock before blocking.
+    ///
+    /// See `RawSharedMutex::set_backoff_cap` for more information.
+    #[inline]
+    pub fn set_backoff_cap(&mut self, cap: u32) { self.raw.set_backoff_cap(cap) }
+
+    /// Set how threads wait for this mutex when they have to block.
+    ///
+    /// See `RawSharedMutex::set_blocking` for more information.
+    #[inline]
+    pub fn set_blocking(&mut self, blocking: Blocking) { self.raw.set_blocking(blocking) }
+
+    /// Run `hook` whenever this mutex is released after being held for longer
+    /// than `threshold`.
+    ///
+    /// Useful to catch accidentally long critical sections. See
+    /// `RawSharedMutex::warn_if_held_longer_than` for more information.
+    ///
+    /// Only available with the `watchdog` feature.
+    #[cfg(feature = "watchdog")]
+    #[inline]
+    pub fn warn_if_held_longer_than<F>(&self, threshold: Duration, hook: F)
+    where F: Fn(Option<&'static str>, Duration) + Send + Sync + 'static {
+        self.raw.warn_if_held_longer_than(threshold, hook)
+    }
+
     /// Get a mutable reference to the data without locking.
     ///
     /// Safe since it requires exclusive access to the lock itself.
@@ -114,9 +703,23 @@ pub struct SharedMutexReadGuard<'mutex, T: ?Sized + 'mutex> {
 unsafe impl<'mutex, T: ?Sized + Send> Send for SharedMutexReadGuard<'mutex, T> {}
 unsafe impl<'mutex, T: ?Sized + Sync> Sync for SharedMutexReadGuard<'mutex, T> {}
 
+/// An upgradable read guard on a SharedMutex.
+///
+/// Shares the lock with plain readers, but excludes writers and other
+/// upgradable readers, so it can be upgraded to a write guard with `upgrade`.
+pub struct SharedMutexUpgradableReadGuard<'mutex, T: ?Sized + 'mutex> {
+    data: &'mutex T,
+    mutex: &'mutex SharedMutex<T>
+}
+
+unsafe impl<'mutex, T: ?Sized + Send + Sync> Send for SharedMutexUpgradableReadGuard<'mutex, T> {}
+unsafe impl<'mutex, T: ?Sized + Sync> Sync for SharedMutexUpgradableReadGuard<'mutex, T> {}
+
 /// An exclusive write guard on a SharedMutex.
 pub struct SharedMutexWriteGuard<'mutex, T: ?Sized + 'mutex> {
-    data: PoisonGuard<'mutex, T>,
+    // Must be released before the lock, so the poison flag is set by the
+    // time other threads can observe the data.
+    data: ManuallyDrop<PoisonGuard<'mutex, T>>,
     mutex: &'mutex SharedMutex<T>
 }
 
@@ -127,6 +730,13 @@ impl<'mutex, T: ?Sized> Deref for SharedMutexReadGuard<'mutex, T> {
     fn deref(&self) -> &T { self.data }
 }
 
+impl<'mutex, T: ?Sized> Deref for SharedMutexUpgradableReadGuard<'mutex, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T { self.data }
+}
+
 impl<'mutex, T: ?Sized> Deref for SharedMutexWriteGuard<'mutex, T> {
     type Target = T;
 
@@ -151,37 +761,135 @@ impl<'mutex, T: ?Sized> SharedMutexReadGuard<'mutex, T> {
     }
 }
 
-impl<'mutex, T: ?Sized> SharedMutexWriteGuard<'mutex, T> {
+impl<'mutex, T: ?Sized> SharedMutexUpgradableReadGuard<'mutex, T> {
     #[inline]
     unsafe fn new(mutex: &'mutex SharedMutex<T>) -> LockResult<Self> {
-        poison::map_result((&mut *mutex.data.get()).lock(), |poison| {
-            SharedMutexWriteGuard {
-                data: poison,
+        poison::map_result((&*mutex.data.get()).get(), |data| {
+            SharedMutexUpgradableReadGuard {
+                data: data,
                 mutex: mutex
             }
         })
     }
-}
 
-impl<'mutex, T: ?Sized> SharedMutexReadGuard<'mutex, T> {
-    /// Turn this guard into a guard which can be mapped to a sub-borrow.
+    /// Check whether this guard holds a lock on `mutex`.
     ///
-    /// Note that a mapped guard cannot wait on a `Condvar`.
-    pub fn into_mapped(self) -> MappedSharedMutexReadGuard<'mutex, T> {
-        let guard = MappedSharedMutexReadGuard {
-            mutex: &self.mutex.raw,
-            data: self.data
-        };
+    /// Compares the underlying locks only, so `mutex` may have any type.
+    #[inline]
+    pub fn points_to<U: ?Sized>(&self, mutex: &SharedMutex<U>) -> bool {
+        self.mutex.raw.is(&mutex.raw)
+    }
 
-        // Don't double-unlock.
+    /// Upgrade this guard to a write guard.
+    ///
+    /// Blocks new readers and waits for the current ones to release the lock.
+    /// No other writer can acquire the lock in between.
+    pub fn upgrade(self) -> LockResult<SharedMutexWriteGuard<'mutex, T>> {
+        let mutex = self.mutex;
         mem::forget(self);
 
-        guard
+        mutex.raw.upgrade();
+        unsafe { SharedMutexWriteGuard::new(mutex) }
     }
 
-    /// Wait on the given condition variable, and resume with a write lock.
+    /// Attempt to upgrade this guard to a write guard without blocking.
     ///
-    /// See the documentation for `std::sync::Condvar::wait` for more information.
+    /// If other readers still hold the lock, this guard is returned, still
+    /// holding the upgradable read lock.
+    pub fn try_upgrade(self) -> Result<LockResult<SharedMutexWriteGuard<'mutex, T>>, Self> {
+        if !self.mutex.raw.try_upgrade() { return Err(self) }
+
+        let mutex = self.mutex;
+        mem::forget(self);
+        Ok(unsafe { SharedMutexWriteGuard::new(mutex) })
+    }
+
+    /// Attempt to upgrade this guard to a write guard, waiting at most
+    /// `timeout` for the other readers to release the lock.
+    ///
+    /// On timeout this guard is returned, still holding the upgradable read
+    /// lock, so the upgrade can be retried or another path taken.
+    pub fn try_upgrade_for(self, timeout: Duration)
+            -> Result<LockResult<SharedMutexWriteGuard<'mutex, T>>, Self> {
+        if !self.mutex.raw.try_upgrade_for(timeout) { return Err(self) }
+
+        let mutex = self.mutex;
+        mem::forget(self);
+        Ok(unsafe { SharedMutexWriteGuard::new(mutex) })
+    }
+}
+
+impl<'mutex, T: ?Sized> SharedMutexWriteGuard<'mutex, T> {
+    #[inline]
+    unsafe fn new(mutex: &'mutex SharedMutex<T>) -> LockResult<Self> {
+        poison::map_result((&mut *mutex.data.get()).lock(), |poison| {
+            SharedMutexWriteGuard {
+                data: ManuallyDrop::new(poison),
+                mutex: mutex
+            }
+        })
+    }
+}
+
+impl<'mutex, T: ?Sized> SharedMutexReadGuard<'mutex, T> {
+    /// Check whether this guard holds a lock on `mutex`.
+    ///
+    /// Compares the underlying locks only, so `mutex` may have any type.
+    #[inline]
+    pub fn points_to<U: ?Sized>(&self, mutex: &SharedMutex<U>) -> bool {
+        self.mutex.raw.is(&mutex.raw)
+    }
+
+    /// Trade this guard for a write guard, blocking until the write lock is
+    /// acquired.
+    ///
+    /// If this is the only reader the lock is switched over directly.
+    /// Otherwise the read lock is released first, so other writers may change
+    /// the data in between; use `SharedMutex::upgradable_read` if that must
+    /// not happen. Since the read lock is given up, several readers upgrading
+    /// at once can't deadlock.
+    pub fn upgrade(self) -> LockResult<SharedMutexWriteGuard<'mutex, T>> {
+        let mutex = self.mutex;
+        mem::forget(self);
+
+        mutex.raw.upgrade_read();
+        unsafe { SharedMutexWriteGuard::new(mutex) }
+    }
+
+    /// Turn this guard into a mapped guard which remembers the mutex it came
+    /// from, so it can be turned back into this guard with `recover_typed`
+    /// after being mapped.
+    pub fn into_mapped_recoverable(self) -> MappedSharedMutexReadGuard<'mutex, T>
+    where T: Sized + Any + Send + Sync {
+        let mutex = self.mutex;
+        let mut guard = self.into_mapped();
+        guard.recovery = Some(mutex);
+        guard
+    }
+
+    /// Turn this guard into a guard which can be mapped to a sub-borrow.
+    ///
+    /// Note that a mapped guard cannot wait on a `Condvar`.
+    pub fn into_mapped(self) -> MappedSharedMutexReadGuard<'mutex, T> {
+        let guard = MappedSharedMutexReadGuard {
+            mutex: &self.mutex.raw,
+            recovery: None,
+            data: self.data
+        };
+
+        // Don't double-unlock.
+        mem::forget(self);
+
+        guard
+    }
+
+    /// Wait on the given condition variable, and resume with a write lock.
+    ///
+    /// The read lock is released and the wait starts atomically, so a writer
+    /// which changes the data and then notifies `cond` can't be missed: it
+    /// can only get the lock once this thread is already waiting.
+    ///
+    /// See the documentation for `std::sync::Condvar::wait` for more information.
     pub fn wait_for_write(self, cond: &Condvar) -> LockResult<SharedMutexWriteGuard<'mutex, T>> {
         self.mutex.raw.wait_from_read_to_write(cond);
 
@@ -193,6 +901,25 @@ impl<'mutex, T: ?Sized> SharedMutexReadGuard<'mutex, T> {
         guard
     }
 
+    /// Wait on the given condition variable for at most `timeout`, and
+    /// resume with a write lock.
+    ///
+    /// The returned guard holds the write lock even if the wait timed out, so
+    /// the condition can be checked again. Reacquiring the lock may take
+    /// longer than `timeout`. See the documentation for
+    /// `std::sync::Condvar::wait_timeout` for more information.
+    pub fn wait_timeout_for_write(self, cond: &Condvar, timeout: Duration)
+        -> LockResult<(SharedMutexWriteGuard<'mutex, T>, WaitTimeoutResult)> {
+        let timed_out = self.mutex.raw.wait_timeout_from_read_to_write(cond, timeout);
+
+        let guard = unsafe { SharedMutexWriteGuard::new(self.mutex) };
+
+        // Don't double-unlock.
+        mem::forget(self);
+
+        poison::map_result(guard, |guard| (guard, WaitTimeoutResult(timed_out)))
+    }
+
     /// Wait on the given condition variable, and resume with another read lock.
     ///
     /// See the documentation for `std::sync::Condvar::wait` for more information.
@@ -206,16 +933,122 @@ impl<'mutex, T: ?Sized> SharedMutexReadGuard<'mutex, T> {
 
         guard
     }
+
+    /// Wait on the given condition variable while `condition` returns true,
+    /// and resume with another read lock.
+    ///
+    /// See `SharedMutexWriteGuard::wait_while_for_write` for more
+    /// information.
+    pub fn wait_while_for_read<F>(self, cond: &Condvar, mut condition: F) -> LockResult<Self>
+    where F: FnMut(&T) -> bool {
+        let mut guard = self;
+
+        while condition(&guard) {
+            guard = try!(guard.wait_for_read(cond));
+        }
+
+        Ok(guard)
+    }
+
+    /// Wait on the given condition variable for at most `timeout`, and
+    /// resume with another read lock.
+    ///
+    /// See `wait_timeout_for_write` for more information.
+    pub fn wait_timeout_for_read(self, cond: &Condvar, timeout: Duration)
+        -> LockResult<(Self, WaitTimeoutResult)> {
+        let timed_out = self.mutex.raw.wait_timeout_from_read_to_read(cond, timeout);
+
+        let guard = unsafe { SharedMutexReadGuard::new(self.mutex) };
+
+        // Don't double-unlock.
+        mem::forget(self);
+
+        poison::map_result(guard, |guard| (guard, WaitTimeoutResult(timed_out)))
+    }
+
+    /// Wait on the given condition variable, resume with a write lock and
+    /// map it with `action`.
+    #[inline]
+    pub fn wait_for_write_then_map<U: ?Sized, F>(self, cond: &Condvar, action: F)
+        -> LockResult<MappedSharedMutexWriteGuard<'mutex, U>>
+    where F: FnOnce(&mut T) -> &mut U {
+        poison::map_result(self.wait_for_write(cond), |guard| guard.into_mapped().map(action))
+    }
+
+    /// Wait on the given condition variable, resume with another read lock
+    /// and map it with `action`.
+    #[inline]
+    pub fn wait_for_read_then_map<U: ?Sized, F>(self, cond: &Condvar, action: F)
+        -> LockResult<MappedSharedMutexReadGuard<'mutex, U>>
+    where F: FnOnce(&T) -> &U {
+        poison::map_result(self.wait_for_read(cond), |guard| guard.into_mapped().map(action))
+    }
+
+    /// Give up the guard without ever releasing the read lock, returning a
+    /// reference to the data which lasts as long as the mutex.
+    ///
+    /// **The mutex stays read-locked forever**, so writers can never acquire
+    /// it again. See `SharedMutexWriteGuard::leak` for more information.
+    pub fn leak(self) -> &'mutex T {
+        let data = self.data;
+        self.mutex.raw.forget_level();
+
+        // Never unlock.
+        mem::forget(self);
+
+        data
+    }
+
+    /// Release the read lock, then notify the given condition variable.
+    ///
+    /// See `SharedMutexWriteGuard::release_and_notify`.
+    pub fn release_and_notify(self, cond: &Condvar, kind: NotifyKind) {
+        drop(self);
+        kind.notify(cond)
+    }
+
+    /// Release the read lock, handing it directly to the longest waiting
+    /// thread if this was the last reader.
+    ///
+    /// See `SharedMutexWriteGuard::unlock_fair`.
+    pub fn unlock_fair(self) {
+        let mutex = self.mutex;
+
+        // Don't double-unlock.
+        mem::forget(self);
+
+        mutex.raw.unlock_read_fair()
+    }
 }
 
 impl<'mutex, T: ?Sized> SharedMutexWriteGuard<'mutex, T> {
+    /// Check whether this guard holds a lock on `mutex`.
+    ///
+    /// Compares the underlying locks only, so `mutex` may have any type.
+    #[inline]
+    pub fn points_to<U: ?Sized>(&self, mutex: &SharedMutex<U>) -> bool {
+        self.mutex.raw.is(&mutex.raw)
+    }
+
+    /// Turn this guard into a mapped guard which remembers the mutex it came
+    /// from, so it can be turned back into this guard with `recover_typed`
+    /// after being mapped.
+    pub fn into_mapped_recoverable(self) -> MappedSharedMutexWriteGuard<'mutex, T>
+    where T: Sized + Any + Send + Sync {
+        let mutex = self.mutex;
+        let mut guard = self.into_mapped();
+        guard.recovery = Some(mutex);
+        guard
+    }
+
     /// Turn this guard into a guard which can be mapped to a sub-borrow.
     ///
     /// Note that a mapped guard cannot wait on a `Condvar`.
     pub fn into_mapped(self) -> MappedSharedMutexWriteGuard<'mutex, T> {
         let guard = MappedSharedMutexWriteGuard {
             mutex: &self.mutex.raw,
-            poison: unsafe { ptr::read(&self.data).into_raw() },
+            recovery: None,
+            hold: WriteHold::Unique(ManuallyDrop::new(unsafe { ManuallyDrop::into_inner(ptr::read(&self.data)) }.into_raw())),
             data: unsafe { (&mut *self.mutex.data.get()).get_mut() }
         };
 
@@ -225,6 +1058,31 @@ impl<'mutex, T: ?Sized> SharedMutexWriteGuard<'mutex, T> {
         guard
     }
 
+    /// Try to narrow this guard to a sub-borrow with `map`, then run either
+    /// `mapped` with the narrowed guard, or `whole` with this guard if `map`
+    /// returns `None`.
+    ///
+    /// Expresses "narrow if possible, else operate on the whole" without
+    /// going through `into_mapped`, `result_map` and `recover` by hand.
+    pub fn map_or_recover<U: ?Sized + 'mutex, M, F, G, R>(self, map: M, mapped: F, whole: G) -> R
+    where M: FnOnce(&mut T) -> Option<&mut U>,
+          F: FnOnce(MappedSharedMutexWriteGuard<'mutex, U>) -> R,
+          G: FnOnce(SharedMutexWriteGuard<'mutex, T>) -> R {
+        let mutex = self.mutex;
+
+        match self.into_mapped().result_map(|data| map(data).ok_or(())) {
+            Ok(guard) => mapped(guard),
+            Err((guard, ())) => {
+                // Take over the lock held by the mapped guard.
+                mem::forget(guard);
+
+                // This guard was already handed out, poisoned or not.
+                let guard = unsafe { SharedMutexWriteGuard::new(mutex) };
+                whole(guard.unwrap_or_else(|poisoned| poisoned.into_inner()))
+            }
+        }
+    }
+
     /// Wait on the given condition variable, and resume with another write lock.
     pub fn wait_for_write(self, cond: &Condvar) -> LockResult<Self> {
         self.mutex.raw.wait_from_write_to_write(cond);
@@ -248,420 +1106,3070 @@ impl<'mutex, T: ?Sized> SharedMutexWriteGuard<'mutex, T> {
 
         guard
     }
-}
-
-impl<'mutex, T: ?Sized> Drop for SharedMutexReadGuard<'mutex, T> {
-    #[inline]
-    fn drop(&mut self) { self.mutex.raw.unlock_read() }
-}
 
-impl<'mutex, T: ?Sized> Drop for SharedMutexWriteGuard<'mutex, T> {
+    /// Wait on the given condition variable, resume with another write lock
+    /// and map it with `action`.
+    ///
+    /// Saves narrowing the resumed guard by hand when only part of the data
+    /// is needed after waiting.
     #[inline]
-    fn drop(&mut self) { self.mutex.raw.unlock_write() }
-}
-
-/// A read guard to a sub-borrow of an original SharedMutexReadGuard.
-///
-/// Unlike SharedMutexReadGuard, it cannot be used to wait on a
-/// `Condvar`.
-pub struct MappedSharedMutexReadGuard<'mutex, T: ?Sized + 'mutex> {
-    mutex: &'mutex RawSharedMutex,
-    data: &'mutex T
-}
-
-/// A write guard to a sub-borrow of an original `SharedMutexWriteGuard`.
-///
-/// Unlike `SharedMutexWriteGuard`, it cannot be used to wait on a
-/// `Condvar`.
-pub struct MappedSharedMutexWriteGuard<'mutex, T: ?Sized + 'mutex> {
-    mutex: &'mutex RawSharedMutex,
-    poison: RawPoisonGuard<'mutex>,
-    data: &'mutex mut T,
-}
+    pub fn wait_for_write_then_map<U: ?Sized, F>(self, cond: &Condvar, action: F)
+        -> LockResult<MappedSharedMutexWriteGuard<'mutex, U>>
+    where F: FnOnce(&mut T) -> &mut U {
+        poison::map_result(self.wait_for_write(cond), |guard| guard.into_mapped().map(action))
+    }
 
-impl<'mutex, T: ?Sized> MappedSharedMutexReadGuard<'mutex, T> {
-    /// Transform this guard into a sub-borrow of the original data.
+    /// Wait on the given condition variable, resume with a read lock and map
+    /// it with `action`.
     #[inline]
-    pub fn map<U: ?Sized, F>(self, action: F) -> MappedSharedMutexReadGuard<'mutex, U>
+    pub fn wait_for_read_then_map<U: ?Sized, F>(self, cond: &Condvar, action: F)
+        -> LockResult<MappedSharedMutexReadGuard<'mutex, U>>
     where F: FnOnce(&T) -> &U {
-        self.option_map(move |t| Some(action(t))).unwrap()
+        poison::map_result(self.wait_for_read(cond), |guard| guard.into_mapped().map(action))
     }
 
-    /// Conditionally transform this guard into a sub-borrow of the original data.
-    #[inline]
-    pub fn option_map<U: ?Sized, F>(self, action: F) -> Option<MappedSharedMutexReadGuard<'mutex, U>>
-    where F: FnOnce(&T) -> Option<&U> {
-        self.result_map(move |t| action(t).ok_or(())).ok()
+    /// Wait on the given condition variable while `condition` returns true,
+    /// and resume with another write lock.
+    ///
+    /// `condition` is checked before waiting and after every wakeup, so
+    /// spurious wakeups are handled. Returns early if the mutex is found to
+    /// be poisoned.
+    pub fn wait_while_for_write<F>(self, cond: &Condvar, mut condition: F) -> LockResult<Self>
+    where F: FnMut(&mut T) -> bool {
+        let mut guard = self;
+
+        while condition(&mut guard) {
+            guard = try!(guard.wait_for_write(cond));
+        }
+
+        Ok(guard)
     }
 
-    /// Conditionally transform this guard into a sub-borrow of the original data.
+    /// Wait on the given condition variable while `condition` returns true,
+    /// for at most `timeout` in total, and resume with another write lock.
     ///
-    /// If the transformation operation is aborted, returns the original guard.
-    #[inline]
-    pub fn result_map<U: ?Sized, E, F>(self, action: F)
-        -> Result<MappedSharedMutexReadGuard<'mutex, U>, (Self, E)>
-    where F: FnOnce(&T) -> Result<&U, E> {
-        let data = self.data;
-        let mutex = self.mutex;
+    /// `condition` is checked before waiting and after every wakeup, so
+    /// spurious wakeups are handled. Each wait only lasts for the remainder of
+    /// `timeout`, so the bound applies to the whole operation rather than to
+    /// each individual wait.
+    ///
+    /// The returned guard always holds the write lock; if the timeout elapsed
+    /// while `condition` was still true, `WaitTimeoutResult::timed_out` is true.
+    pub fn wait_while_for_write_timeout<F>(self, cond: &Condvar, mut condition: F,
+                                           timeout: Duration) -> LockResult<(Self, WaitTimeoutResult)>
+    where F: FnMut(&mut T) -> bool {
+        let start = Instant::now();
+        let mut guard = self;
 
-        match action(data) {
-            Ok(new_data) => {
-                // Don't double-unlock.
-                mem::forget(self);
+        loop {
+            if !condition(&mut guard) {
+                return Ok((guard, WaitTimeoutResult(false)));
+            }
 
-                Ok(MappedSharedMutexReadGuard {
-                    data: new_data,
-                    mutex: mutex
-                })
-            },
-            Err(e) => { Err((self, e)) }
+            let remaining = match timeout.checked_sub(start.elapsed()) {
+                Some(remaining) => remaining,
+                None => return Ok((guard, WaitTimeoutResult(true)))
+            };
+
+            guard = match guard.wait_timeout_for_write(cond, remaining) {
+                Ok((guard, _)) => guard,
+                Err(poisoned) => return Err(poisoned)
+            };
         }
     }
 
-    /// Recover the original guard for waiting.
+    /// Release the write lock, then notify the given condition variable.
     ///
-    /// Takes the original mutex to recover the original type and data. If the
-    /// passed mutex is not the same object as the original mutex, returns `Err`.
-    #[inline]
-    pub fn recover<U: ?Sized>(self, mutex: &'mutex SharedMutex<U>) -> Result<SharedMutexReadGuard<'mutex, U>, Self> {
-        if self.mutex.is(&mutex.raw) {
-            // The mutex can't have become poisoned since we are continuously holding a guard.
-            let guard = unsafe { SharedMutexReadGuard::new(mutex) }.unwrap();
+    /// Releasing first means woken threads don't immediately block again on
+    /// the lock this guard was still holding.
+    pub fn release_and_notify(self, cond: &Condvar, kind: NotifyKind) {
+        drop(self);
+        kind.notify(cond)
+    }
 
-            // Don't double-unlock.
-            mem::forget(self);
+    /// Move the data out into an `Arc` and release the write lock, so that
+    /// any number of threads can read it without locking.
+    ///
+    /// The mutex is left holding `T::default()`, unlocked, and poisoned only
+    /// if it already was. Use `freeze_clone` to keep the data in the mutex
+    /// instead.
+    pub fn freeze(mut self) -> Arc<T>
+    where T: Default {
+        Arc::new(mem::replace(&mut *self, T::default()))
+    }
 
-            Ok(guard)
-        } else {
-            Err(self)
-        }
+    /// Clone the data into an `Arc` and release the write lock, so that any
+    /// number of threads can read the snapshot without locking.
+    ///
+    /// Unlike `freeze`, the mutex keeps its data, and later writes to it are
+    /// not seen through the returned `Arc`.
+    pub fn freeze_clone(self) -> Arc<T>
+    where T: Clone {
+        Arc::new((*self).clone())
     }
-}
 
-impl<'mutex, T: ?Sized> MappedSharedMutexWriteGuard<'mutex, T> {
-    /// Transform this guard into a sub-borrow of the original data.
-    #[inline]
-    pub fn map<U: ?Sized, F>(self, action: F) -> MappedSharedMutexWriteGuard<'mutex, U>
-    where F: FnOnce(&mut T) -> &mut U {
-        self.option_map(move |t| Some(action(t))).unwrap()
+    /// Turn this guard into one which wakes all threads waiting on the given
+    /// condition variable once it is dropped.
+    ///
+    /// Like `release_and_notify`, the lock is released before notifying, but
+    /// the data can still be changed until then, and the notification is sent
+    /// exactly once however the guard goes out of scope.
+    pub fn notify_all_on_drop(self, cond: &'mutex Condvar) -> NotifyOnDropGuard<'mutex, T> {
+        NotifyOnDropGuard { guard: ManuallyDrop::new(self), cond: cond, kind: NotifyKind::All }
     }
 
-    /// Conditionally transform this guard into a sub-borrow of the original data.
-    #[inline]
-    pub fn option_map<U: ?Sized, F>(self, action: F) -> Option<MappedSharedMutexWriteGuard<'mutex, U>>
-    where F: FnOnce(&mut T) -> Option<&mut U> {
-        self.result_map(move |t| action(t).ok_or(())).ok()
+    /// Turn this guard into one which wakes one thread waiting on the given
+    /// condition variable once it is dropped.
+    ///
+    /// See `notify_all_on_drop` for more information.
+    pub fn notify_one_on_drop(self, cond: &'mutex Condvar) -> NotifyOnDropGuard<'mutex, T> {
+        NotifyOnDropGuard { guard: ManuallyDrop::new(self), cond: cond, kind: NotifyKind::One }
     }
 
-    /// Conditionally transform this guard into a sub-borrow of the original data.
+    /// Release the write lock, handing it directly to the longest waiting
+    /// thread instead of letting the woken threads race for it.
     ///
-    /// If the transformation operation is aborted, returns the original guard.
-    #[inline]
-    pub fn result_map<U: ?Sized, E, F>(self, action: F)
-        -> Result<MappedSharedMutexWriteGuard<'mutex, U>, (Self, E)>
-    where F: FnOnce(&mut T) -> Result<&mut U, E> {
-        let data = unsafe { ptr::read(&self.data) };
+    /// This bounds how long threads wait for the lock, but costs throughput,
+    /// since the lock stays unused until the next thread has woken up. See
+    /// `RawSharedMutex::unlock_write_fair` for more information.
+    pub fn unlock_fair(mut self) {
         let mutex = self.mutex;
 
-        match action(data) {
-            Ok(new_data) => {
-                let poison = unsafe { ptr::read(&self.poison) };
+        // Release the poison guard first, as in `drop`.
+        unsafe { ManuallyDrop::drop(&mut self.data) };
 
-                // Don't double-unlock.
-                mem::forget(self);
+        // Don't double-unlock.
+        mem::forget(self);
 
-                Ok(MappedSharedMutexWriteGuard {
-                    data: new_data,
-                    poison: poison,
-                    mutex: mutex
-                })
-            },
-            Err(e) => { Err((self, e)) }
-        }
+        mutex.raw.unlock_write_fair()
     }
 
-    /// Recover the original guard for waiting.
+    /// Give up the guard without ever releasing the write lock, returning a
+    /// reference to the data which lasts as long as the mutex.
     ///
-    /// Takes the original mutex to recover the original type and data. If the
-    /// passed mutex is not the same object as the original mutex, returns `Err`.
-    #[inline]
-    pub fn recover<U: ?Sized>(self, mutex: &'mutex SharedMutex<U>) -> Result<SharedMutexWriteGuard<'mutex, U>, Self> {
-        if self.mutex.is(&mutex.raw) {
-            // The mutex can't have become poisoned since we are continuously holding a guard.
-            let guard = unsafe { SharedMutexWriteGuard::new(mutex) }.unwrap();
+    /// **The mutex stays write-locked forever**: any other attempt to lock it
+    /// blocks or fails. Meant for mutexes which live for the rest of the
+    /// program, such as from `SharedMutex::leak_static`.
+    pub fn leak(self) -> &'mutex mut T {
+        let data = unsafe { ManuallyDrop::into_inner(ptr::read(&self.data)).into_mut() };
+        self.mutex.raw.forget_level();
 
-            // Don't double-unlock.
-            mem::forget(self);
+        // Never unlock.
+        mem::forget(self);
 
-            Ok(guard)
-        } else {
-            Err(self)
-        }
+        data
     }
-}
 
-impl<'mutex, T: ?Sized> Deref for MappedSharedMutexReadGuard<'mutex, T> {
-    type Target = T;
+    /// Wait on the given condition variable for at most `timeout`, and
+    /// resume with a read lock.
+    ///
+    /// See `SharedMutexReadGuard::wait_timeout_for_write` for more
+    /// information.
+    pub fn wait_timeout_for_read(self, cond: &Condvar, timeout: Duration)
+        -> LockResult<(SharedMutexReadGuard<'mutex, T>, WaitTimeoutResult)> {
+        let timed_out = self.mutex.raw.wait_timeout_from_write_to_read(cond, timeout);
+
+        let guard = unsafe { SharedMutexReadGuard::new(self.mutex) };
+
+        // Don't double-unlock.
+        mem::forget(self);
+
+        poison::map_result(guard, |guard| (guard, WaitTimeoutResult(timed_out)))
+    }
+
+    /// Wait on the given condition variable for at most `timeout`, and
+    /// resume with another write lock.
+    ///
+    /// See `SharedMutexReadGuard::wait_timeout_for_write` for more
+    /// information.
+    pub fn wait_timeout_for_write(self, cond: &Condvar, timeout: Duration) -> LockResult<(Self, WaitTimeoutResult)> {
+        let timed_out = self.mutex.raw.wait_timeout_from_write_to_write(cond, timeout);
+
+        let guard = unsafe { SharedMutexWriteGuard::new(self.mutex) };
+
+        // Don't double-unlock.
+        mem::forget(self);
+
+        poison::map_result(guard, |guard| (guard, WaitTimeoutResult(timed_out)))
+    }
+}
+
+/// Whether a timed wait on a condition variable returned because of a timeout.
+///
+/// The equivalent of `std::sync::WaitTimeoutResult` for the timed waits in
+/// this crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitTimeoutResult(bool);
 
+impl WaitTimeoutResult {
+    /// Returns true if the wait was known to have timed out.
     #[inline]
-    fn deref(&self) -> &T { self.data }
+    pub fn timed_out(&self) -> bool { self.0 }
 }
 
-impl<'mutex, T: ?Sized> Deref for MappedSharedMutexWriteGuard<'mutex, T> {
+/// The error returned when a lock couldn't be acquired in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimedOut;
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("timed out acquiring the lock")
+    }
+}
+
+impl Error for TimedOut {}
+
+/// Why `SharedMutex::try_write_detailed` didn't return a guard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteBlockedBy {
+    /// The lock was held by this many readers.
+    Readers(usize),
+
+    /// The lock was held by a writer.
+    Writer,
+
+    /// The lock was acquired, but is poisoned. The lock is released again.
+    Poisoned
+}
+
+impl fmt::Display for WriteBlockedBy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WriteBlockedBy::Readers(readers) => write!(f, "write lock held by {} readers", readers),
+            WriteBlockedBy::Writer => f.write_str("write lock held by a writer"),
+            WriteBlockedBy::Poisoned => f.write_str("poisoned lock: another task failed inside")
+        }
+    }
+}
+
+impl Error for WriteBlockedBy {}
+
+/// How many waiters to wake when notifying a condition variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotifyKind {
+    /// Wake one waiter, as with `Condvar::notify_one`.
+    One,
+
+    /// Wake all waiters, as with `Condvar::notify_all`.
+    All
+}
+
+impl NotifyKind {
+    #[inline]
+    fn notify(self, cond: &Condvar) {
+        match self {
+            NotifyKind::One => cond.notify_one(),
+            NotifyKind::All => cond.notify_all()
+        }
+    }
+}
+
+/// A write guard which notifies a condition variable once it is released.
+///
+/// Created by `SharedMutexWriteGuard::notify_all_on_drop` and
+/// `SharedMutexWriteGuard::notify_one_on_drop`.
+pub struct NotifyOnDropGuard<'mutex, T: ?Sized + 'mutex> {
+    // Released before notifying, so woken threads don't block on it.
+    guard: ManuallyDrop<SharedMutexWriteGuard<'mutex, T>>,
+    cond: &'mutex Condvar,
+    kind: NotifyKind
+}
+
+impl<'mutex, T: ?Sized> Deref for NotifyOnDropGuard<'mutex, T> {
     type Target = T;
 
     #[inline]
-    fn deref(&self) -> &T { self.data }
+    fn deref(&self) -> &T { &self.guard }
 }
 
-impl<'mutex, T: ?Sized> DerefMut for MappedSharedMutexWriteGuard<'mutex, T> {
+impl<'mutex, T: ?Sized> DerefMut for NotifyOnDropGuard<'mutex, T> {
     #[inline]
-    fn deref_mut(&mut self) -> &mut T { self.data }
+    fn deref_mut(&mut self) -> &mut T { &mut self.guard }
+}
+
+impl<'mutex, T: ?Sized> Drop for NotifyOnDropGuard<'mutex, T> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        self.kind.notify(self.cond)
+    }
+}
+
+impl<'mutex, T: ?Sized> Drop for SharedMutexReadGuard<'mutex, T> {
+    #[inline]
+    fn drop(&mut self) { self.mutex.raw.unlock_read() }
+}
+
+impl<'mutex, T: ?Sized> Drop for SharedMutexUpgradableReadGuard<'mutex, T> {
+    #[inline]
+    fn drop(&mut self) { self.mutex.raw.unlock_upgradable_read() }
+}
+
+impl<'mutex, T: ?Sized> Drop for SharedMutexWriteGuard<'mutex, T> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.data) };
+        self.mutex.raw.unlock_write()
+    }
+}
+
+/// A read guard to a sub-borrow of an original SharedMutexReadGuard.
+///
+/// Unlike SharedMutexReadGuard, it cannot be used to wait on a
+/// `Condvar`.
+pub struct MappedSharedMutexReadGuard<'mutex, T: ?Sized + 'mutex> {
+    mutex: &'mutex RawSharedMutex,
+    recovery: Option<&'mutex (Any + Send + Sync)>,
+    data: &'mutex T
+}
+
+/// A write guard to a sub-borrow of an original `SharedMutexWriteGuard`.
+///
+/// Unlike `SharedMutexWriteGuard`, it cannot be used to wait on a
+/// `Condvar`.
+pub struct MappedSharedMutexWriteGuard<'mutex, T: ?Sized + 'mutex> {
+    mutex: &'mutex RawSharedMutex,
+    recovery: Option<&'mutex (Any + Send + Sync)>,
+    hold: WriteHold<'mutex>,
+    data: &'mutex mut T,
+}
+
+// How a mapped write guard holds the write lock.
+enum WriteHold<'mutex> {
+    // The guard is the only one holding the lock.
+    Unique(ManuallyDrop<RawPoisonGuard<'mutex>>),
+
+    // The hold is split between several guards, the lock is released when
+    // the last of them is dropped.
+    Shared(Arc<SharedWriteHold<'mutex>>)
+}
+
+struct SharedWriteHold<'mutex> {
+    mutex: &'mutex RawSharedMutex,
+
+    // Taken and released by the first guard dropped while panicking, since
+    // the last guard may be dropped by a thread which is not panicking.
+    poison: Mutex<Option<RawPoisonGuard<'mutex>>>
+}
+
+impl<'mutex> SharedWriteHold<'mutex> {
+    #[inline]
+    fn release_part(&self) {
+        if thread::panicking() {
+            let mut poison = self.poison.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            drop(poison.take());
+        }
+    }
+}
+
+impl<'mutex> Drop for SharedWriteHold<'mutex> {
+    fn drop(&mut self) {
+        let poison = self.poison.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner());
+        drop(poison.take());
+        self.mutex.unlock_write()
+    }
+}
+
+impl<'mutex, T: ?Sized> MappedSharedMutexReadGuard<'mutex, T> {
+    /// Check whether this guard holds a lock on `mutex`.
+    ///
+    /// Compares the underlying locks only, so `mutex` may have any type.
+    #[inline]
+    pub fn points_to<U: ?Sized>(&self, mutex: &SharedMutex<U>) -> bool {
+        self.mutex.is(&mutex.raw)
+    }
+
+    /// Borrow the data this guard currently points to.
+    ///
+    /// The same as dereferencing the guard, spelled out for generic code.
+    #[inline]
+    pub fn reborrow(&self) -> &T { &**self }
+
+    /// Transform this guard into a sub-borrow of the original data.
+    #[inline]
+    pub fn map<U: ?Sized, F>(self, action: F) -> MappedSharedMutexReadGuard<'mutex, U>
+    where F: FnOnce(&T) -> &U {
+        self.option_map(move |t| Some(action(t))).unwrap()
+    }
+
+    /// Transform this guard into a sub-borrow of the element at `index`.
+    ///
+    /// Shorthand for `map(|t| &t[index])`, so it panics if `index` is out of
+    /// bounds.
+    #[inline]
+    pub fn index<I>(self, index: I) -> MappedSharedMutexReadGuard<'mutex, T::Output>
+    where T: Index<I> {
+        self.map(move |t| &t[index])
+    }
+
+    /// Conditionally transform this guard into a sub-borrow of the original data.
+    ///
+    /// If `action` returns `None`, releases the lock. Use `try_map` to get
+    /// the original guard back instead, for example to try another mapping.
+    #[inline]
+    pub fn option_map<U: ?Sized, F>(self, action: F) -> Option<MappedSharedMutexReadGuard<'mutex, U>>
+    where F: FnOnce(&T) -> Option<&U> {
+        self.result_map(move |t| action(t).ok_or(())).ok()
+    }
+
+    /// Conditionally transform this guard into a sub-borrow of the original data.
+    ///
+    /// If `action` returns `None`, returns the original guard.
+    #[inline]
+    pub fn try_map<U: ?Sized, F>(self, action: F) -> Result<MappedSharedMutexReadGuard<'mutex, U>, Self>
+    where F: FnOnce(&T) -> Option<&U> {
+        self.result_map(move |t| action(t).ok_or(())).map_err(|(guard, ())| guard)
+    }
+
+    /// Conditionally transform this guard into a sub-borrow of the original data.
+    ///
+    /// If the transformation operation is aborted, releases the lock and
+    /// returns just the error, for callers which give up rather than retry.
+    #[inline]
+    pub fn result_map_dropping<U: ?Sized, E, F>(self, action: F) -> Result<MappedSharedMutexReadGuard<'mutex, U>, E>
+    where F: FnOnce(&T) -> Result<&U, E> {
+        self.result_map(action).map_err(|(_, error)| error)
+    }
+
+    /// Conditionally transform this guard into a sub-borrow of the original data.
+    ///
+    /// If the transformation operation is aborted, returns the original guard.
+    #[inline]
+    pub fn result_map<U: ?Sized, E, F>(self, action: F)
+        -> Result<MappedSharedMutexReadGuard<'mutex, U>, (Self, E)>
+    where F: FnOnce(&T) -> Result<&U, E> {
+        let data = self.data;
+        let mutex = self.mutex;
+        let recovery = self.recovery;
+
+        match action(data) {
+            Ok(new_data) => {
+                // Don't double-unlock.
+                mem::forget(self);
+
+                Ok(MappedSharedMutexReadGuard {
+                    data: new_data,
+                    recovery: recovery,
+                    mutex: mutex
+                })
+            },
+            Err(e) => { Err((self, e)) }
+        }
+    }
+
+    /// Recover the original guard for waiting.
+    ///
+    /// Takes the original mutex to recover the original type and data. If the
+    /// passed mutex is not the same object as the original mutex, returns `Err`.
+    #[inline]
+    pub fn recover<U: ?Sized>(self, mutex: &'mutex SharedMutex<U>) -> Result<SharedMutexReadGuard<'mutex, U>, Self> {
+        if self.mutex.is(&mutex.raw) {
+            // The mutex can't have become poisoned since we are continuously holding a guard.
+            let guard = unsafe { SharedMutexReadGuard::new(mutex) }.unwrap();
+
+            // Don't double-unlock.
+            mem::forget(self);
+
+            Ok(guard)
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Recover the original guard for waiting, without the original mutex.
+    ///
+    /// Only guards created through `into_mapped_recoverable` can be recovered
+    /// this way. Returns `Err` if this guard can't be recovered or if `U` is
+    /// not the type of the original mutex.
+    #[inline]
+    pub fn recover_typed<U>(self) -> Result<SharedMutexReadGuard<'mutex, U>, Self>
+    where U: Any + Send + Sync {
+        match self.recovery.and_then(|mutex| mutex.downcast_ref::<SharedMutex<U>>()) {
+            Some(mutex) => self.recover(mutex),
+            None => Err(self)
+        }
+    }
+
+    /// Wait on the given condition variable, and resume with a full read
+    /// guard on the original data of type `R`.
+    ///
+    /// Like `recover_typed`, only works for guards created through
+    /// `into_mapped_recoverable`, returning `Err` with this guard otherwise.
+    pub fn wait_for_read<R>(self, cond: &Condvar) -> Result<LockResult<SharedMutexReadGuard<'mutex, R>>, Self>
+    where R: Any + Send + Sync {
+        self.recover_typed::<R>().map(|guard| guard.wait_for_read(cond))
+    }
+
+    /// Wait on the given condition variable, and resume with a full write
+    /// guard on the original data of type `R`.
+    ///
+    /// See `wait_for_read` for more information.
+    pub fn wait_for_write<R>(self, cond: &Condvar) -> Result<LockResult<SharedMutexWriteGuard<'mutex, R>>, Self>
+    where R: Any + Send + Sync {
+        self.recover_typed::<R>().map(|guard| guard.wait_for_write(cond))
+    }
 }
 
-impl<'mutex, T: ?Sized> Drop for MappedSharedMutexReadGuard<'mutex, T> {
-    #[inline]
-    fn drop(&mut self) { self.mutex.unlock_read() }
-}
+impl<'mutex, T: ?Sized> MappedSharedMutexWriteGuard<'mutex, T> {
+    /// Check whether this guard holds a lock on `mutex`.
+    ///
+    /// Compares the underlying locks only, so `mutex` may have any type.
+    #[inline]
+    pub fn points_to<U: ?Sized>(&self, mutex: &SharedMutex<U>) -> bool {
+        self.mutex.is(&mutex.raw)
+    }
+
+    /// Borrow the data this guard currently points to.
+    ///
+    /// The same as dereferencing the guard, spelled out for generic code.
+    #[inline]
+    pub fn reborrow(&self) -> &T { &**self }
+
+    /// Mutably borrow the data this guard currently points to.
+    ///
+    /// See `reborrow` for more information.
+    #[inline]
+    pub fn reborrow_mut(&mut self) -> &mut T { &mut **self }
+
+    /// Transform this guard into a sub-borrow of the original data.
+    #[inline]
+    pub fn map<U: ?Sized, F>(self, action: F) -> MappedSharedMutexWriteGuard<'mutex, U>
+    where F: FnOnce(&mut T) -> &mut U {
+        self.option_map(move |t| Some(action(t))).unwrap()
+    }
+
+    /// Transform this guard into a sub-borrow of the element at `index`.
+    ///
+    /// Shorthand for `map(|t| &mut t[index])`, so it panics if `index` is out
+    /// of bounds.
+    #[inline]
+    pub fn index_mut<I>(self, index: I) -> MappedSharedMutexWriteGuard<'mutex, T::Output>
+    where T: IndexMut<I> {
+        self.map(move |t| &mut t[index])
+    }
+
+    /// Conditionally transform this guard into a sub-borrow of the original data.
+    ///
+    /// If `action` returns `None`, releases the lock. Use `try_map` to get
+    /// the original guard back instead, for example to try another mapping.
+    #[inline]
+    pub fn option_map<U: ?Sized, F>(self, action: F) -> Option<MappedSharedMutexWriteGuard<'mutex, U>>
+    where F: FnOnce(&mut T) -> Option<&mut U> {
+        self.result_map(move |t| action(t).ok_or(())).ok()
+    }
+
+    /// Conditionally transform this guard into a sub-borrow of the original data.
+    ///
+    /// If `action` returns `None`, returns the original guard.
+    #[inline]
+    pub fn try_map<U: ?Sized, F>(self, action: F) -> Result<MappedSharedMutexWriteGuard<'mutex, U>, Self>
+    where F: FnOnce(&mut T) -> Option<&mut U> {
+        self.result_map(move |t| action(t).ok_or(())).map_err(|(guard, ())| guard)
+    }
+
+    /// Conditionally transform this guard into a sub-borrow of the original data.
+    ///
+    /// If the transformation operation is aborted, releases the lock and
+    /// returns just the error, for callers which give up rather than retry.
+    #[inline]
+    pub fn result_map_dropping<U: ?Sized, E, F>(self, action: F) -> Result<MappedSharedMutexWriteGuard<'mutex, U>, E>
+    where F: FnOnce(&mut T) -> Result<&mut U, E> {
+        self.result_map(action).map_err(|(_, error)| error)
+    }
+
+    /// Conditionally transform this guard into a sub-borrow of the original data.
+    ///
+    /// If the transformation operation is aborted, returns the original guard.
+    #[inline]
+    pub fn result_map<U: ?Sized, E, F>(self, action: F)
+        -> Result<MappedSharedMutexWriteGuard<'mutex, U>, (Self, E)>
+    where F: FnOnce(&mut T) -> Result<&mut U, E> {
+        let data = unsafe { ptr::read(&self.data) };
+        let mutex = self.mutex;
+        let recovery = self.recovery;
+
+        match action(data) {
+            Ok(new_data) => {
+                let hold = unsafe { ptr::read(&self.hold) };
+
+                // Don't double-unlock.
+                mem::forget(self);
+
+                Ok(MappedSharedMutexWriteGuard {
+                    data: new_data,
+                    hold: hold,
+                    recovery: recovery,
+                    mutex: mutex
+                })
+            },
+            Err(e) => { Err((self, e)) }
+        }
+    }
+
+    /// Recover the original guard for waiting.
+    ///
+    /// Takes the original mutex to recover the original type and data. If the
+    /// passed mutex is not the same object as the original mutex, returns `Err`.
+    ///
+    /// Guards which share their hold on the lock with other guards, such as
+    /// the halves from `map_split`, can't be recovered.
+    #[inline]
+    pub fn recover<U: ?Sized>(self, mutex: &'mutex SharedMutex<U>) -> Result<SharedMutexWriteGuard<'mutex, U>, Self> {
+        let unique = match self.hold { WriteHold::Unique(_) => true, WriteHold::Shared(_) => false };
+
+        if unique && self.mutex.is(&mutex.raw) {
+            // The mutex can't have become poisoned since we are continuously holding a guard.
+            let guard = unsafe { SharedMutexWriteGuard::new(mutex) }.unwrap();
+
+            // Don't double-unlock.
+            mem::forget(self);
+
+            Ok(guard)
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Recover the original guard for waiting, without the original mutex.
+    ///
+    /// Only guards created through `into_mapped_recoverable` can be recovered
+    /// this way. Returns `Err` if this guard can't be recovered or if `U` is
+    /// not the type of the original mutex.
+    #[inline]
+    pub fn recover_typed<U>(self) -> Result<SharedMutexWriteGuard<'mutex, U>, Self>
+    where U: Any + Send + Sync {
+        match self.recovery.and_then(|mutex| mutex.downcast_ref::<SharedMutex<U>>()) {
+            Some(mutex) => self.recover(mutex),
+            None => Err(self)
+        }
+    }
+
+    /// Wait on the given condition variable, and resume with a full write
+    /// guard on the original data of type `R`.
+    ///
+    /// Like `recover_typed`, only works for guards created through
+    /// `into_mapped_recoverable`, returning `Err` with this guard otherwise.
+    /// See `wait_for_write_remap` to narrow the new guard right away.
+    pub fn wait_for_write<R>(self, cond: &Condvar) -> Result<LockResult<SharedMutexWriteGuard<'mutex, R>>, Self>
+    where R: Any + Send + Sync {
+        self.recover_typed::<R>().map(|guard| guard.wait_for_write(cond))
+    }
+
+    /// Wait on the given condition variable, and resume with a full read
+    /// guard on the original data of type `R`.
+    ///
+    /// See `wait_for_write` for more information.
+    pub fn wait_for_read<R>(self, cond: &Condvar) -> Result<LockResult<SharedMutexReadGuard<'mutex, R>>, Self>
+    where R: Any + Send + Sync {
+        self.recover_typed::<R>().map(|guard| guard.wait_for_read(cond))
+    }
+
+    /// Wait on the given condition variable, and resume with a write guard
+    /// mapped by `remap`.
+    ///
+    /// The data may change while waiting, so rather than reusing this guard's
+    /// sub-borrow, `remap` narrows the original data of type `R` again. Like
+    /// `recover_typed`, only works for guards created through
+    /// `into_mapped_recoverable`, returning `Err` with this guard otherwise.
+    /// The new guard can be remapped again in the same way.
+    pub fn wait_for_write_remap<R, U: ?Sized, F>(self, cond: &Condvar, remap: F)
+        -> Result<LockResult<MappedSharedMutexWriteGuard<'mutex, U>>, Self>
+    where R: Any + Send + Sync, F: FnOnce(&mut R) -> &mut U {
+        self.recover_typed::<R>().map(|guard| {
+            poison::map_result(guard.wait_for_write(cond), |guard| {
+                guard.into_mapped_recoverable().map(remap)
+            })
+        })
+    }
+
+    /// Split this guard into two guards over disjoint parts of the data.
+    ///
+    /// The two guards jointly hold the write lock, which is only released once
+    /// both are dropped, so each part can be handed to a different worker.
+    /// Either of them can be split further, but neither can be recovered.
+    pub fn map_split<U: ?Sized, V: ?Sized, F>(self, action: F)
+        -> (MappedSharedMutexWriteGuard<'mutex, U>, MappedSharedMutexWriteGuard<'mutex, V>)
+    where F: FnOnce(&mut T) -> (&mut U, &mut V) {
+        let (first, second) = action(unsafe { ptr::read(&self.data) });
+        let mutex = self.mutex;
+
+        let hold = match unsafe { ptr::read(&self.hold) } {
+            WriteHold::Unique(poison) => Arc::new(SharedWriteHold {
+                mutex: mutex,
+                poison: Mutex::new(Some(ManuallyDrop::into_inner(poison)))
+            }),
+            WriteHold::Shared(hold) => hold
+        };
+
+        // Don't double-unlock.
+        mem::forget(self);
+
+        let first = MappedSharedMutexWriteGuard {
+            mutex: mutex,
+            recovery: None,
+            hold: WriteHold::Shared(hold.clone()),
+            data: first
+        };
+
+        let second = MappedSharedMutexWriteGuard {
+            mutex: mutex,
+            recovery: None,
+            hold: WriteHold::Shared(hold),
+            data: second
+        };
+
+        (first, second)
+    }
+}
+
+impl<'mutex, T> MappedSharedMutexWriteGuard<'mutex, [T]> {
+    /// Split this guard into guards over `[0, mid)` and `[mid, len)`.
+    ///
+    /// The two guards jointly hold the write lock, which is only released once
+    /// both are dropped, so they can be handed to different threads to work
+    /// on their halves in parallel. Either of them can be split further.
+    ///
+    /// Panics if `mid > len`.
+    pub fn split_at_mut_mapped(self, mid: usize) -> (Self, Self) {
+        self.map_split(|slice| slice.split_at_mut(mid))
+    }
+}
+
+/// Takes another read lock for the clone, so each clone can be released
+/// independently, and writers are excluded until all of them are dropped.
+///
+/// The lock is taken recursively, so cloning doesn't queue behind a writer
+/// which is waiting for the original guard to be released.
+impl<'mutex, T: ?Sized> Clone for MappedSharedMutexReadGuard<'mutex, T> {
+    fn clone(&self) -> Self {
+        self.mutex.read_recursive();
+
+        MappedSharedMutexReadGuard {
+            mutex: self.mutex,
+            recovery: self.recovery,
+            data: self.data
+        }
+    }
+}
+
+impl<'mutex, T: ?Sized> Deref for MappedSharedMutexReadGuard<'mutex, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T { self.data }
+}
+
+impl<'mutex, T: ?Sized> Deref for MappedSharedMutexWriteGuard<'mutex, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T { self.data }
+}
+
+impl<'mutex, T: ?Sized> DerefMut for MappedSharedMutexWriteGuard<'mutex, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T { self.data }
+}
+
+impl<'mutex, T: ?Sized> Drop for MappedSharedMutexReadGuard<'mutex, T> {
+    #[inline]
+    fn drop(&mut self) { self.mutex.unlock_read() }
+}
+
+impl<'mutex, T: ?Sized> Drop for MappedSharedMutexWriteGuard<'mutex, T> {
+    #[inline]
+    fn drop(&mut self) {
+        match self.hold {
+            WriteHold::Unique(ref mut poison) => {
+                unsafe { ManuallyDrop::drop(poison) };
+                self.mutex.unlock_write()
+            },
+            WriteHold::Shared(ref hold) => hold.release_part()
+        }
+    }
+}
+
+impl<T: ?Sized> SharedMutex<T> {
+    // Read-lock this mutex and `other`, and apply `action` to their data,
+    // whether or not they are poisoned.
+    //
+    // If both are the same mutex it is only locked once, since a second read
+    // lock could deadlock behind a waiting writer. Otherwise the mutex at the
+    // lower address is locked first, so that comparing `a` to `b` and `b` to
+    // `a` at the same time can't deadlock behind writers waiting on both.
+    fn with_both<R, F>(&self, other: &Self, action: F) -> R
+    where F: FnOnce(&T, &T) -> R {
+        if self.raw.is(&other.raw) {
+            let (this, _) = read_poisoned(self.read());
+            return action(&this, &this);
+        }
+
+        let address = |mutex: &Self| &mutex.raw as *const RawSharedMutex as usize;
+
+        if address(self) < address(other) {
+            let (this, _) = read_poisoned(self.read());
+            let (that, _) = read_poisoned(other.read());
+            action(&this, &that)
+        } else {
+            let (that, _) = read_poisoned(other.read());
+            let (this, _) = read_poisoned(self.read());
+            action(&this, &that)
+        }
+    }
+}
+
+// Extract a guard from a LockResult, along with whether it was poisoned.
+fn read_poisoned<G>(result: LockResult<G>) -> (G, bool) {
+    match result {
+        Ok(guard) => (guard, false),
+        Err(poisoned) => (poisoned.into_inner(), true)
+    }
+}
+
+impl<T: Default> Default for SharedMutex<T> {
+    #[inline]
+    fn default() -> Self { SharedMutex::new(T::default()) }
+}
+
+/// Creates an independent copy of the mutex and its data.
+///
+/// **The clone does not share the lock.** It is a new mutex, with a deep copy
+/// of the data taken under a read lock, and default settings. Locking one of
+/// them doesn't affect the other. Use an `Arc<SharedMutex<T>>` to share the
+/// lock instead. If this mutex is poisoned, so is the clone.
+impl<T: Clone> Clone for SharedMutex<T> {
+    fn clone(&self) -> Self {
+        match self.read() {
+            Ok(guard) => SharedMutex::new((*guard).clone()),
+            Err(poisoned) => {
+                let mut clone = SharedMutex {
+                    raw: RawSharedMutex::new(),
+                    data: UnsafeCell::new(Poison::poisoned((*poisoned.into_inner()).clone()))
+                };
+                clone.raw.set_panicked();
+                clone
+            }
+        }
+    }
+}
+
+/// Wraps the value in a new mutex, so functions can take any
+/// `impl Into<SharedMutex<T>>`:
+///
+/// ```
+/// use shared_mutex::SharedMutex;
+///
+/// let mutex: SharedMutex<i32> = 5.into();
+/// assert_eq!(*mutex.read().unwrap(), 5);
+/// ```
+impl<T> From<T> for SharedMutex<T> {
+    #[inline]
+    fn from(value: T) -> Self { SharedMutex::new(value) }
+}
+
+/// Compares the data in both mutexes, taking a read lock on each.
+///
+/// Since it takes locks, comparing mutexes can block. Poisoned mutexes
+/// compare by their last-known value, as if they weren't poisoned.
+impl<T: ?Sized + PartialEq> PartialEq for SharedMutex<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.with_both(other, |this, that| this == that)
+    }
+}
+
+impl<T: ?Sized + Eq> Eq for SharedMutex<T> {}
+
+/// Orders mutexes by their data, taking a read lock on each.
+///
+/// Since it takes locks, comparing mutexes can block. Like `PartialEq`,
+/// poisoned mutexes compare by their last-known value.
+impl<T: ?Sized + PartialOrd> PartialOrd for SharedMutex<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.with_both(other, |this, that| this.partial_cmp(that))
+    }
+}
+
+/// Orders mutexes by their data, taking a read lock on each.
+///
+/// Since it takes locks, comparing mutexes can block. Like `PartialEq`,
+/// poisoned mutexes compare by their last-known value.
+impl<T: ?Sized + Ord> Ord for SharedMutex<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.with_both(other, |this, that| this.cmp(that))
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for SharedMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut writer = f.debug_struct("SharedMutex");
+
+        match self.try_read() {
+            Ok(l) => writer.field("data", &&*l),
+            Err(TryLockError::WouldBlock) => writer.field("data", &"{{ locked }}"),
+            Err(TryLockError::Poisoned(_)) => writer.field("data", &"{{ poisoned }}")
+        }.finish()
+    }
+}
+
+impl<'mutex, T: ?Sized + fmt::Debug> fmt::Debug for SharedMutexReadGuard<'mutex, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SharedMutexReadGuard")
+            .field("data", &*self)
+            .finish()
+    }
+}
+
+impl<'mutex, T: ?Sized + fmt::Debug> fmt::Debug for SharedMutexUpgradableReadGuard<'mutex, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SharedMutexUpgradableReadGuard")
+            .field("data", &*self)
+            .finish()
+    }
+}
+
+impl<'mutex, T: ?Sized + fmt::Debug> fmt::Debug for SharedMutexWriteGuard<'mutex, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SharedMutexWriteGuard")
+            .field("data", &*self)
+            .finish()
+    }
+}
+
+impl<'mutex, T: ?Sized + fmt::Debug> fmt::Debug for NotifyOnDropGuard<'mutex, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NotifyOnDropGuard")
+            .field("data", &&**self)
+            .field("kind", &self.kind)
+            .finish()
+    }
+}
+
+impl<'mutex, T: ?Sized + fmt::Debug> fmt::Debug for MappedSharedMutexReadGuard<'mutex, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MappedSharedMutexReadGuard")
+            .field("data", &*self)
+            .finish()
+    }
+}
+
+impl<'mutex, T: ?Sized + fmt::Debug> fmt::Debug for MappedSharedMutexWriteGuard<'mutex, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MappedSharedMutexWriteGuard")
+            .field("data", &*self)
+            .finish()
+    }
+}
+
+// The guards point into the mutex rather than into themselves, so their
+// target doesn't move when they do.
+#[cfg(feature = "stable_deref_trait")]
+mod stable_deref {
+    use stable_deref_trait::{StableDeref, CloneStableDeref};
+
+    use super::*;
+
+    unsafe impl<'mutex, T: ?Sized> StableDeref for SharedMutexReadGuard<'mutex, T> {}
+    unsafe impl<'mutex, T: ?Sized> StableDeref for SharedMutexUpgradableReadGuard<'mutex, T> {}
+    unsafe impl<'mutex, T: ?Sized> StableDeref for SharedMutexWriteGuard<'mutex, T> {}
+    unsafe impl<'mutex, T: ?Sized> StableDeref for MappedSharedMutexReadGuard<'mutex, T> {}
+    unsafe impl<'mutex, T: ?Sized> StableDeref for MappedSharedMutexWriteGuard<'mutex, T> {}
+    unsafe impl<T: ?Sized> StableDeref for OwnedSharedMutexReadGuard<T> {}
+    unsafe impl<T: ?Sized> StableDeref for OwnedSharedMutexWriteGuard<T> {}
+    unsafe impl<T: ?Sized, U: ?Sized> StableDeref for OwnedMappedSharedMutexReadGuard<T, U> {}
+    unsafe impl<T: ?Sized, U: ?Sized> StableDeref for OwnedMappedSharedMutexWriteGuard<T, U> {}
+
+    unsafe impl<'mutex, T: ?Sized> CloneStableDeref for MappedSharedMutexReadGuard<'mutex, T> {}
+    unsafe impl<T: ?Sized> CloneStableDeref for OwnedSharedMutexReadGuard<T> {}
+}
+
+// A SharedMutex serializes as its data, read under a read lock.
+//
+// Poisoned data is serialized as is: poisoning only says a writer panicked,
+// and failing the whole serialization over it would lose the rest of the
+// document. Deserializing creates a new, unpoisoned mutex.
+#[cfg(feature = "serde")]
+mod serialize {
+    use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+    use super::*;
+
+    impl<T: ?Sized + Serialize> Serialize for SharedMutex<T> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let guard = self.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+            (*guard).serialize(serializer)
+        }
+    }
+
+    impl<'de, T: Deserialize<'de>> Deserialize<'de> for SharedMutex<T> {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            T::deserialize(deserializer).map(SharedMutex::new)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Condvar, Barrier};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::thread;
+    use std::time::Duration;
+    use scoped_pool::Pool;
+
+    use super::*;
+
+    fn poison<T: ?Sized>(mutex: &SharedMutex<T>) {
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _lock = mutex.write();
+            panic!("Poisoning the mutex.");
+        }));
+    }
+
+    fn _check_bounds() {
+        fn _is_send_sync<T: Send + Sync>() {}
+
+        _is_send_sync::<RawSharedMutex>();
+        _is_send_sync::<SharedMutex<()>>();
+        _is_send_sync::<SharedMutexReadGuard<()>>();
+        _is_send_sync::<SharedMutexUpgradableReadGuard<()>>();
+        _is_send_sync::<SharedMutexWriteGuard<()>>();
+        _is_send_sync::<OwnedSharedMutexReadGuard<()>>();
+        _is_send_sync::<OwnedSharedMutexWriteGuard<()>>();
+        _is_send_sync::<OwnedMappedSharedMutexReadGuard<(), ()>>();
+        _is_send_sync::<OwnedMappedSharedMutexWriteGuard<(), ()>>();
+    }
+
+    #[test]
+    fn test_simple_multithreaded() {
+        let pool = Pool::new(8);
+        let mut mutex = SharedMutex::new(0);
+        let n = 100;
+
+        pool.scoped(|scope| {
+            for _ in 0..n {
+                scope.execute(|| {
+                    let before = *mutex.read().unwrap();
+                    *mutex.write().unwrap() += 1;
+                    let after = *mutex.read().unwrap();
+
+                    assert!(before < after, "Time travel! {:?} >= {:?}", before, after);
+                })
+            }
+        });
+
+        assert_eq!(*mutex.get_mut().unwrap(), 100);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_simple_single_thread() {
+        let mut mutex = SharedMutex::new(0);
+        let n = 100;
+
+        for _ in 0..n {
+            let before = *mutex.read().unwrap();
+            *mutex.write().unwrap() += 1;
+            let after = *mutex.read().unwrap();
+
+            assert!(before < after, "Time travel! {:?} >= {:?}", before, after);
+        }
+
+        assert_eq!(*mutex.get_mut().unwrap(), 100);
+    }
+
+    #[test]
+    fn test_locking_multithreaded() {
+        // This test makes a best effort to test the actual locking
+        // behavior of the mutex.
+        //
+        // Read locks attempt to read from an atomic many times,
+        // while write locks write to them many times.
+        //
+        // If any of these operations interleave (readers read different
+        // values under the same lock, writers observe other writers) then
+        // we know there is a bug.
+        //
+        // We make use of a barrier to attempt to cluster threads together.
+
+        let mut mutex = SharedMutex::new(());
+        let value = AtomicUsize::new(0);
+
+        let threads = 50;
+        let actors = threads * 20; // Must be a multiple threads.
+        let actions_per_actor = 100;
+        let start_barrier = Barrier::new(threads);
+        let pool = Pool::new(threads);
+
+        pool.scoped(|scope| {
+            for _ in 0..actors {
+                // Reader
+                scope.execute(|| {
+                    start_barrier.wait();
+
+                    let _read = mutex.read().unwrap();
+                    let original = value.load(Ordering::SeqCst);
+
+                    for _ in 0..actions_per_actor {
+                        assert_eq!(original, value.load(Ordering::SeqCst));
+                    }
+                });
+
+                // Writer
+                scope.execute(|| {
+                    start_barrier.wait();
+
+                    let _write = mutex.write().unwrap();
+                    let mut previous = value.load(Ordering::SeqCst);
+
+                    for _ in 0..actions_per_actor {
+                        let next = value.fetch_add(1, Ordering::SeqCst);
+
+                        // fetch_add returns the old value
+                        assert_eq!(previous, next);
+
+                        // next time we will expect the old value + 1
+                        previous = next + 1;
+                    }
+                });
+            }
+        });
+
+        mutex.get_mut().unwrap();
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_simple_waiting() {
+        let pool = Pool::new(20);
+        let mutex = SharedMutex::new(());
+        let cond = Condvar::new();
+
+        pool.scoped(|scope| {
+            let lock = mutex.write().unwrap();
+
+            scope.execute(|| {
+                let _ = mutex.write().unwrap();
+                cond.notify_one();
+            });
+
+            // Write -> Read
+            let lock = lock.wait_for_read(&cond).unwrap();
+
+            scope.execute(|| {
+                drop(mutex.write().unwrap());
+                cond.notify_one();
+            });
+
+            // Read -> Read
+            let lock = lock.wait_for_read(&cond).unwrap();
+
+            scope.execute(|| {
+                drop(mutex.write().unwrap());
+                cond.notify_one();
+            });
+
+
+            // Read -> Write
+            let lock = lock.wait_for_write(&cond).unwrap();
+
+            scope.execute(|| {
+                drop(mutex.write().unwrap());
+                cond.notify_one();
+            });
+
+            // Write -> Write
+            lock.wait_for_write(&cond).unwrap();
+        });
+
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_wait_while_timeout() {
+        let pool = Pool::new(1);
+        let mutex = SharedMutex::new(0);
+        let cond = Condvar::new();
+
+        // Times out if the condition never changes, even with wakeups.
+        pool.scoped(|scope| {
+            let lock = mutex.write().unwrap();
+
+            scope.execute(|| {
+                for _ in 0..5 {
+                    drop(mutex.read().unwrap());
+                    cond.notify_all();
+                    thread::sleep(Duration::from_millis(10));
+                }
+            });
+
+            let (lock, result) = lock.wait_while_for_write_timeout(&cond, |n| *n == 0,
+                                                                   Duration::from_millis(100)).unwrap();
+            assert!(result.timed_out());
+            assert_eq!(*lock, 0);
+        });
+
+        // Returns as soon as the condition is satisfied.
+        pool.scoped(|scope| {
+            let lock = mutex.write().unwrap();
+
+            scope.execute(|| {
+                *mutex.write().unwrap() = 1;
+                cond.notify_all();
+            });
+
+            let (lock, result) = lock.wait_while_for_write_timeout(&cond, |n| *n == 0,
+                                                                   Duration::from_secs(60)).unwrap();
+            assert!(!result.timed_out());
+            assert_eq!(*lock, 1);
+        });
+
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_mapping() {
+        let mutex = SharedMutex::new(vec![1, 2, 3]);
+
+        *mutex.write().unwrap().into_mapped()
+            .map(|v| &mut v[0]) = 100;
+
+        assert_eq!(*mutex.read().unwrap().into_mapped().map(|v| &v[0]), 100);
+    }
+
+    #[test]
+    fn test_map_recover() {
+        let mutex = SharedMutex::new(vec![1, 2]);
+
+        let mut write_map = mutex.write().unwrap().into_mapped()
+            .map(|v| &mut v[0]);
+        *write_map = 123;
+
+        let whole_guard = write_map.recover(&mutex).unwrap();
+        assert_eq!(&*whole_guard, &[123, 2]);
+    }
+
+    #[test]
+    fn test_map_recover_typed() {
+        let mutex = SharedMutex::new(vec![1, 2]);
+
+        let mut write_map = mutex.write().unwrap().into_mapped_recoverable()
+            .map(|v| &mut v[0]);
+        *write_map = 123;
+
+        let write_map = write_map.recover_typed::<Vec<u8>>().unwrap_err();
+        let whole_guard = write_map.recover_typed::<Vec<i32>>().unwrap();
+        assert_eq!(&*whole_guard, &[123, 2]);
+        drop(whole_guard);
+
+        // Guards mapped without a recovery token can't be recovered.
+        let read_map = mutex.read().unwrap().into_mapped().map(|v| &v[1]);
+        assert!(read_map.recover_typed::<Vec<i32>>().is_err());
+
+        let read_map = mutex.read().unwrap().into_mapped_recoverable().map(|v| &v[1]);
+        assert_eq!(&*read_map.recover_typed::<Vec<i32>>().unwrap(), &[123, 2]);
+    }
+
+    #[test]
+    fn test_rwlock() {
+        let lock = RwLock::new(vec![1, 2]);
+        assert!(!lock.is_locked());
+
+        {
+            let read = lock.read();
+            assert!(lock.is_locked() && !lock.is_locked_exclusive());
+            assert_eq!(*lock.try_read().unwrap(), *read);
+            assert!(lock.try_write().is_none());
+        }
+
+        lock.write().push(3);
+
+        {
+            let read = lock.read();
+            assert_eq!(lock.data_ptr() as *const Vec<i32>, &*read as *const Vec<i32>);
+            assert_eq!(unsafe { &*lock.data_ptr() }, &[1, 2, 3]);
+        }
+
+        // The pointer is found the same way for unsized data.
+        let slice: &RwLock<[u8]> = &RwLock::new([1, 2, 3]);
+        let read = slice.read();
+        assert_eq!(slice.data_ptr() as *const [u8], &*read as *const [u8]);
+        drop(read);
+
+        // Poisoning is ignored.
+        poison(lock.as_shared_mutex());
+        lock.write().push(4);
+        assert!(!lock.is_locked());
+        assert_eq!(lock.into_inner(), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_try_peek() {
+        let mutex = SharedMutex::new(10);
+        assert_eq!(*mutex.try_peek().unwrap(), 10);
+
+        {
+            let _write = mutex.write().unwrap();
+            assert!(mutex.try_peek().is_none());
+        }
+
+        poison(&mutex);
+        assert!(mutex.try_peek().is_none());
+    }
+
+    #[test]
+    fn test_wait_for_write_remap() {
+        let mutex = SharedMutex::new(vec![0]);
+        let cond = Condvar::new();
+        let pool = Pool::new(1);
+
+        pool.scoped(|scope| {
+            let mut last = mutex.write().unwrap().into_mapped_recoverable()
+                .map(|v| v.last_mut().unwrap());
+
+            scope.execute(|| {
+                // Reallocates the vector, moving the element the guard pointed to.
+                mutex.write().unwrap().extend(1..100);
+                cond.notify_all();
+            });
+
+            while *last == 0 {
+                last = last.wait_for_write_remap(&cond, |v: &mut Vec<i32>| v.last_mut().unwrap())
+                    .unwrap().unwrap();
+            }
+
+            assert_eq!(*last, 99);
+        });
+
+        let unrecoverable = mutex.write().unwrap().into_mapped();
+        assert!(unrecoverable.wait_for_write_remap(&cond, |v: &mut Vec<i32>| v).is_err());
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_owned_into_mapped() {
+        let mutex = Arc::new(SharedMutex::new(vec![1, 2]));
+
+        let mut write = mutex.clone().write_owned().unwrap().into_mapped().map(|v| &mut v[0]);
+        assert_eq!(Arc::strong_count(&mutex), 2);
+
+        // Owned guards can move to other threads with the lock held.
+        thread::spawn(move || *write = 10).join().unwrap();
+        assert_eq!(Arc::strong_count(&mutex), 1);
+
+        let read = mutex.clone().read_owned().unwrap().into_mapped().map(|v| &v[0]);
+        assert!(mutex.try_write().is_err());
+        assert_eq!(*read, 10);
+
+        drop(read);
+        assert_eq!(Arc::strong_count(&mutex), 1);
+        assert_eq!(*mutex.try_write().unwrap(), [10, 2]);
+
+        let mut write = mutex.write_arc().unwrap();
+        thread::spawn(move || write[1] += write[0]).join().unwrap();
+        assert_eq!(*mutex.try_read().unwrap(), [10, 12]);
+
+        let (read, shared) = mutex.clone().read_owned_arc().unwrap();
+        assert!(Arc::ptr_eq(&mutex, &shared));
+        assert_eq!(Arc::strong_count(&mutex), 3);
+        assert_eq!(read[0], 10);
+        drop((read, shared));
+
+        let write = mutex.clone().write_owned().unwrap();
+        let write = write.try_into_inner().err().unwrap();
+        let weak = Arc::downgrade(&mutex);
+        drop(mutex);
+        let write = write.try_into_inner().err().unwrap();
+        drop(weak);
+        assert_eq!(write.try_into_inner().ok().unwrap(), [10, 12]);
+    }
+
+    #[test]
+    fn test_poisoned_while_waiting() {
+        let mutex = SharedMutex::new(0);
+        let cond = Condvar::new();
+        let pool = Pool::new(1);
+
+        pool.scoped(|scope| {
+            let read = mutex.read().unwrap();
+
+            scope.execute(|| {
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+                    let mut write = mutex.write().unwrap();
+                    *write = 1;
+                    cond.notify_all();
+                    panic!("Poisoning the mutex while a reader waits.");
+                }));
+            });
+
+            // The writer can only notify once we are waiting.
+            let guard = match read.wait_for_write(&cond) {
+                Ok(_) => panic!("Poisoning not detected after waiting."),
+                Err(poisoned) => poisoned.into_inner()
+            };
+            assert_eq!(*guard, 1);
+        });
+
+        pool.shutdown();
+    }
+
+    #[cfg(feature = "poison-backtrace")]
+    #[test]
+    fn test_poison_backtrace() {
+        let mutex = SharedMutex::new(0);
+        assert!(mutex.poison_backtrace().is_none());
+
+        *mutex.write().unwrap() += 1;
+        assert!(mutex.poison_backtrace().is_none());
+
+        poison(&mutex);
+        assert!(mutex.poison_backtrace().unwrap().to_string().contains("poison"));
+    }
+
+    #[test]
+    fn test_monitor_notify_locked() {
+        use monitor::Monitor;
+
+        let monitor = Monitor::new(0);
+        let pool = Pool::new(2);
+        let rounds = 1000;
+
+        // Each thread waits for its turn, so a single lost wakeup hangs both.
+        pool.scoped(|scope| {
+            for parity in 0..2 {
+                let monitor = &monitor;
+                scope.execute(move || for _ in 0..rounds {
+                    let mut guard = monitor.read().unwrap();
+                    while **guard % 2 != parity {
+                        guard = guard.wait_for_read().unwrap();
+                    }
+                    drop(guard);
+
+                    monitor.notify_all_locked(|value| *value += 1).unwrap();
+                });
+            }
+        });
+
+        assert_eq!(monitor.notify_one_locked(|value| *value).unwrap(), 2 * rounds);
+    }
+
+    #[test]
+    fn test_monitor_wait_while() {
+        use monitor::Monitor;
+
+        let monitor = Monitor::new(Vec::new());
+        let pool = Pool::new(1);
+
+        pool.scoped(|scope| {
+            scope.execute(|| for i in 0..10 {
+                let mut guard = monitor.wait_while_write(|queue| queue.len() == 2).unwrap();
+                guard.push(i);
+                guard.notify_all();
+            });
+
+            let mut received = Vec::new();
+            while received.len() < 10 {
+                let mut guard = monitor.wait_while_write(|queue| queue.is_empty()).unwrap();
+                received.push(guard.remove(0));
+                guard.notify_all();
+            }
+
+            assert_eq!(received, (0..10).collect::<Vec<_>>());
+        });
+
+        let guard = monitor.read().unwrap().wait_while(|queue| !queue.is_empty()).unwrap();
+        assert!(guard.is_empty());
+    }
+
+    #[test]
+    fn test_monitor_wait_timeout_while() {
+        use std::sync::atomic::AtomicBool;
+        use monitor::Monitor;
+
+        let monitor = Monitor::new(0);
+        let pool = Pool::new(1);
+        let timeout = Duration::from_millis(50);
+
+        // Notifications which don't change the predicate don't extend the
+        // wait, otherwise it would only end once the notifier gives up.
+        let done = AtomicBool::new(false);
+        let start = Instant::now();
+
+        pool.scoped(|scope| {
+            scope.execute(|| while !done.load(Ordering::SeqCst) && start.elapsed() < timeout * 10 {
+                monitor.notify_all();
+                thread::sleep(Duration::from_millis(1));
+            });
+
+            let (guard, result) = monitor.wait_timeout_while_write(timeout, |value| *value == 0).unwrap();
+            done.store(true, Ordering::SeqCst);
+            assert!(result.timed_out());
+            assert!(start.elapsed() < timeout * 10);
+            assert!(monitor.as_ref().try_read().is_err());
+            drop(guard);
+        });
+
+        pool.scoped(|scope| {
+            scope.execute(|| monitor.notify_all_locked(|value| *value = 1).unwrap());
+
+            let (guard, result) = monitor.wait_timeout_while_write(Duration::from_secs(10), |value| *value == 0).unwrap();
+            assert!(!result.timed_out());
+            assert_eq!(**guard, 1);
+        });
+    }
+
+    #[test]
+    fn test_mailbox() {
+        use mailbox::Mailbox;
+
+        let mailbox = Mailbox::new(0);
+        let pool = Pool::new(1);
+        let rounds = 1000;
+
+        pool.scoped(|scope| {
+            let mut guard = mailbox.write().unwrap();
+
+            scope.execute(|| for message in 0..rounds {
+                mailbox.post(message).unwrap();
+            });
+
+            // Every message arrives exactly once, in order.
+            let mut received = Vec::new();
+            while received.len() < rounds {
+                let (next, messages) = guard.wait_for_write_msg().unwrap();
+                guard = next;
+                received.extend(messages);
+            }
+            **guard = received.len();
+            assert_eq!(received, (0..rounds).collect::<Vec<_>>());
+            assert!(guard.take_messages().is_empty());
+        });
+
+        let mut guard = mailbox.write().unwrap();
+        guard.post(rounds);
+        assert_eq!(guard.take_messages(), [rounds]);
+        assert_eq!(**guard, rounds);
+    }
+
+    #[test]
+    fn test_write_if_changed() {
+        let mutex = SharedMutex::new(0);
+        let cond = Condvar::new();
+        let pool = Pool::new(1);
+
+        pool.scoped(|scope| {
+            let guard = mutex.write().unwrap();
+
+            scope.execute(|| {
+                let set = |value| mutex.write_if_changed(|v| {
+                    let changed = *v != value;
+                    *v = value;
+                    changed
+                }, &cond).unwrap();
+
+                // An unchanged value doesn't wake the waiter.
+                assert!(!set(0));
+                thread::sleep(Duration::from_millis(10));
+                assert!(set(1));
+            });
+
+            let (guard, timeout) = guard.wait_timeout_for_write(&cond, Duration::from_secs(10)).unwrap();
+            assert!(!timeout.timed_out());
+            assert_eq!(*guard, 1);
+        });
+    }
+
+    #[test]
+    fn test_wait_timeout_for_write() {
+        let mutex = SharedMutex::new(0);
+        let cond = Condvar::new();
+        let pool = Pool::new(1);
+
+        // Times out, but still resumes with the write lock.
+        let read = mutex.read().unwrap();
+        let (mut write, timeout) = read.wait_timeout_for_write(&cond, Duration::from_millis(10)).unwrap();
+        assert!(timeout.timed_out());
+        assert!(mutex.try_read().is_err());
+        *write += 1;
+        drop(write);
+
+        pool.scoped(|scope| {
+            let read = mutex.read().unwrap();
+
+            scope.execute(|| {
+                *mutex.write().unwrap() += 1;
+                cond.notify_all();
+            });
+
+            let (write, timeout) = read.wait_timeout_for_write(&cond, Duration::from_secs(10)).unwrap();
+            assert!(!timeout.timed_out());
+            assert_eq!(*write, 2);
+        });
+    }
+
+    #[test]
+    fn test_wait_timeout_for_read() {
+        let mutex = SharedMutex::new(0);
+        let cond = Condvar::new();
+
+        // Times out alongside another reader, and comes back as one reader.
+        let other = mutex.read().unwrap();
+        let read = mutex.read().unwrap();
+        let (read, timeout) = read.wait_timeout_for_read(&cond, Duration::from_millis(10)).unwrap();
+        assert!(timeout.timed_out());
+        drop(other);
+        drop(read);
+        *mutex.try_write().unwrap() += 1;
+
+        let write = mutex.write().unwrap();
+        let (read, timeout) = write.wait_timeout_for_read(&cond, Duration::from_millis(10)).unwrap();
+        assert!(timeout.timed_out());
+        assert!(mutex.try_write().is_err());
+        assert_eq!(*read, 1);
+        drop(read);
+
+        mutex.raw.assert_unlocked();
+        assert!(mutex.try_write().is_ok());
+    }
+
+    #[test]
+    fn test_wait_while() {
+        let mutex = SharedMutex::new(0);
+        let cond = Condvar::new();
+        let pool = Pool::new(1);
+
+        pool.scoped(|scope| {
+            let read = mutex.read().unwrap();
+
+            // Wakes the waiters once per step, so they wait several times.
+            scope.execute(|| for _ in 0..10 {
+                *mutex.write().unwrap() += 1;
+                cond.notify_all();
+            });
+
+            let read = read.wait_while_for_read(&cond, |n| *n < 5).unwrap();
+            assert!(*read >= 5);
+            drop(read);
+
+            let write = mutex.write().unwrap().wait_while_for_write(&cond, |n| *n < 10).unwrap();
+            assert_eq!(*write, 10);
+        });
+
+        // Doesn't wait at all if the condition is already false.
+        let write = mutex.write().unwrap().wait_while_for_write(&cond, |_| false).unwrap();
+        drop(write);
+        let read = mutex.read().unwrap().wait_while_for_read(&cond, |_| false).unwrap();
+        assert_eq!(*read, 10);
+    }
+
+    #[test]
+    fn test_leak_guard() {
+        let mutex = SharedMutex::leak_static(vec![1]);
+
+        let data: &'static Vec<i32> = mutex.read().unwrap().leak();
+        assert!(mutex.try_write().is_err());
+        assert_eq!(*mutex.read().unwrap(), *data);
+
+        let other = SharedMutex::leak_static(0);
+        let data: &'static mut i32 = other.write().unwrap().leak();
+        *data += 1;
+        assert!(other.try_read().is_err());
+
+        // Destroying a mutex whose guard was leaked is fine.
+        let mutex = SharedMutex::new(0);
+        mem::forget(mutex.read());
+        drop(mutex);
+
+        let mutex = SharedMutex::new(0);
+        let _ = mutex.write().unwrap().leak();
+        drop(mutex);
+    }
+
+    #[test]
+    fn test_leak_static() {
+        fn keep(guard: SharedMutexWriteGuard<'static, Vec<i32>>) -> thread::JoinHandle<()> {
+            thread::spawn(move || { let mut guard = guard; guard.push(1) })
+        }
+
+        let mutex = SharedMutex::leak_static(Vec::new());
+        keep(mutex.write().unwrap()).join().unwrap();
+        assert_eq!(*mutex.read().unwrap(), [1]);
+    }
+
+    #[test]
+    fn test_read_filtered() {
+        use std::collections::HashMap;
+
+        let mutex = SharedMutex::new(HashMap::new());
+        mutex.write().unwrap().insert("present", 1);
+
+        assert_eq!(*mutex.read_filtered(|map| map.get("present")).unwrap().unwrap(), 1);
+
+        // The lock is released on a miss.
+        assert!(mutex.read_filtered(|map| map.get("missing")).unwrap().is_none());
+        assert!(mutex.try_write().is_ok());
+    }
+
+    #[test]
+    fn test_mapped_wait() {
+        let mutex = SharedMutex::new(vec![0]);
+        let cond = Condvar::new();
+        let pool = Pool::new(1);
+
+        // Only recoverable guards can wait.
+        let mapped = mutex.write().unwrap().into_mapped().map(|v| &mut v[0]);
+        let mapped = mapped.wait_for_write::<Vec<i32>>(&cond).unwrap_err();
+        drop(mapped);
+
+        pool.scoped(|scope| {
+            let mut first = mutex.write().unwrap().into_mapped_recoverable()
+                .map(|v| &mut v[0]);
+            *first = 1;
+
+            // The wrong type gives the guard back.
+            let first = first.wait_for_read::<Vec<u8>>(&cond).unwrap_err();
+
+            scope.execute(|| {
+                mutex.write().unwrap().push(2);
+                cond.notify_one();
+            });
+
+            let full = first.wait_for_read::<Vec<i32>>(&cond).ok().unwrap().unwrap();
+            assert_eq!(*full, [1, 2]);
+        });
+    }
+
+    #[test]
+    fn test_no_lost_wakeups() {
+        let mutex = Arc::new(SharedMutex::new(false));
+        let cond = Arc::new(Condvar::new());
+        let barrier = Arc::new(Barrier::new(2));
+
+        let notifier = {
+            let (mutex, cond, barrier) = (mutex.clone(), cond.clone(), barrier.clone());
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    barrier.wait();
+                    *mutex.write().unwrap() = true;
+                    cond.notify_all();
+                }
+            })
+        };
+
+        // Race the notification against the start of each wait. A lost
+        // wakeup hangs this loop.
+        for _ in 0..1000 {
+            barrier.wait();
+
+            let read = mutex.read().unwrap();
+            let mut write = if *read {
+                drop(read);
+                mutex.write().unwrap()
+            } else {
+                read.wait_for_write(&cond).unwrap()
+            };
+
+            while !*write { write = write.wait_for_write(&cond).unwrap() }
+            *write = false;
+        }
+
+        notifier.join().unwrap();
+    }
+
+    #[test]
+    fn test_shared_mutex_ref() {
+        let mut buffer = [0u8; 16];
+
+        {
+            let lock = SharedMutexRef::new(&mut buffer[..]);
+            let pool = Pool::new(4);
+
+            pool.scoped(|scope| {
+                for i in 0..16 {
+                    let lock = &lock;
+                    scope.execute(move || lock.write().unwrap()[i] = i as u8);
+                }
+            });
+
+            let read = lock.read().unwrap();
+            assert_eq!(read.len(), 16);
+            assert!(lock.try_read().is_ok());
+            assert!(lock.try_write().is_err());
+            drop(read);
+
+            lock.into_inner().unwrap()[0] = 100;
+        }
+
+        assert_eq!(buffer[0], 100);
+        assert_eq!(buffer[15], 15);
+    }
+
+    #[test]
+    fn test_shared_mutex_vec() {
+        let locks = SharedMutexVec::from_fn(4, |i| i);
+        let pool = Pool::new(4);
+
+        pool.scoped(|scope| {
+            for i in 0..100 {
+                let locks = &locks;
+                scope.execute(move || {
+                    if i % 10 == 0 {
+                        locks.for_each_write(|_, value| *value += 1).unwrap();
+                    } else {
+                        *locks.write(i % locks.len()).unwrap() += 1;
+                    }
+                });
+            }
+        });
+
+        let mut total = 0;
+        locks.for_each_read(|index, &value| {
+            assert!(value >= index + 10);
+            total += value;
+        }).unwrap();
+        assert_eq!(total, 0 + 1 + 2 + 3 + 90 + 4 * 10);
+
+        poison(locks.get(2));
+        let mut visited = 0;
+        assert!(locks.for_each_read(|_, _| visited += 1).is_err());
+        assert_eq!(visited, 4);
+
+        let padded = SharedMutexVec::from_fn_padded(2, |i| i);
+        let (first, second) = (padded.get(0) as *const _ as usize, padded.get(1) as *const _ as usize);
+        assert!(second - first >= 64);
+
+        padded.for_each_write(|index, value| *value += index).unwrap();
+        *padded.write(1).unwrap() += 1;
+        assert_eq!(*padded.read(1).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_clone_owned_poisoned() {
+        let mutex = Arc::new(SharedMutex::new(5));
+        poison(&mutex);
+
+        let guard = mutex.clone().read_owned().unwrap_err().into_inner();
+        let clone = guard.clone();
+        drop(guard);
+
+        assert_eq!(*clone, 5);
+        match mutex.try_write() {
+            Err(TryLockError::WouldBlock) => {},
+            _ => panic!("Write lock acquired while a clone is held.")
+        }
+
+        drop(clone);
+        assert!(!mutex.raw.is_locked());
+    }
+
+    #[test]
+    fn test_arc_guards() {
+        let mutex = Arc::new(SharedMutex::new(0));
+
+        let mut write = mutex.write_arc().unwrap();
+        thread::spawn(move || *write += 1).join().unwrap();
+
+        let read = mutex.read_arc().unwrap();
+        let other = mutex.read_arc().unwrap();
+        assert_eq!(thread::spawn(move || *read).join().unwrap(), 1);
+        assert_eq!(Arc::strong_count(&mutex), 2);
+        drop(other);
+
+        assert!(mutex.try_write().is_ok());
+    }
+
+    #[test]
+    fn test_owned_wait() {
+        let mutex = Arc::new(SharedMutex::new(0));
+        let cond = Arc::new(Condvar::new());
+
+        let notifier = {
+            let (mutex, cond) = (mutex.clone(), cond.clone());
+
+            thread::spawn(move || {
+                for _ in 0..4 {
+                    *mutex.write().unwrap() += 1;
+                    cond.notify_all();
+                    thread::sleep(Duration::from_millis(10));
+                }
+            })
+        };
+
+        let mut read = mutex.clone().read_owned().unwrap();
+        while *read == 0 { read = read.wait_for_read(&cond).unwrap(); }
+
+        let mut write = read.wait_for_write(&cond).unwrap();
+        let seen = *write;
+        while *write == seen { write = write.wait_for_write(&cond).unwrap(); }
+
+        let mut read = write.wait_for_read(&cond).unwrap();
+        while *read != 4 { read = read.wait_for_read(&cond).unwrap(); }
+
+        // The guard still holds its own reference, and only that one.
+        notifier.join().unwrap();
+        assert_eq!(Arc::strong_count(&mutex), 2);
+
+        drop(read);
+        assert_eq!(Arc::strong_count(&mutex), 1);
+        assert!(mutex.try_write().is_ok());
+    }
+
+    #[test]
+    fn test_owned_wait_in_thread() {
+        let mutex = Arc::new(SharedMutex::new(0));
+        let cond = Arc::new(Condvar::new());
+
+        // The guard carries the mutex into the thread, and across the wait.
+        let read = mutex.read_arc().unwrap();
+        let waiter = {
+            let cond = cond.clone();
+            thread::spawn(move || {
+                let mut write = read.wait_for_write(&cond).unwrap();
+                while *write == 0 { write = write.wait_for_write(&cond).unwrap(); }
+                *write += 1;
+            })
+        };
+
+        // Only gets in once the waiter released its read lock to wait.
+        *mutex.write().unwrap() += 1;
+        cond.notify_all();
+
+        waiter.join().unwrap();
+        assert_eq!(*mutex.read().unwrap(), 2);
+        assert_eq!(Arc::strong_count(&mutex), 1);
+    }
+
+    #[test]
+    fn test_drain_collected() {
+        let mutex = SharedMutex::new(vec![1, 2, 3]);
+
+        assert_eq!(mutex.drain_collected().unwrap(), [1, 2, 3]);
+        assert!(mutex.try_write().unwrap().is_empty());
+
+        mutex.write().unwrap().push(4);
+        assert_eq!(mutex.drain_collected().unwrap(), [4]);
+    }
+
+    #[test]
+    fn test_scope() {
+        let mutex = SharedMutex::new(vec![1]);
+
+        let len = mutex.write_scope(|guard| {
+            guard.push(2);
+            assert!(guard.points_to(&mutex));
+            guard.len()
+        }).unwrap();
+        assert_eq!(len, 2);
+
+        assert_eq!(mutex.read_scope(|guard| guard[1]).unwrap(), 2);
+        assert!(mutex.try_write().is_ok());
+
+        poison(&mutex);
+        assert_eq!(mutex.read_scope(|guard| guard.len()).unwrap_err().into_inner(), 2);
+    }
+
+    #[test]
+    fn test_unlock_fair() {
+        let mutex = SharedMutex::new(0);
+        let pool = Pool::new(2);
+
+        pool.scoped(|scope| {
+            let guard = mutex.write().unwrap();
+
+            for _ in 0..2 {
+                scope.execute(|| {
+                    let mut guard = mutex.write().unwrap();
+                    *guard += 1;
+                    guard.unlock_fair();
+                });
+            }
+
+            thread::sleep(Duration::from_millis(10));
+            guard.unlock_fair();
+
+            let read = mutex.read().unwrap();
+            assert!(*read > 0);
+            read.unlock_fair();
+        });
+
+        assert_eq!(*mutex.read().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_wait_then_map() {
+        let mutex = SharedMutex::new((0, String::new()));
+        let cond = Condvar::new();
+        let pool = Pool::new(1);
+
+        pool.scoped(|scope| {
+            let guard = mutex.write().unwrap();
+
+            scope.execute(|| {
+                let guard = mutex.write().unwrap();
+                cond.notify_one();
+
+                let mut name = guard.wait_for_write_then_map(&cond, |pair| &mut pair.1).unwrap();
+                name.push_str("done");
+            });
+
+            let count = guard.wait_for_read_then_map(&cond, |pair| &pair.0).unwrap();
+            assert_eq!(*count, 0);
+            cond.notify_one();
+        });
+
+        assert_eq!(mutex.read().unwrap().1, "done");
+    }
+
+    #[test]
+    fn test_map_index() {
+        let mutex = SharedMutex::new(vec![1, 2, 3]);
+
+        *mutex.write().unwrap().into_mapped().index_mut(1) += 10;
+        assert_eq!(*mutex.read().unwrap().into_mapped().index(1), 12);
+        assert_eq!(&*mutex.read().unwrap().into_mapped().index(1..), &[12, 3]);
+    }
+
+    #[test]
+    fn test_map_or_recover() {
+        let mutex = SharedMutex::new(vec![1, 2, 3]);
+
+        let first = mutex.write().unwrap().map_or_recover(|v| v.first_mut(), |mut first| {
+            *first += 10;
+            *first
+        }, |_| 0);
+        assert_eq!(first, 11);
+
+        let len = mutex.write().unwrap().map_or_recover(|v| v.get_mut(5), |_| 0, |mut whole| {
+            whole.resize(6, 0);
+            whole.len()
+        });
+        assert_eq!(len, 6);
+        assert_eq!(*mutex.read().unwrap(), [11, 2, 3, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_result_map_dropping() {
+        let mutex = SharedMutex::new(vec![1, 2, 3]);
+
+        let error = mutex.read().unwrap().into_mapped()
+            .result_map_dropping(|v| v.get(5).ok_or("out of bounds")).err();
+        assert_eq!(error, Some("out of bounds"));
+        assert!(mutex.try_write().is_ok());
+
+        let mut last = mutex.write().unwrap().into_mapped()
+            .result_map_dropping(|v| v.last_mut().ok_or(())).unwrap();
+        *last = 4;
+        drop(last);
+        assert_eq!(*mutex.read().unwrap(), [1, 2, 4]);
+    }
+
+    #[test]
+    fn test_reborrow() {
+        fn sum(values: &[i32]) -> i32 { values.iter().sum() }
+
+        let mutex = SharedMutex::new(vec![1, 2, 3]);
+
+        let mut write = mutex.write().unwrap().into_mapped().map(|v| &mut v[1..]);
+        write.reborrow_mut()[0] = 5;
+        assert_eq!(sum(write.reborrow()), 8);
+        drop(write);
+
+        let read = mutex.read().unwrap().into_mapped();
+        assert_eq!(sum(read.reborrow()), 9);
+        assert_eq!(read.len(), 3);
+    }
+
+    #[test]
+    fn test_points_to() {
+        let mutex = SharedMutex::new(vec![1]);
+        let other = SharedMutex::new(vec![1]);
+        assert!(mutex.ptr_eq(&mutex));
+        assert!(!mutex.ptr_eq(&other));
+
+        let read = mutex.read().unwrap();
+        assert!(read.points_to(&mutex));
+        assert!(!read.points_to(&other));
+
+        let mapped = read.into_mapped().map(|v| &v[0]);
+        assert!(mapped.points_to(&mutex));
+        assert!(!mapped.points_to(&other));
+        drop(mapped);
+
+        let write = other.write().unwrap().into_mapped().map(|v| &mut v[0]);
+        assert!(write.points_to(&other));
+        assert!(!write.points_to(&mutex));
+    }
+
+    #[test]
+    fn test_upgradable_read() {
+        let mutex = SharedMutex::new(0);
+        let pool = Pool::new(1);
+
+        let upgradable = mutex.upgradable_read().unwrap();
+        let read = mutex.try_read().unwrap();
+        assert!(mutex.try_write().is_err());
+
+        pool.scoped(|scope| {
+            scope.execute(move || {
+                thread::sleep(Duration::from_millis(10));
+                drop(read);
+            });
+
+            // Waits for the other reader.
+            let mut write = upgradable.upgrade().unwrap();
+            *write += 1;
+        });
+
+        assert_eq!(*mutex.read().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mutex = SharedMutex::new(vec![1, 2]);
+
+        let snapshot = mutex.snapshot().unwrap();
+        mutex.write().unwrap().push(3);
+        assert_eq!(snapshot, [1, 2]);
+        assert!(mutex.try_write().is_ok());
+
+        poison(&mutex);
+        assert_eq!(mutex.snapshot().unwrap_err().into_inner(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_clone() {
+        let mutex = SharedMutex::new(vec![1]);
+
+        // The clone has its own lock and data.
+        let clone = mutex.clone();
+        let write = clone.write().unwrap();
+        mutex.write().unwrap().push(2);
+        assert_eq!(*write, [1]);
+        drop(write);
+
+        poison(&mutex);
+        let clone = mutex.clone();
+        assert!(clone.is_poisoned());
+        assert_eq!(*clone.read().unwrap_err().into_inner(), [1, 2]);
+    }
+
+    #[test]
+    fn test_lock_counts() {
+        let mutex = SharedMutex::new(0);
+        assert_eq!(mutex.reader_count(), 0);
+        assert!(!mutex.is_write_locked());
+
+        {
+            let _first = mutex.read().unwrap();
+            let _second = mutex.read().unwrap();
+            assert_eq!(mutex.reader_count(), 2);
+            assert!(!mutex.is_write_locked());
+        }
+
+        let write = mutex.write().unwrap();
+        assert_eq!(mutex.reader_count(), 0);
+        assert!(mutex.is_write_locked());
+
+        drop(write);
+        assert!(!mutex.is_write_locked());
+    }
+
+    #[test]
+    fn test_non_poison() {
+        let mutex = NonPoisonSharedMutex::new(0);
+        let cond = Condvar::new();
+        let pool = Pool::new(1);
+
+        // A panicking writer leaves the data as it was.
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            *mutex.write() += 1;
+            let _write = mutex.write();
+            panic!("Not poisoning the mutex.");
+        }));
+        assert_eq!(*mutex.read(), 1);
+
+        pool.scoped(|scope| {
+            let mut write = mutex.write();
+
+            scope.execute(|| {
+                for _ in 0..2 {
+                    *mutex.write() += 1;
+                    cond.notify_all();
+                }
+            });
+
+            while *write < 2 { write = write.wait_for_write(&cond); }
+            drop(write);
+
+            let mut read = mutex.read();
+            while *read < 3 { read = read.wait_for_read(&cond); }
+            assert!(mutex.try_write().is_none());
+        });
+
+        assert_eq!(mutex.into_inner(), 3);
+    }
+
+    #[test]
+    fn test_cache_padded() {
+        let locks: Vec<PaddedSharedMutex<u64>> = (0..2).map(|_| Default::default()).collect();
+        assert_eq!(mem::align_of::<PaddedSharedMutex<u64>>(), 64);
+
+        let (first, second) = (&*locks[0] as *const _ as usize, &*locks[1] as *const _ as usize);
+        assert!(second - first >= 64);
+
+        *locks[0].write().unwrap() += 1;
+        assert_eq!(locks.into_iter().map(|lock| lock.into_inner().into_inner().unwrap())
+                       .collect::<Vec<_>>(), [1, 0]);
+    }
+
+    #[test]
+    fn test_default() {
+        #[derive(Default)]
+        struct Config {
+            names: SharedMutex<Vec<u8>>
+        }
+
+        assert!(SharedMutex::<Vec<u8>>::default().read().unwrap().is_empty());
+        assert!(Config::default().names.into_inner().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_with_write() {
+        let mutex = SharedMutex::new(vec![1]);
+
+        assert_eq!(mutex.with_write(|v| { v.push(2); v.len() }).unwrap(), 2);
+        assert_eq!(mutex.with_read(|v| v.clone()).unwrap(), [1, 2]);
+        assert!(mutex.try_write().is_ok());
+
+        // A panicking action poisons the mutex and releases the lock.
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| mutex.with_write(|_| panic!("poison"))));
+        assert!(mutex.is_poisoned());
+        assert_eq!(mutex.with_read(|v| v.len()).unwrap_err().into_inner(), 2);
+    }
+
+    #[test]
+    fn test_with_timeout() {
+        let mutex = SharedMutex::new(0);
+
+        assert_eq!(mutex.with_write_timeout(Duration::from_millis(10), |n| { *n += 1; *n })
+                        .unwrap().unwrap(), 1);
+
+        let read = mutex.read().unwrap();
+        let mut ran = false;
+        assert_eq!(mutex.with_write_timeout(Duration::from_millis(10), |_| ran = true).err(),
+                   Some(TimedOut));
+        assert!(!ran);
+        assert_eq!(mutex.with_read_timeout(Duration::from_millis(10), |n| *n).unwrap().unwrap(), 1);
+        drop(read);
+
+        let write = mutex.write().unwrap();
+        assert_eq!(mutex.with_read_timeout(Duration::from_millis(10), |n| *n).err(), Some(TimedOut));
+        drop(write);
+
+        assert!(mutex.try_write().is_ok());
+    }
+
+    #[test]
+    fn test_try_read_for() {
+        let mutex = SharedMutex::new(0);
+        let pool = Pool::new(4);
+
+        pool.scoped(|scope| {
+            // Hold a read lock while a writer waits for it to be released.
+            let read = mutex.read().unwrap();
+            scope.execute(|| *mutex.write().unwrap() += 1);
+            while mutex.try_read().is_ok() { thread::yield_now() }
+
+            // Readers queued behind the draining writer all time out cleanly.
+            for _ in 0..3 {
+                scope.execute(|| for _ in 0..10 {
+                    assert!(mutex.try_read_for(Duration::from_millis(1)).is_err());
+                });
+            }
+
+            thread::sleep(Duration::from_millis(50));
+            drop(read);
+        });
+
+        assert_eq!(*mutex.try_read_for(Duration::from_secs(0)).unwrap(), 1);
+        mutex.raw.assert_unlocked();
+    }
+
+    #[test]
+    fn test_try_write_for() {
+        let mutex = SharedMutex::new(0);
+        let pool = Pool::new(1);
+
+        let read = mutex.read().unwrap();
+        assert!(mutex.try_write_for(Duration::from_secs(0)).is_err());
+        assert!(mutex.try_write_for(Duration::from_millis(10)).is_err());
+
+        pool.scoped(|scope| {
+            scope.execute(|| {
+                thread::sleep(Duration::from_millis(10));
+                drop(read);
+            });
+
+            *mutex.try_write_for(Duration::from_secs(10)).unwrap() += 1;
+        });
+
+        *mutex.try_write_for(Duration::from_secs(0)).unwrap() += 1;
+
+        poison(&mutex);
+        let poisoned = match mutex.try_write_for(Duration::from_millis(10)) {
+            Err(TryLockError::Poisoned(poisoned)) => *poisoned.into_inner() == 2,
+            _ => false
+        };
+        assert!(poisoned);
+    }
+
+    #[test]
+    fn test_timed() {
+        let mutex = SharedMutex::new(0);
+        let pool = Pool::new(1);
+
+        let (read, waited) = mutex.read_timed().unwrap();
+        assert_eq!(waited, Duration::from_secs(0));
+
+        pool.scoped(|scope| {
+            scope.execute(|| {
+                let (mut write, waited) = mutex.write_timed().unwrap();
+                assert!(waited >= Duration::from_millis(10));
+                *write += 1;
+            });
+
+            thread::sleep(Duration::from_millis(50));
+            drop(read);
+        });
+
+        let (write, waited) = mutex.write_timed().unwrap();
+        assert_eq!((*write, waited), (1, Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn test_try_write_detailed() {
+        let mutex = SharedMutex::new(0);
+
+        let reads = (mutex.read().unwrap(), mutex.read().unwrap());
+        assert_eq!(mutex.try_write_detailed().err(), Some(WriteBlockedBy::Readers(2)));
+        drop(reads);
+
+        let write = mutex.write().unwrap();
+        assert_eq!(mutex.try_write_detailed().err(), Some(WriteBlockedBy::Writer));
+        drop(write);
+
+        *mutex.try_write_detailed().unwrap() += 1;
+
+        poison(&mutex);
+        assert_eq!(mutex.try_write_detailed().err(), Some(WriteBlockedBy::Poisoned));
+
+        // Released again, so trying once more finds it poisoned rather than locked.
+        let poisoned = match mutex.try_write() {
+            Err(TryLockError::Poisoned(_)) => true,
+            _ => false
+        };
+        assert!(poisoned);
+    }
+
+    #[test]
+    fn test_try_upgrade_for() {
+        let mutex = SharedMutex::new(0);
+
+        let upgradable = mutex.upgradable_read().unwrap();
+        let read = mutex.try_read().unwrap();
+
+        // Times out while the other reader holds on.
+        let upgradable = upgradable.try_upgrade_for(Duration::from_millis(10)).unwrap_err();
+        assert!(mutex.try_write().is_err());
+        drop(mutex.try_read().unwrap());
+        drop(read);
+
+        let mut write = upgradable.try_upgrade_for(Duration::from_millis(10)).ok().unwrap().unwrap();
+        *write += 1;
+        drop(write);
+
+        assert_eq!(*mutex.read().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_try_upgrade() {
+        let mutex = SharedMutex::new(0);
+
+        let upgradable = mutex.upgradable_read().unwrap();
+        let read = mutex.read().unwrap();
+
+        // Fails while the other reader holds on, without blocking readers.
+        let upgradable = upgradable.try_upgrade().err().unwrap();
+        assert!(mutex.try_write().is_err());
+        drop(mutex.try_read().unwrap());
+        drop(read);
+
+        *upgradable.try_upgrade().ok().unwrap().unwrap() += 1;
+        assert_eq!(*mutex.try_read().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_read_upgrade() {
+        let mutex = SharedMutex::new(0);
+
+        // The only reader is switched over directly.
+        let mut write = mutex.read().unwrap().upgrade().unwrap();
+        *write += 1;
+        assert!(mutex.try_read().is_err());
+        drop(write);
+
+        // Readers upgrading at the same time take turns.
+        let pool = Pool::new(4);
+        let barrier = Barrier::new(4);
+        pool.scoped(|scope| {
+            for _ in 0..4 {
+                scope.execute(|| {
+                    let read = mutex.read().unwrap();
+                    barrier.wait();
+                    *read.upgrade().unwrap() += 1;
+                });
+            }
+        });
+
+        assert_eq!(*mutex.read().unwrap(), 5);
+        mutex.raw.assert_unlocked();
+    }
+
+    #[test]
+    fn test_is_poisoned() {
+        let mutex = SharedMutex::new(0);
+        assert!(!mutex.is_poisoned());
+
+        let write = mutex.write().unwrap();
+        assert!(!mutex.is_poisoned());
+        drop(write);
+
+        poison(&mutex);
+        assert!(mutex.is_poisoned());
+
+        let read = mutex.read().unwrap_err().into_inner();
+        assert!(mutex.is_poisoned());
+        drop(read);
+    }
+
+    #[test]
+    fn test_clear_poison() {
+        let mutex = SharedMutex::new(0);
+        let pool = Pool::new(1);
+
+        pool.scoped(|scope| {
+            scope.execute(|| {
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+                    let mut write = mutex.write().unwrap();
+                    *write += 1;
+                    panic!("Poisoning the mutex.");
+                }));
+            });
+        });
+        assert!(mutex.write().is_err());
+
+        mutex.clear_poison();
+        assert!(!mutex.is_poisoned());
+        assert_eq!(*mutex.write().unwrap(), 1);
+        assert!(mutex.read().is_ok());
+    }
+
+    #[test]
+    fn test_with_upgradable() {
+        let cache = SharedMutex::new(None);
+        let pool = Pool::new(4);
+        let computed = AtomicUsize::new(0);
+
+        pool.scoped(|scope| {
+            for _ in 0..8 {
+                scope.execute(|| {
+                    let value = cache.with_upgradable(|guard| {
+                        if let Some(value) = *guard { return value }
+
+                        let mut write = guard.upgrade().unwrap();
+                        computed.fetch_add(1, Ordering::SeqCst);
+                        *write = Some(5);
+                        5
+                    }).unwrap();
+
+                    assert_eq!(value, 5);
+                });
+            }
+        });
+
+        assert_eq!(computed.load(Ordering::SeqCst), 1);
+        assert!(cache.try_write().is_ok());
+    }
+
+    #[test]
+    fn test_map_split() {
+        let mutex = SharedMutex::new((0, String::new()));
+        let pool = Pool::new(2);
+
+        let guard = mutex.write().unwrap().into_mapped();
+        let (mut count, mut text) = guard.map_split(|&mut (ref mut count, ref mut text)| (count, text));
+
+        pool.scoped(|scope| {
+            scope.execute(move || *count += 1);
+            scope.execute(move || text.push_str("split"));
+        });
+
+        // Released once both halves were dropped.
+        assert_eq!(*mutex.try_read().unwrap(), (1, "split".to_string()));
+    }
+
+    #[test]
+    fn test_split_at_mut_mapped() {
+        let mutex = SharedMutex::new(vec![0; 10]);
+        let pool = Pool::new(2);
+
+        let guard = mutex.write().unwrap().into_mapped().map(|v| &mut v[..]);
+        let (mut left, mut right) = guard.split_at_mut_mapped(4);
+        assert_eq!((left.len(), right.len()), (4, 6));
+
+        pool.scoped(|scope| {
+            scope.execute(move || for x in left.iter_mut() { *x = 1 });
+
+            // The lock is held until both halves are released.
+            thread::sleep(Duration::from_millis(10));
+            assert!(mutex.try_read().is_err());
+
+            scope.execute(move || for x in right.iter_mut() { *x = 2 });
+        });
+
+        assert_eq!(*mutex.read().unwrap(), [1, 1, 1, 1, 2, 2, 2, 2, 2, 2]);
+
+        // A panic while holding one half poisons the mutex, even if the other
+        // half is released last.
+        let (left, right) = mutex.write().unwrap().into_mapped()
+            .map(|v| &mut v[..]).split_at_mut_mapped(5);
+
+        let _ = panic::catch_unwind(AssertUnwindSafe(move || {
+            let _left = left;
+            panic!("Poisoning the mutex through one half.");
+        }));
+
+        drop(right);
+        assert!(mutex.read().is_err());
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_clone_mapped_read() {
+        let mutex = SharedMutex::new((1, 2));
+        let pool = Pool::new(2);
+
+        let first = mutex.read().unwrap().into_mapped().map(|pair| &pair.1);
+
+        pool.scoped(|scope| {
+            // A waiting writer doesn't block cloning.
+            scope.execute(|| *mutex.write().unwrap() = (3, 4));
+            thread::sleep(Duration::from_millis(10));
+
+            let second = first.clone();
+            scope.execute(move || assert_eq!(*second, 2));
+
+            // Dropping one clone doesn't release the others.
+            thread::sleep(Duration::from_millis(10));
+            assert_eq!(*first, 2);
+            drop(first);
+        });
+
+        assert_eq!(*mutex.read().unwrap(), (3, 4));
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_try_locking() {
+        let mutex = SharedMutex::new(10);
+        mutex.try_read().unwrap();
+        mutex.try_write().unwrap();
+
+        let read = mutex.try_read_checked().unwrap();
+        assert_eq!(*mutex.try_read_checked().unwrap(), *read);
+    }
+
+    #[test]
+    fn test_try_locking_poisoned() {
+        let mutex = SharedMutex::new(10);
+        poison(&mutex);
+
+        // Poisoning is reported as such when the lock is uncontended.
+        match mutex.try_write() {
+            Err(TryLockError::Poisoned(poisoned)) => *poisoned.into_inner() += 1,
+            _ => panic!("Poisoning not reported by try_write.")
+        }
+
+        match mutex.try_read() {
+            Err(TryLockError::Poisoned(poisoned)) => assert_eq!(*poisoned.into_inner(), 11),
+            _ => panic!("Poisoning not reported by try_read.")
+        }
+
+        // Contention takes precedence, since no guard can be handed out.
+        let _write = mutex.write().unwrap_err();
+        match mutex.try_write() {
+            Err(TryLockError::WouldBlock) => {},
+            _ => panic!("Contention not reported by try_write.")
+        };
+    }
+
+    #[test]
+    fn test_reader_preference() {
+        let pool = Pool::new(1);
+        let mutex = SharedMutex::with_policy(0, LockPolicy::ReaderPreference);
+
+        pool.scoped(|scope| {
+            let read = mutex.read().unwrap();
+
+            scope.execute(|| *mutex.write().unwrap() += 1);
+
+            // Give the writer time to start waiting.
+            thread::sleep(Duration::from_millis(50));
+
+            // The waiting writer does not block new readers.
+            assert_eq!(*mutex.try_read().unwrap(), 0);
+            drop(read);
+        });
+
+        assert_eq!(*mutex.read().unwrap(), 1);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_writer_preferred() {
+        let mutex = SharedMutex::new_writer_preferred(0);
+        let done = AtomicUsize::new(0);
+        let pool = Pool::new(4);
+        assert_eq!(mutex.raw.policy(), LockPolicy::Neutral);
 
-impl<'mutex, T: ?Sized> Drop for MappedSharedMutexWriteGuard<'mutex, T> {
-    #[inline]
-    fn drop(&mut self) { self.mutex.unlock_write() }
-}
+        pool.scoped(|scope| {
+            // Overlapping readers, so the lock is never free of readers.
+            for _ in 0..3 {
+                scope.execute(|| {
+                    while done.load(Ordering::SeqCst) == 0 {
+                        let _read = mutex.read().unwrap();
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                });
+            }
 
-impl<T: ?Sized + fmt::Debug> fmt::Debug for SharedMutex<T> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut writer = f.debug_struct("SharedMutex");
+            thread::sleep(Duration::from_millis(20));
 
-        match self.try_read() {
-            Ok(l) => writer.field("data", &&*l),
-            Err(TryLockError::WouldBlock) => writer.field("data", &"{{ locked }}"),
-            Err(TryLockError::Poisoned(_)) => writer.field("data", &"{{ poisoned }}")
-        }.finish()
-    }
-}
+            for _ in 0..100 {
+                *mutex.write().unwrap() += 1;
+            }
 
-impl<'mutex, T: ?Sized + fmt::Debug> fmt::Debug for SharedMutexReadGuard<'mutex, T> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_struct("SharedMutexReadGuard")
-            .field("data", &*self)
-            .finish()
-    }
-}
+            done.store(1, Ordering::SeqCst);
+        });
 
-impl<'mutex, T: ?Sized + fmt::Debug> fmt::Debug for SharedMutexWriteGuard<'mutex, T> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_struct("SharedMutexWriteGuard")
-            .field("data", &*self)
-            .finish()
+        assert_eq!(*mutex.read().unwrap(), 100);
+        pool.shutdown();
     }
-}
 
-impl<'mutex, T: ?Sized + fmt::Debug> fmt::Debug for MappedSharedMutexReadGuard<'mutex, T> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_struct("MappedSharedMutexReadGuard")
-            .field("data", &*self)
-            .finish()
-    }
-}
+    #[test]
+    fn test_phase_fair() {
+        let mutex = SharedMutex::new_phase_fair(0);
+        let seen = AtomicUsize::new(0);
+        let pool = Pool::new(2);
 
-impl<'mutex, T: ?Sized + fmt::Debug> fmt::Debug for MappedSharedMutexWriteGuard<'mutex, T> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_struct("MappedSharedMutexWriteGuard")
-            .field("data", &*self)
-            .finish()
-    }
-}
+        pool.scoped(|scope| {
+            let mut write = mutex.write().unwrap();
 
-#[cfg(test)]
-mod test {
-    use std::sync::{Condvar, Barrier};
-    use std::sync::atomic::{AtomicUsize, Ordering};
-    use scoped_pool::Pool;
+            scope.execute(|| *mutex.write().unwrap() += 10);
+            while !mutex.has_waiting_writer() { thread::yield_now() }
 
-    use super::*;
+            // The reader blocks behind our write lock, after the writer.
+            scope.execute(|| seen.store(*mutex.read().unwrap(), Ordering::SeqCst));
+            thread::sleep(Duration::from_millis(50));
 
-    fn _check_bounds() {
-        fn _is_send_sync<T: Send + Sync>() {}
+            // Releasing the lock ends the write phase, so the reader gets in
+            // before the waiting writer.
+            *write += 1;
+        });
 
-        _is_send_sync::<RawSharedMutex>();
-        _is_send_sync::<SharedMutex<()>>();
-        _is_send_sync::<SharedMutexReadGuard<()>>();
-        _is_send_sync::<SharedMutexWriteGuard<()>>();
+        assert_eq!(seen.load(Ordering::SeqCst), 1);
+        assert_eq!(*mutex.read().unwrap(), 11);
+        pool.shutdown();
     }
 
     #[test]
-    fn test_simple_multithreaded() {
-        let pool = Pool::new(8);
-        let mut mutex = SharedMutex::new(0);
-        let n = 100;
+    fn test_adaptive_policy() {
+        let patience = Duration::from_millis(200);
+        let mutex = SharedMutex::with_policy(0, LockPolicy::Adaptive(patience));
+        let pool = Pool::new(4);
 
         pool.scoped(|scope| {
-            for _ in 0..n {
+            let read = mutex.read().unwrap();
+
+            scope.execute(|| *mutex.write().unwrap() += 1);
+
+            // A writer which hasn't waited too long does not block new readers.
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(*mutex.try_read().unwrap(), 0);
+
+            // Once it has, it does.
+            thread::sleep(patience);
+            assert!(mutex.try_read().is_err());
+            drop(read);
+        });
+
+        assert_eq!(*mutex.read().unwrap(), 1);
+
+        // Writers make progress despite a continuous stream of readers.
+        let done = AtomicUsize::new(0);
+
+        pool.scoped(|scope| {
+            for _ in 0..3 {
                 scope.execute(|| {
-                    let before = *mutex.read().unwrap();
-                    *mutex.write().unwrap() += 1;
-                    let after = *mutex.read().unwrap();
+                    while done.load(Ordering::SeqCst) == 0 {
+                        let _read = mutex.read().unwrap();
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                });
+            }
 
-                    assert!(before < after, "Time travel! {:?} >= {:?}", before, after);
-                })
+            for _ in 0..5 {
+                let start = Instant::now();
+                *mutex.write().unwrap() += 1;
+
+                let waited = start.elapsed();
+                assert!(waited < patience * 5, "Writer waited for {:?}", waited);
             }
+
+            done.store(1, Ordering::SeqCst);
         });
 
-        assert_eq!(*mutex.get_mut().unwrap(), 100);
+        assert_eq!(*mutex.read().unwrap(), 6);
+
+        // Patience too long to represent never runs out.
+        let mutex = SharedMutex::with_policy(0, LockPolicy::Adaptive(Duration::MAX));
+
+        pool.scoped(|scope| {
+            let read = mutex.read().unwrap();
+            scope.execute(|| *mutex.write().unwrap() += 1);
+
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(*mutex.try_read().unwrap(), 0);
+            drop(read);
+        });
+
+        assert_eq!(*mutex.read().unwrap(), 1);
         pool.shutdown();
     }
 
     #[test]
-    fn test_simple_single_thread() {
-        let mut mutex = SharedMutex::new(0);
-        let n = 100;
+    fn test_on_write_unlock() {
+        let mutex = SharedMutex::new(vec![1, 2]);
+        let unlocks = Arc::new(AtomicUsize::new(0));
 
-        for _ in 0..n {
-            let before = *mutex.read().unwrap();
-            *mutex.write().unwrap() += 1;
-            let after = *mutex.read().unwrap();
+        let counter = unlocks.clone();
+        mutex.on_write_unlock(move || { counter.fetch_add(1, Ordering::SeqCst); });
 
-            assert!(before < after, "Time travel! {:?} >= {:?}", before, after);
+        drop(mutex.read().unwrap());
+        assert_eq!(unlocks.load(Ordering::SeqCst), 0);
+
+        drop(mutex.write().unwrap());
+        assert_eq!(unlocks.load(Ordering::SeqCst), 1);
+
+        // Mapped guards release the lock through the same path.
+        drop(mutex.write().unwrap().into_mapped().map(|v| &mut v[0]));
+        assert_eq!(unlocks.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_try_unwrap_arc() {
+        let mutex = Arc::new(SharedMutex::new(vec![1, 2]));
+        let other = mutex.clone();
+
+        let mutex = SharedMutex::try_unwrap_arc(mutex).unwrap_err();
+        drop(other);
+
+        assert_eq!(SharedMutex::try_unwrap_arc(mutex).unwrap().unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_leveled() {
+        let low = SharedMutex::new_leveled(1, 1);
+        let high = SharedMutex::new_leveled(2, 2);
+        let plain = SharedMutex::new(3);
+        assert_eq!(high.level(), Some(2));
+        assert_eq!(plain.level(), None);
+
+        // Acquiring in increasing order is fine, as are unleveled locks.
+        {
+            let _low = low.read().unwrap();
+            let _high = high.write().unwrap();
+            let _plain = plain.write().unwrap();
         }
 
-        assert_eq!(*mutex.get_mut().unwrap(), 100);
+        // Released locks no longer count.
+        drop(high.read().unwrap());
+        drop(low.write().unwrap());
     }
 
     #[test]
-    fn test_locking_multithreaded() {
-        // This test makes a best effort to test the actual locking
-        // behavior of the mutex.
-        //
-        // Read locks attempt to read from an atomic many times,
-        // while write locks write to them many times.
-        //
-        // If any of these operations interleave (readers read different
-        // values under the same lock, writers observe other writers) then
-        // we know there is a bug.
-        //
-        // We make use of a barrier to attempt to cluster threads together.
+    fn test_leveled_across_threads() {
+        let low = SharedMutex::new_leveled(1, 1);
+        let high = Arc::new(SharedMutex::new_leveled(2, 2));
+
+        // Locks released on another thread no longer count for the thread
+        // which acquired them.
+        let write = high.write_arc().unwrap();
+        thread::spawn(move || drop(write)).join().unwrap();
+        drop(low.read().unwrap());
+
+        let read = high.read_arc().unwrap();
+        thread::spawn(move || drop(read)).join().unwrap();
+        drop(low.write().unwrap());
+
+        // Neither do leaked locks.
+        let high = SharedMutex::leak_static(2);
+        high.read().unwrap().leak();
+        drop(low.write().unwrap());
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "acquiring mutex \"low\" (level 1) while holding mutex \"high\" (level 2)")]
+    fn test_leveled_violation() {
+        let mut low = SharedMutex::new_leveled((), 1);
+        low.set_name("low");
+        let mut high = SharedMutex::new_leveled((), 2);
+        high.set_name("high");
+
+        let _high = high.read().unwrap();
+        let _low = low.read().unwrap();
+    }
 
+    #[cfg(feature = "watchdog")]
+    #[test]
+    fn test_watchdog() {
         let mut mutex = SharedMutex::new(());
-        let value = AtomicUsize::new(0);
+        mutex.set_name("test");
 
-        let threads = 50;
-        let actors = threads * 20; // Must be a multiple threads.
-        let actions_per_actor = 100;
-        let start_barrier = Barrier::new(threads);
-        let pool = Pool::new(threads);
+        let warnings = Arc::new(AtomicUsize::new(0));
+        let counter = warnings.clone();
+        mutex.warn_if_held_longer_than(Duration::from_millis(20), move |name, held| {
+            assert_eq!(name, Some("test"));
+            assert!(held > Duration::from_millis(20));
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
 
-        pool.scoped(|scope| {
-            for _ in 0..actors {
-                // Reader
-                scope.execute(|| {
-                    start_barrier.wait();
+        drop(mutex.write().unwrap());
+        drop(mutex.read().unwrap());
+        assert_eq!(warnings.load(Ordering::SeqCst), 0);
 
-                    let _read = mutex.read().unwrap();
-                    let original = value.load(Ordering::SeqCst);
+        let write = mutex.write().unwrap();
+        thread::sleep(Duration::from_millis(30));
+        drop(write);
+        assert_eq!(warnings.load(Ordering::SeqCst), 1);
 
-                    for _ in 0..actions_per_actor {
-                        assert_eq!(original, value.load(Ordering::SeqCst));
-                    }
-                });
+        let read = mutex.read().unwrap();
+        thread::sleep(Duration::from_millis(30));
+        drop(read);
+        assert_eq!(warnings.load(Ordering::SeqCst), 2);
+    }
 
-                // Writer
-                scope.execute(|| {
-                    start_barrier.wait();
+    #[test]
+    fn test_ordering() {
+        let mut mutexes = vec![SharedMutex::new(3), SharedMutex::new(1), SharedMutex::new(2)];
+        poison(&mutexes[1]);
 
-                    let _write = mutex.write().unwrap();
-                    let mut previous = value.load(Ordering::SeqCst);
+        assert!(mutexes[0] == mutexes[0]);
+        assert!(mutexes[0] != SharedMutex::new(1));
+        assert!(mutexes[1] == SharedMutex::new(1));
 
-                    for _ in 0..actions_per_actor {
-                        let next = value.fetch_add(1, Ordering::SeqCst);
+        mutexes.sort();
 
-                        // fetch_add returns the old value
-                        assert_eq!(previous, next);
+        assert_eq!(*mutexes[0].read().unwrap_err().into_inner(), 1);
+        assert_eq!(*mutexes[1].read().unwrap(), 2);
+        assert_eq!(*mutexes[2].read().unwrap(), 3);
+    }
 
-                        // next time we will expect the old value + 1
-                        previous = next + 1;
-                    }
-                });
+    #[test]
+    fn test_eq() {
+        let (a, b) = (SharedMutex::new(1), SharedMutex::new(1));
+        assert!(a == b && a == a);
+        assert!(SharedMutex::new(0) < a);
+
+        // Poisoned mutexes compare by their last-known value.
+        poison(&b);
+        assert!(a == b && SharedMutex::new(2) > b);
+
+        // Comparing both ways at once doesn't deadlock behind writers.
+        let b = SharedMutex::new(1);
+        let pool = Pool::new(4);
+        pool.scoped(|scope| {
+            for &(x, y) in &[(&a, &b), (&b, &a)] {
+                scope.execute(move || for _ in 0..1000 { let _ = x == y; });
+                scope.execute(move || for _ in 0..1000 { *x.write().unwrap() += 1; });
             }
         });
+        assert!(a == b);
+    }
 
-        mutex.get_mut().unwrap();
-        pool.shutdown();
+    #[test]
+    fn test_compare_and_set() {
+        let mutex = SharedMutex::new(10);
+
+        assert!(!mutex.compare_and_set(&5, 20).unwrap());
+        assert_eq!(*mutex.read().unwrap(), 10);
+
+        assert!(mutex.compare_and_set(&10, 20).unwrap());
+        assert_eq!(*mutex.read().unwrap(), 20);
     }
 
     #[test]
-    fn test_simple_waiting() {
-        let pool = Pool::new(20);
-        let mutex = SharedMutex::new(());
+    fn test_release_and_notify() {
+        let mutex = SharedMutex::new(false);
         let cond = Condvar::new();
+        let pool = Pool::new(1);
 
         pool.scoped(|scope| {
-            let lock = mutex.write().unwrap();
+            let mut read = mutex.read().unwrap();
 
             scope.execute(|| {
-                let _ = mutex.write().unwrap();
-                cond.notify_one();
+                let mut write = mutex.write().unwrap();
+                *write = true;
+                write.release_and_notify(&cond, NotifyKind::All);
             });
 
-            // Write -> Read
-            let lock = lock.wait_for_read(&cond).unwrap();
+            while !*read { read = read.wait_for_read(&cond).unwrap(); }
+        });
 
-            scope.execute(|| {
-                drop(mutex.write().unwrap());
-                cond.notify_one();
-            });
+        // The lock was released before notifying.
+        assert!(mutex.try_write().is_ok());
 
-            // Read -> Read
-            let lock = lock.wait_for_read(&cond).unwrap();
+        mutex.read().unwrap().release_and_notify(&cond, NotifyKind::One);
+        assert!(mutex.try_write().is_ok());
+        pool.shutdown();
+    }
 
-            scope.execute(|| {
-                drop(mutex.write().unwrap());
-                cond.notify_one();
-            });
+    #[test]
+    fn test_freeze() {
+        let mutex = SharedMutex::new(vec![1, 2, 3]);
 
+        let frozen = mutex.write().unwrap().freeze_clone();
+        mutex.write().unwrap().push(4);
+        assert_eq!(*frozen, [1, 2, 3]);
 
-            // Read -> Write
-            let lock = lock.wait_for_write(&cond).unwrap();
+        // The mutex is left unlocked and unpoisoned, holding the default.
+        let frozen = mutex.write().unwrap().freeze();
+        assert_eq!(*frozen, [1, 2, 3, 4]);
+        assert!(mutex.try_write().unwrap().is_empty());
+
+        let threads = (0..4).map(|_| {
+            let frozen = frozen.clone();
+            thread::spawn(move || frozen.iter().sum::<i32>())
+        }).collect::<Vec<_>>();
+
+        for thread in threads { assert_eq!(thread.join().unwrap(), 10) }
+    }
+
+    #[test]
+    fn test_notify_on_drop() {
+        let mutex = SharedMutex::new(0);
+        let cond = Condvar::new();
+        let pool = Pool::new(1);
+
+        pool.scoped(|scope| {
+            let mut read = mutex.read().unwrap();
 
             scope.execute(|| {
-                drop(mutex.write().unwrap());
-                cond.notify_one();
+                for _ in 0..2 {
+                    let mut write = mutex.write().unwrap().notify_all_on_drop(&cond);
+                    *write += 1;
+                    *write += 1;
+                }
             });
 
-            // Write -> Write
-            lock.wait_for_write(&cond).unwrap();
+            while *read < 4 { read = read.wait_for_read(&cond).unwrap(); }
         });
 
+        // The lock was released before notifying.
+        assert!(mutex.try_write().is_ok());
+
+        *mutex.write().unwrap().notify_one_on_drop(&cond) += 1;
+        assert_eq!(*mutex.try_write().unwrap(), 5);
         pool.shutdown();
     }
 
     #[test]
-    fn test_mapping() {
+    fn test_try_map() {
         let mutex = SharedMutex::new(vec![1, 2, 3]);
 
-        *mutex.write().unwrap().into_mapped()
-            .map(|v| &mut v[0]) = 100;
+        let read = mutex.read().unwrap().into_mapped();
+        let read = read.try_map(|v| v.get(5)).unwrap_err();
+        assert_eq!(*read.try_map(|v| v.get(1)).unwrap(), 2);
 
-        assert_eq!(*mutex.read().unwrap().into_mapped().map(|v| &v[0]), 100);
+        let write = mutex.write().unwrap().into_mapped();
+        let write = write.try_map(|v| v.get_mut(5)).unwrap_err();
+        *write.try_map(|v| v.get_mut(1)).unwrap() = 20;
+
+        assert_eq!(*mutex.read().unwrap(), vec![1, 20, 3]);
     }
 
+    #[cfg(feature = "async")]
     #[test]
-    fn test_map_recover() {
-        let mutex = SharedMutex::new(vec![1, 2]);
+    fn test_async() {
+        use std::future::Future;
+        use std::pin::Pin;
+        use std::sync::atomic::AtomicBool;
+        use std::task::{Context, Poll, Wake, Waker};
 
-        let mut write_map = mutex.write().unwrap().into_mapped()
-            .map(|v| &mut v[0]);
-        *write_map = 123;
+        struct Flag(AtomicBool);
 
-        let whole_guard = write_map.recover(&mutex).unwrap();
-        assert_eq!(&*whole_guard, &[123, 2]);
-    }
+        impl Wake for Flag {
+            fn wake(self: Arc<Self>) { self.0.store(true, Ordering::SeqCst) }
+        }
 
-    #[test]
-    fn test_try_locking() {
-        let mutex = SharedMutex::new(10);
-        mutex.try_read().unwrap();
-        mutex.try_write().unwrap();
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mutex = SharedMutex::new(0);
+
+        // Reading waits for the writer to release the lock.
+        let write = mutex.write().unwrap();
+        let mut read = mutex.read_async();
+        assert!(Pin::new(&mut read).poll(&mut cx).is_pending());
+        assert!(!flag.0.load(Ordering::SeqCst));
+
+        drop(write);
+        assert!(flag.0.swap(false, Ordering::SeqCst));
+
+        let read = match Pin::new(&mut read).poll(&mut cx) {
+            Poll::Ready(guard) => guard.unwrap(),
+            Poll::Pending => panic!("Read lock not acquired after writer released.")
+        };
+
+        // Writing waits for the reader to release the lock.
+        let mut write = mutex.write_async();
+        assert!(Pin::new(&mut write).poll(&mut cx).is_pending());
+
+        drop(read);
+        assert!(flag.0.load(Ordering::SeqCst));
+
+        match Pin::new(&mut write).poll(&mut cx) {
+            Poll::Ready(guard) => *guard.unwrap() = 1,
+            Poll::Pending => panic!("Write lock not acquired after reader released.")
+        }
+
+        assert_eq!(*mutex.read().unwrap(), 1);
+
+        // Writing waits for the previous writer to release the lock.
+        let first = mutex.write().unwrap();
+        let mut second = mutex.write_async();
+        assert!(Pin::new(&mut second).poll(&mut cx).is_pending());
+
+        flag.0.store(false, Ordering::SeqCst);
+        drop(first);
+        assert!(flag.0.load(Ordering::SeqCst));
+
+        match Pin::new(&mut second).poll(&mut cx) {
+            Poll::Ready(guard) => *guard.unwrap() = 2,
+            Poll::Pending => panic!("Write lock not acquired after writer released.")
+        }
+
+        assert_eq!(*mutex.read().unwrap(), 2);
     }
 }
 
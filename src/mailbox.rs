@@ -0,0 +1,188 @@
+//! A SharedMutex paired with a mailbox, for handing messages to woken waiters.
+//!
+//! Like a `Monitor`, a `Mailbox` couples a SharedMutex with a condition
+//! variable. Notifiers also post a message, which the waiter receives along
+//! with the lock when it wakes up, so no separate channel is needed:
+//!
+//! ```
+//! use std::sync::Arc;
+//! use std::thread;
+//! use shared_mutex::mailbox::Mailbox;
+//!
+//! let mailbox = Arc::new(Mailbox::new(0));
+//!
+//! let guard = mailbox.write().unwrap();
+//!
+//! let notifier = {
+//!     let mailbox = mailbox.clone();
+//!     thread::spawn(move || mailbox.post("hello").unwrap())
+//! };
+//!
+//! let (mut guard, messages) = guard.wait_for_write_msg().unwrap();
+//! assert_eq!(messages, ["hello"]);
+//! **guard += 1;
+//!
+//! drop(guard);
+//! notifier.join().unwrap();
+//! ```
+
+use std::sync::{Mutex, Condvar, LockResult};
+use std::ops::{Deref, DerefMut};
+use std::{fmt, mem};
+
+use poison;
+use {SharedMutex, SharedMutexReadGuard, SharedMutexWriteGuard};
+
+/// A SharedMutex and a Condvar, with messages for the threads waiting on it.
+///
+/// Messages are posted and collected under the write lock, so a waiter which
+/// checked for messages while holding a lock can't miss one.
+pub struct Mailbox<T: ?Sized, M> {
+    cond: Condvar,
+    messages: Mutex<Vec<M>>,
+    mutex: SharedMutex<T>
+}
+
+/// A shared read guard to the data in a Mailbox.
+pub struct MailboxReadGuard<'mutex, T: ?Sized + 'mutex, M: 'mutex> {
+    guard: SharedMutexReadGuard<'mutex, T>,
+    cond: &'mutex Condvar,
+    messages: &'mutex Mutex<Vec<M>>
+}
+
+/// An exclusive write guard to the data in a Mailbox.
+pub struct MailboxWriteGuard<'mutex, T: ?Sized + 'mutex, M: 'mutex> {
+    guard: SharedMutexWriteGuard<'mutex, T>,
+    cond: &'mutex Condvar,
+    messages: &'mutex Mutex<Vec<M>>
+}
+
+impl<T, M> Mailbox<T, M> {
+    /// Create a new Mailbox with no messages.
+    pub fn new(val: T) -> Mailbox<T, M> {
+        Mailbox {
+            cond: Condvar::new(),
+            messages: Mutex::new(Vec::new()),
+            mutex: SharedMutex::new(val)
+        }
+    }
+}
+
+impl<T: ?Sized, M> Mailbox<T, M> {
+    /// Acquire a shared read lock on the mailbox.
+    pub fn read(&self) -> LockResult<MailboxReadGuard<T, M>> {
+        poison::map_result(self.mutex.read(), |guard| {
+            MailboxReadGuard {
+                guard: guard,
+                cond: &self.cond,
+                messages: &self.messages
+            }
+        })
+    }
+
+    /// Acquire an exclusive write lock on the mailbox.
+    pub fn write(&self) -> LockResult<MailboxWriteGuard<T, M>> {
+        poison::map_result(self.mutex.write(), |guard| {
+            MailboxWriteGuard {
+                guard: guard,
+                cond: &self.cond,
+                messages: &self.messages
+            }
+        })
+    }
+
+    /// Post a message and wake all waiting threads, under a write lock.
+    ///
+    /// The message is posted even if the mailbox is poisoned.
+    pub fn post(&self, message: M) -> LockResult<()> {
+        poison::map_result(self.write(), |mut guard| guard.post(message))
+    }
+}
+
+// Take all messages posted so far.
+fn take<M>(messages: &Mutex<Vec<M>>) -> Vec<M> {
+    let mut messages = messages.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    mem::replace(&mut *messages, Vec::new())
+}
+
+impl<'mutex, T: ?Sized, M> MailboxReadGuard<'mutex, T, M> {
+    /// Wait for a message, then resume with a write guard and all messages
+    /// posted and not yet taken.
+    ///
+    /// The messages are taken under the reacquired write lock, and may be
+    /// empty after a spurious wakeup.
+    pub fn wait_for_write_msg(self) -> LockResult<(MailboxWriteGuard<'mutex, T, M>, Vec<M>)> {
+        let (guard, cond, messages) = (self.guard, self.cond, self.messages);
+        poison::map_result(guard.wait_for_write(cond), |guard| {
+            (MailboxWriteGuard { guard: guard, cond: cond, messages: messages }, take(messages))
+        })
+    }
+}
+
+impl<'mutex, T: ?Sized, M> MailboxWriteGuard<'mutex, T, M> {
+    /// Wait for a message, then resume with another write guard and all
+    /// messages posted and not yet taken.
+    ///
+    /// See `MailboxReadGuard::wait_for_write_msg` for more information.
+    pub fn wait_for_write_msg(self) -> LockResult<(Self, Vec<M>)> {
+        let (guard, cond, messages) = (self.guard, self.cond, self.messages);
+        poison::map_result(guard.wait_for_write(cond), |guard| {
+            (MailboxWriteGuard { guard: guard, cond: cond, messages: messages }, take(messages))
+        })
+    }
+
+    /// Post a message and wake all waiting threads.
+    ///
+    /// The waiters only resume once this guard is released.
+    pub fn post(&mut self, message: M) {
+        self.messages.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(message);
+        self.cond.notify_all()
+    }
+
+    /// Take all messages posted and not yet taken, without waiting.
+    pub fn take_messages(&mut self) -> Vec<M> { take(self.messages) }
+}
+
+impl<'mutex, T: ?Sized, M> Deref for MailboxReadGuard<'mutex, T, M> {
+    type Target = SharedMutexReadGuard<'mutex, T>;
+
+    fn deref(&self) -> &Self::Target { &self.guard }
+}
+
+impl<'mutex, T: ?Sized, M> Deref for MailboxWriteGuard<'mutex, T, M> {
+    type Target = SharedMutexWriteGuard<'mutex, T>;
+
+    fn deref(&self) -> &Self::Target { &self.guard }
+}
+
+impl<'mutex, T: ?Sized, M> DerefMut for MailboxWriteGuard<'mutex, T, M> {
+    fn deref_mut(&mut self) -> &mut Self::Target { &mut self.guard }
+}
+
+impl<T: ?Sized, M> AsRef<SharedMutex<T>> for Mailbox<T, M> {
+    fn as_ref(&self) -> &SharedMutex<T> { &self.mutex }
+}
+
+impl<T: ?Sized + fmt::Debug, M> fmt::Debug for Mailbox<T, M> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Mailbox")
+            .field("mutex", &&self.mutex)
+            .finish()
+    }
+}
+
+impl<'mutex, T: ?Sized + fmt::Debug, M> fmt::Debug for MailboxReadGuard<'mutex, T, M> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MailboxReadGuard")
+            .field("data", &self.guard)
+            .finish()
+    }
+}
+
+impl<'mutex, T: ?Sized + fmt::Debug, M> fmt::Debug for MailboxWriteGuard<'mutex, T, M> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MailboxWriteGuard")
+            .field("data", &self.guard)
+            .finish()
+    }
+}
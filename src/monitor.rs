@@ -1,43 +1,109 @@
 //! A monitor convenience type that couples a SharedMutex and a Condvar.
 //!
 //! This type is more convenient to use but a little bit less general than
-//! SharedMutex since the monitor uses only one condition variable, whereas
-//! a SharedMutex can be used with any number of condition variables.
+//! SharedMutex since the monitor uses a fixed set of condition variables,
+//! whereas a SharedMutex can be used with any condition variable.
+//!
+//! A monitor has a single condition by default. Monitors created with
+//! `Monitor::with_conditions` have several, identified by an index or by
+//! any type which converts into one, so that waiters are only woken by the
+//! events they are waiting for:
+//!
+//! ```
+//! use std::sync::Arc;
+//! use std::thread;
+//! use std::collections::VecDeque;
+//! use shared_mutex::NotifyKind;
+//! use shared_mutex::monitor::Monitor;
+//!
+//! #[derive(Clone, Copy)]
+//! enum Condition { NotEmpty, NotFull }
+//!
+//! impl From<Condition> for usize {
+//!     fn from(condition: Condition) -> usize { condition as usize }
+//! }
+//!
+//! const CAPACITY: usize = 2;
+//!
+//! let buffer = Arc::new(Monitor::with_conditions(VecDeque::new(), 2));
+//!
+//! let producer = {
+//!     let buffer = buffer.clone();
+//!     thread::spawn(move || for i in 0..10 {
+//!         let mut queue = buffer.write().unwrap();
+//!         while queue.len() == CAPACITY {
+//!             queue = queue.wait_on(Condition::NotFull).unwrap();
+//!         }
+//!
+//!         queue.push_back(i);
+//!         queue.notify(Condition::NotEmpty, NotifyKind::One);
+//!     })
+//! };
+//!
+//! let mut received = Vec::new();
+//! while received.len() < 10 {
+//!     let mut queue = buffer.write().unwrap();
+//!     while queue.is_empty() {
+//!         queue = queue.wait_on(Condition::NotEmpty).unwrap();
+//!     }
+//!
+//!     received.push(queue.pop_front().unwrap());
+//!     queue.notify(Condition::NotFull, NotifyKind::One);
+//! }
+//!
+//! producer.join().unwrap();
+//! assert_eq!(received, (0..10).collect::<Vec<_>>());
+//! ```
 
-use std::sync::{Condvar, LockResult};
+use std::sync::{Condvar, LockResult, PoisonError};
 use std::ops::{Deref, DerefMut};
+use std::time::Duration;
 use std::fmt;
 
 use poison;
-use {SharedMutex, SharedMutexReadGuard, SharedMutexWriteGuard};
+use {SharedMutex, SharedMutexReadGuard, SharedMutexWriteGuard, NotifyKind, WaitTimeoutResult};
 
 /// A convenience wrapper around a SharedMutex and a Condvar.
 ///
 /// Provides an ergonomic API for locking and waiting on predicates
 /// associated with the internal data.
+///
+/// Methods which don't take a condition use the first condition.
 pub struct Monitor<T: ?Sized> {
-    cond: Condvar,
+    conds: Box<[Condvar]>,
     mutex: SharedMutex<T>
 }
 
 /// A shared read guard to the data in a Monitor.
 pub struct MonitorReadGuard<'mutex, T: ?Sized + 'mutex> {
     guard: SharedMutexReadGuard<'mutex, T>,
-    cond: &'mutex Condvar
+    conds: &'mutex [Condvar]
 }
 
 /// An exclusive write guard to the data in a Monitor.
 pub struct MonitorWriteGuard<'mutex, T: ?Sized + 'mutex> {
     guard: SharedMutexWriteGuard<'mutex, T>,
-    cond: &'mutex Condvar
+    conds: &'mutex [Condvar]
 }
 
 impl<T> Monitor<T> {
     /// Create a new Monitor.
     pub fn new(val: T) -> Monitor<T> {
+        Monitor::with_conditions(val, 1)
+    }
+
+    /// Create a new Monitor with the given number of conditions.
+    ///
+    /// Conditions are identified by their index, or by any type which
+    /// converts into one, such as a user enum implementing `Into<usize>`.
+    ///
+    /// Panics if `conditions` is 0.
+    pub fn with_conditions(val: T, conditions: usize) -> Monitor<T> {
+        assert!(conditions != 0, "a Monitor needs at least one condition");
+
         Monitor {
             mutex: SharedMutex::new(val),
-            cond: Condvar::new()
+            conds: (0..conditions).map(|_| Condvar::new()).collect::<Vec<_>>().into_boxed_slice()
         }
     }
 }
@@ -48,7 +114,7 @@ impl<T: ?Sized> Monitor<T> {
         poison::map_result(self.mutex.read(), |guard| {
             MonitorReadGuard {
                 guard: guard,
-                cond: &self.cond
+                conds: &self.conds
             }
         })
     }
@@ -58,18 +124,56 @@ impl<T: ?Sized> Monitor<T> {
         poison::map_result(self.mutex.write(), |guard| {
             MonitorWriteGuard {
                 guard: guard,
-                cond: &self.cond
+                conds: &self.conds
             }
         })
     }
 
+    /// Acquire a shared read lock on the monitor once `condition` returns
+    /// false, waiting for notifications on the monitor meanwhile.
+    ///
+    /// See `MonitorReadGuard::wait_while` for more information.
+    pub fn wait_while_read<F>(&self, condition: F) -> LockResult<MonitorReadGuard<T>>
+    where F: FnMut(&T) -> bool {
+        match self.read() {
+            Ok(guard) => guard.wait_while(condition),
+            Err(poisoned) => Err(poisoned)
+        }
+    }
+
+    /// Acquire an exclusive write lock on the monitor once `condition`
+    /// returns false, waiting for notifications on the monitor meanwhile.
+    ///
+    /// See `MonitorWriteGuard::wait_while` for more information.
+    pub fn wait_while_write<F>(&self, condition: F) -> LockResult<MonitorWriteGuard<T>>
+    where F: FnMut(&mut T) -> bool {
+        match self.write() {
+            Ok(guard) => guard.wait_while(condition),
+            Err(poisoned) => Err(poisoned)
+        }
+    }
+
+    /// Acquire an exclusive write lock on the monitor, and wait for
+    /// notifications on the monitor while `condition` returns true, for at
+    /// most `timeout` in total.
+    ///
+    /// See `MonitorWriteGuard::wait_timeout_while` for more information.
+    pub fn wait_timeout_while_write<F>(&self, timeout: Duration, condition: F)
+        -> LockResult<(MonitorWriteGuard<T>, WaitTimeoutResult)>
+    where F: FnMut(&mut T) -> bool {
+        match self.write() {
+            Ok(guard) => guard.wait_timeout_while(timeout, condition),
+            Err(poisoned) => Err(PoisonError::new((poisoned.into_inner(), WaitTimeoutResult(false))))
+        }
+    }
+
     /// Notify one thread which is waiting on the monitor.
     ///
     /// Note that it is safe but often incorrect to notify without holding any
     /// lock on the monitor, since the predicate may change between a
     /// notification and a predicate check, potentially causing a deadlock.
     #[inline]
-    pub fn notify_one(&self) { self.cond.notify_one() }
+    pub fn notify_one(&self) { self.conds[0].notify_one() }
 
     /// Notify all threads which are waiting on the monitor.
     ///
@@ -77,71 +181,207 @@ impl<T: ?Sized> Monitor<T> {
     /// lock on the monitor, since the predicate may change between a
     /// notification and a predicate check, potentially causing a deadlock.
     #[inline]
-    pub fn notify_all(&self) { self.cond.notify_all() }
+    pub fn notify_all(&self) { self.conds[0].notify_all() }
+
+    /// Run `action` on the data under a write lock, then notify one thread
+    /// which is waiting on the monitor before releasing the lock.
+    ///
+    /// Waiters check their predicate and start waiting while holding a lock
+    /// on the monitor, so any waiter which saw the data before `action`
+    /// changed it is already waiting by the time it is notified. Unlike with
+    /// `notify_one`, no wakeups can be lost.
+    pub fn notify_one_locked<R, F>(&self, action: F) -> LockResult<R>
+    where F: FnOnce(&mut T) -> R {
+        poison::map_result(self.mutex.write(), |mut guard| {
+            let result = action(&mut guard);
+            self.conds[0].notify_one();
+            result
+        })
+    }
+
+    /// Run `action` on the data under a write lock, then notify all threads
+    /// which are waiting on the monitor before releasing the lock.
+    ///
+    /// See `notify_one_locked` for more information.
+    pub fn notify_all_locked<R, F>(&self, action: F) -> LockResult<R>
+    where F: FnOnce(&mut T) -> R {
+        poison::map_result(self.mutex.write(), |mut guard| {
+            let result = action(&mut guard);
+            self.conds[0].notify_all();
+            result
+        })
+    }
+
+    /// Notify threads which are waiting on the given condition.
+    ///
+    /// The same caveats as for `notify_one` apply. Panics if the monitor has
+    /// no such condition.
+    #[inline]
+    pub fn notify<C: Into<usize>>(&self, condition: C, kind: NotifyKind) {
+        kind.notify(&self.conds[condition.into()])
+    }
 
     /// Get a reference to the condition variable in this Monitor for external use.
     #[inline]
-    pub fn cond(&self) -> &Condvar { &self.cond }
+    pub fn cond(&self) -> &Condvar { &self.conds[0] }
+
+    /// Get a reference to the condition variable for the given condition.
+    ///
+    /// Panics if the monitor has no such condition.
+    #[inline]
+    pub fn condition<C: Into<usize>>(&self, condition: C) -> &Condvar {
+        &self.conds[condition.into()]
+    }
 }
 
 impl<'mutex, T: ?Sized> MonitorReadGuard<'mutex, T> {
     /// Wait for a notification on the monitor, then resume with another read guard.
     pub fn wait_for_read(self) -> LockResult<Self> {
-        let (guard, cond) = (self.guard, self.cond);
-        poison::map_result(guard.wait_for_read(cond), |guard| {
+        let (guard, conds) = (self.guard, self.conds);
+        poison::map_result(guard.wait_for_read(&conds[0]), |guard| {
             MonitorReadGuard {
                 guard: guard,
-                cond: cond
+                conds: conds
             }
         })
     }
 
     /// Wait for a notification on the monitor, then resume with a write guard.
     pub fn wait_for_write(self) -> LockResult<MonitorWriteGuard<'mutex, T>> {
-        let (guard, cond) = (self.guard, self.cond);
-        poison::map_result(guard.wait_for_write(cond), |guard| {
+        let (guard, conds) = (self.guard, self.conds);
+        poison::map_result(guard.wait_for_write(&conds[0]), |guard| {
             MonitorWriteGuard {
                 guard: guard,
-                cond: cond
+                conds: conds
+            }
+        })
+    }
+
+    /// Wait for a notification on the given condition, then resume with
+    /// another read guard.
+    ///
+    /// Panics if the monitor has no such condition.
+    pub fn wait_on<C: Into<usize>>(self, condition: C) -> LockResult<Self> {
+        let (guard, conds) = (self.guard, self.conds);
+        poison::map_result(guard.wait_for_read(&conds[condition.into()]), |guard| {
+            MonitorReadGuard {
+                guard: guard,
+                conds: conds
+            }
+        })
+    }
+
+    /// Wait for notifications on the monitor while `condition` returns true,
+    /// then resume with another read guard.
+    ///
+    /// See `SharedMutexReadGuard::wait_while_for_read` for more information.
+    pub fn wait_while<F>(self, condition: F) -> LockResult<Self>
+    where F: FnMut(&T) -> bool {
+        let (guard, conds) = (self.guard, self.conds);
+        poison::map_result(guard.wait_while_for_read(&conds[0], condition), |guard| {
+            MonitorReadGuard {
+                guard: guard,
+                conds: conds
             }
         })
     }
 
     /// Notify a thread waiting on the monitor.
-    pub fn notify_one(&self) { self.cond.notify_one() }
+    pub fn notify_one(&self) { self.conds[0].notify_one() }
 
     /// Notify all threads waiting on the monitor.
-    pub fn notify_all(&self) { self.cond.notify_all() }
+    pub fn notify_all(&self) { self.conds[0].notify_all() }
+
+    /// Notify threads waiting on the given condition.
+    ///
+    /// Panics if the monitor has no such condition.
+    pub fn notify<C: Into<usize>>(&self, condition: C, kind: NotifyKind) {
+        kind.notify(&self.conds[condition.into()])
+    }
 }
 
 impl<'mutex, T: ?Sized> MonitorWriteGuard<'mutex, T> {
     /// Wait for a notification on the monitor, then resume with another read guard.
     pub fn wait_for_read(self) -> LockResult<MonitorReadGuard<'mutex, T>> {
-        let (guard, cond) = (self.guard, self.cond);
-        poison::map_result(guard.wait_for_read(cond), |guard| {
+        let (guard, conds) = (self.guard, self.conds);
+        poison::map_result(guard.wait_for_read(&conds[0]), |guard| {
             MonitorReadGuard {
                 guard: guard,
-                cond: cond
+                conds: conds
             }
         })
     }
 
     /// Wait for a notification on the monitor, then resume with another write guard.
     pub fn wait_for_write(self) -> LockResult<Self> {
-        let (guard, cond) = (self.guard, self.cond);
-        poison::map_result(guard.wait_for_write(cond), |guard| {
+        let (guard, conds) = (self.guard, self.conds);
+        poison::map_result(guard.wait_for_write(&conds[0]), |guard| {
             MonitorWriteGuard {
                 guard: guard,
-                cond: cond
+                conds: conds
             }
         })
     }
 
+    /// Wait for a notification on the given condition, then resume with
+    /// another write guard.
+    ///
+    /// Panics if the monitor has no such condition.
+    pub fn wait_on<C: Into<usize>>(self, condition: C) -> LockResult<Self> {
+        let (guard, conds) = (self.guard, self.conds);
+        poison::map_result(guard.wait_for_write(&conds[condition.into()]), |guard| {
+            MonitorWriteGuard {
+                guard: guard,
+                conds: conds
+            }
+        })
+    }
+
+    /// Wait for notifications on the monitor while `condition` returns true,
+    /// then resume with another write guard.
+    ///
+    /// See `SharedMutexWriteGuard::wait_while_for_write` for more information.
+    pub fn wait_while<F>(self, condition: F) -> LockResult<Self>
+    where F: FnMut(&mut T) -> bool {
+        let (guard, conds) = (self.guard, self.conds);
+        poison::map_result(guard.wait_while_for_write(&conds[0], condition), |guard| {
+            MonitorWriteGuard {
+                guard: guard,
+                conds: conds
+            }
+        })
+    }
+
+    /// Wait for notifications on the monitor while `condition` returns true,
+    /// for at most `timeout` in total, then resume with another write guard.
+    ///
+    /// Spurious wakeups don't restart the timeout. The returned guard always
+    /// holds the write lock, even if the timeout elapsed while `condition`
+    /// was still true. See `SharedMutexWriteGuard::wait_while_for_write_timeout`
+    /// for more information.
+    pub fn wait_timeout_while<F>(self, timeout: Duration, condition: F)
+        -> LockResult<(Self, WaitTimeoutResult)>
+    where F: FnMut(&mut T) -> bool {
+        let (guard, conds) = (self.guard, self.conds);
+        let result = guard.wait_while_for_write_timeout(&conds[0], condition, timeout);
+
+        poison::map_result(result, |(guard, timed_out)| {
+            (MonitorWriteGuard { guard: guard, conds: conds }, timed_out)
+        })
+    }
+
     /// Notify a thread waiting on the monitor.
-    pub fn notify_one(&self) { self.cond.notify_one() }
+    pub fn notify_one(&self) { self.conds[0].notify_one() }
 
     /// Notify all threads waiting on the monitor.
-    pub fn notify_all(&self) { self.cond.notify_all() }
+    pub fn notify_all(&self) { self.conds[0].notify_all() }
+
+    /// Notify threads waiting on the given condition.
+    ///
+    /// Panics if the monitor has no such condition.
+    pub fn notify<C: Into<usize>>(&self, condition: C, kind: NotifyKind) {
+        kind.notify(&self.conds[condition.into()])
+    }
 }
 
 impl<'mutex, T: ?Sized> Deref for MonitorReadGuard<'mutex, T> {
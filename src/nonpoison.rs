@@ -0,0 +1,216 @@
+//! A SharedMutex without poisoning.
+//!
+//! `NonPoisonSharedMutex` stores its data directly in the lock, without the
+//! `Poison` layer of `SharedMutex`, so `read` and `write` return guards
+//! rather than a `LockResult`. A panic while holding a write lock leaves the
+//! data as it was at the time, which is fine for data such as caches, where
+//! a partially updated value is acceptable.
+//!
+//! Unlike `RwLock`, which ignores the poisoning of an underlying SharedMutex,
+//! nothing is tracked at all, and the guards can still wait on a `Condvar`
+//! without reporting poison.
+
+use std::sync::Condvar;
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+use std::{fmt, mem};
+
+use RawSharedMutex;
+
+/// A reader-writer lock which can be used with a Condvar, without poisoning.
+pub struct NonPoisonSharedMutex<T: ?Sized> {
+    raw: RawSharedMutex,
+    data: UnsafeCell<T>
+}
+
+unsafe impl<T: ?Sized + Send> Send for NonPoisonSharedMutex<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for NonPoisonSharedMutex<T> {}
+
+/// A shared read guard on a NonPoisonSharedMutex.
+pub struct NonPoisonReadGuard<'mutex, T: ?Sized + 'mutex> {
+    mutex: &'mutex NonPoisonSharedMutex<T>
+}
+
+unsafe impl<'mutex, T: ?Sized + Sync> Send for NonPoisonReadGuard<'mutex, T> {}
+unsafe impl<'mutex, T: ?Sized + Sync> Sync for NonPoisonReadGuard<'mutex, T> {}
+
+/// An exclusive write guard on a NonPoisonSharedMutex.
+pub struct NonPoisonWriteGuard<'mutex, T: ?Sized + 'mutex> {
+    mutex: &'mutex NonPoisonSharedMutex<T>
+}
+
+unsafe impl<'mutex, T: ?Sized + Send> Send for NonPoisonWriteGuard<'mutex, T> {}
+unsafe impl<'mutex, T: ?Sized + Sync> Sync for NonPoisonWriteGuard<'mutex, T> {}
+
+impl<T> NonPoisonSharedMutex<T> {
+    /// Create a new NonPoisonSharedMutex protecting the given value.
+    #[inline]
+    pub fn new(value: T) -> Self {
+        NonPoisonSharedMutex {
+            raw: RawSharedMutex::new(),
+            data: UnsafeCell::new(value)
+        }
+    }
+
+    /// Extract the data from the lock and destroy the lock.
+    ///
+    /// Safe since it requires ownership of the lock.
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> NonPoisonSharedMutex<T> {
+    /// Acquire a shared read lock on the data.
+    #[inline]
+    pub fn read(&self) -> NonPoisonReadGuard<T> {
+        self.raw.read();
+        NonPoisonReadGuard { mutex: self }
+    }
+
+    /// Acquire an exclusive write lock on the data.
+    #[inline]
+    pub fn write(&self) -> NonPoisonWriteGuard<T> {
+        self.raw.write();
+        NonPoisonWriteGuard { mutex: self }
+    }
+
+    /// Attempt to acquire a shared read lock on the data without blocking.
+    #[inline]
+    pub fn try_read(&self) -> Option<NonPoisonReadGuard<T>> {
+        if self.raw.try_read() { Some(NonPoisonReadGuard { mutex: self }) } else { None }
+    }
+
+    /// Attempt to acquire an exclusive write lock on the data without blocking.
+    #[inline]
+    pub fn try_write(&self) -> Option<NonPoisonWriteGuard<T>> {
+        if self.raw.try_write() { Some(NonPoisonWriteGuard { mutex: self }) } else { None }
+    }
+
+    /// Get a mutable reference to the data without locking.
+    ///
+    /// Safe since it requires a unique reference to the lock.
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+}
+
+impl<'mutex, T: ?Sized> NonPoisonReadGuard<'mutex, T> {
+    /// Wait on the given condition variable, and resume with a write lock.
+    ///
+    /// See the documentation for `std::sync::Condvar::wait` for more information.
+    pub fn wait_for_write(self, cond: &Condvar) -> NonPoisonWriteGuard<'mutex, T> {
+        let mutex = self.mutex;
+
+        // Don't double-unlock.
+        mem::forget(self);
+
+        mutex.raw.wait_from_read_to_write(cond);
+        NonPoisonWriteGuard { mutex: mutex }
+    }
+
+    /// Wait on the given condition variable, and resume with another read lock.
+    ///
+    /// See the documentation for `std::sync::Condvar::wait` for more information.
+    pub fn wait_for_read(self, cond: &Condvar) -> Self {
+        let mutex = self.mutex;
+
+        // Don't double-unlock.
+        mem::forget(self);
+
+        mutex.raw.wait_from_read_to_read(cond);
+        NonPoisonReadGuard { mutex: mutex }
+    }
+}
+
+impl<'mutex, T: ?Sized> NonPoisonWriteGuard<'mutex, T> {
+    /// Wait on the given condition variable, and resume with another write lock.
+    pub fn wait_for_write(self, cond: &Condvar) -> Self {
+        let mutex = self.mutex;
+
+        // Don't double-unlock.
+        mem::forget(self);
+
+        mutex.raw.wait_from_write_to_write(cond);
+        NonPoisonWriteGuard { mutex: mutex }
+    }
+
+    /// Wait on the given condition variable, and resume with a read lock.
+    pub fn wait_for_read(self, cond: &Condvar) -> NonPoisonReadGuard<'mutex, T> {
+        let mutex = self.mutex;
+
+        // Don't double-unlock.
+        mem::forget(self);
+
+        mutex.raw.wait_from_write_to_read(cond);
+        NonPoisonReadGuard { mutex: mutex }
+    }
+}
+
+impl<'mutex, T: ?Sized> Deref for NonPoisonReadGuard<'mutex, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T { unsafe { &*self.mutex.data.get() } }
+}
+
+impl<'mutex, T: ?Sized> Deref for NonPoisonWriteGuard<'mutex, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T { unsafe { &*self.mutex.data.get() } }
+}
+
+impl<'mutex, T: ?Sized> DerefMut for NonPoisonWriteGuard<'mutex, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T { unsafe { &mut *self.mutex.data.get() } }
+}
+
+impl<'mutex, T: ?Sized> Drop for NonPoisonReadGuard<'mutex, T> {
+    #[inline]
+    fn drop(&mut self) { self.mutex.raw.unlock_read() }
+}
+
+impl<'mutex, T: ?Sized> Drop for NonPoisonWriteGuard<'mutex, T> {
+    #[inline]
+    fn drop(&mut self) { self.mutex.raw.unlock_write() }
+}
+
+impl<T: Default> Default for NonPoisonSharedMutex<T> {
+    #[inline]
+    fn default() -> Self { NonPoisonSharedMutex::new(T::default()) }
+}
+
+impl<T> From<T> for NonPoisonSharedMutex<T> {
+    #[inline]
+    fn from(value: T) -> Self { NonPoisonSharedMutex::new(value) }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for NonPoisonSharedMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut writer = f.debug_struct("NonPoisonSharedMutex");
+
+        match self.try_read() {
+            Some(l) => writer.field("data", &&*l),
+            None => writer.field("data", &"{{ locked }}")
+        }.finish()
+    }
+}
+
+impl<'mutex, T: ?Sized + fmt::Debug> fmt::Debug for NonPoisonReadGuard<'mutex, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NonPoisonReadGuard")
+            .field("data", &&**self)
+            .finish()
+    }
+}
+
+impl<'mutex, T: ?Sized + fmt::Debug> fmt::Debug for NonPoisonWriteGuard<'mutex, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NonPoisonWriteGuard")
+            .field("data", &&**self)
+            .finish()
+    }
+}
@@ -0,0 +1,446 @@
+//! Guards which keep the locked SharedMutex alive through an `Arc`.
+//!
+//! Since they don't borrow the mutex, these guards can be held across
+//! `.await` points or moved into other threads and data structures.
+
+use std::sync::{Arc, Condvar, LockResult, PoisonError};
+use std::ops::{Deref, DerefMut};
+use std::mem::{self, ManuallyDrop};
+use std::{ptr, fmt};
+
+use poison::{self, RawPoisonGuard};
+
+use SharedMutex;
+
+/// A shared read guard on a SharedMutex, which keeps the mutex alive.
+pub struct OwnedSharedMutexReadGuard<T: ?Sized> {
+    mutex: Arc<SharedMutex<T>>
+}
+
+/// An exclusive write guard on a SharedMutex, which keeps the mutex alive.
+pub struct OwnedSharedMutexWriteGuard<T: ?Sized> {
+    // Taken from the poison guard, so the data is never reached through the
+    // `Poison` that `poison` borrows.
+    data: *mut T,
+    // Must be released before the lock, so the poison flag is set by the
+    // time other threads can observe the data.
+    poison: ManuallyDrop<RawPoisonGuard<'static>>,
+    mutex: Arc<SharedMutex<T>>
+}
+
+/// A read guard to a sub-borrow of an `OwnedSharedMutexReadGuard`, which keeps
+/// the mutex alive.
+pub struct OwnedMappedSharedMutexReadGuard<T: ?Sized, U: ?Sized> {
+    data: *const U,
+    mutex: Arc<SharedMutex<T>>
+}
+
+/// A write guard to a sub-borrow of an `OwnedSharedMutexWriteGuard`, which
+/// keeps the mutex alive.
+pub struct OwnedMappedSharedMutexWriteGuard<T: ?Sized, U: ?Sized> {
+    data: *mut U,
+    poison: ManuallyDrop<RawPoisonGuard<'static>>,
+    mutex: Arc<SharedMutex<T>>
+}
+
+unsafe impl<T: ?Sized + Send + Sync> Send for OwnedSharedMutexWriteGuard<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for OwnedSharedMutexWriteGuard<T> {}
+unsafe impl<T: ?Sized + Send + Sync, U: ?Sized + Sync> Send for OwnedMappedSharedMutexReadGuard<T, U> {}
+unsafe impl<T: ?Sized + Send + Sync, U: ?Sized + Sync> Sync for OwnedMappedSharedMutexReadGuard<T, U> {}
+unsafe impl<T: ?Sized + Send + Sync, U: ?Sized + Send> Send for OwnedMappedSharedMutexWriteGuard<T, U> {}
+unsafe impl<T: ?Sized + Send + Sync, U: ?Sized + Sync> Sync for OwnedMappedSharedMutexWriteGuard<T, U> {}
+
+impl<T: ?Sized> SharedMutex<T> {
+    /// Acquire a shared Read lock on the data, returning a guard which keeps
+    /// the mutex alive.
+    #[inline]
+    pub fn read_owned(self: Arc<Self>) -> LockResult<OwnedSharedMutexReadGuard<T>> {
+        self.raw.read();
+        unsafe { OwnedSharedMutexReadGuard::new(self) }
+    }
+
+    /// Acquire a shared Read lock on the data, returning a guard which keeps
+    /// the mutex alive along with another reference to the mutex.
+    ///
+    /// Shorthand for cloning the `Arc` before calling `read_owned`, for code
+    /// which both locks the mutex and shares it further.
+    #[inline]
+    pub fn read_owned_arc(self: Arc<Self>) -> LockResult<(OwnedSharedMutexReadGuard<T>, Arc<Self>)> {
+        let shared = self.clone();
+        poison::map_result(self.read_owned(), |guard| (guard, shared))
+    }
+
+    /// Acquire an exclusive Write lock on the data, returning a guard which
+    /// keeps the mutex alive.
+    #[inline]
+    pub fn write_owned(self: Arc<Self>) -> LockResult<OwnedSharedMutexWriteGuard<T>> {
+        self.raw.write();
+        unsafe { OwnedSharedMutexWriteGuard::new(self) }
+    }
+
+    /// Acquire a shared Read lock on the data through a borrowed `Arc`,
+    /// returning a `'static` guard which keeps the mutex alive.
+    ///
+    /// Shorthand for cloning the `Arc` before calling `read_owned`.
+    #[inline]
+    pub fn read_arc(self: &Arc<Self>) -> LockResult<OwnedSharedMutexReadGuard<T>> {
+        self.clone().read_owned()
+    }
+
+    /// Acquire an exclusive Write lock on the data through a borrowed `Arc`,
+    /// returning a `'static` guard which keeps the mutex alive.
+    ///
+    /// Shorthand for cloning the `Arc` before calling `write_owned`.
+    #[inline]
+    pub fn write_arc(self: &Arc<Self>) -> LockResult<OwnedSharedMutexWriteGuard<T>> {
+        self.clone().write_owned()
+    }
+
+    // Get the data regardless of poisoning.
+    //
+    // The caller must hold a lock on the mutex.
+    #[inline]
+    unsafe fn data_unchecked(&self) -> &T {
+        match (&*self.data.get()).get() {
+            Ok(data) => data,
+            Err(poisoned) => poisoned.into_inner()
+        }
+    }
+}
+
+impl<T: ?Sized> OwnedSharedMutexReadGuard<T> {
+    #[inline]
+    unsafe fn new(mutex: Arc<SharedMutex<T>>) -> LockResult<Self> {
+        let poisoned = (&*mutex.data.get()).get().is_err();
+        let guard = OwnedSharedMutexReadGuard { mutex: mutex };
+
+        if poisoned { Err(PoisonError::new(guard)) } else { Ok(guard) }
+    }
+
+    /// Turn this guard into a guard which can be mapped to a sub-borrow.
+    pub fn into_mapped(self) -> OwnedMappedSharedMutexReadGuard<T, T> {
+        let guard = OwnedMappedSharedMutexReadGuard {
+            data: &*self as *const T,
+            mutex: unsafe { ptr::read(&self.mutex) }
+        };
+
+        // Don't double-unlock.
+        mem::forget(self);
+
+        guard
+    }
+
+    /// Wait on the given condition variable, and resume with a write lock.
+    ///
+    /// The mutex is kept alive while waiting.
+    pub fn wait_for_write(self, cond: &Condvar) -> LockResult<OwnedSharedMutexWriteGuard<T>> {
+        let mutex = self.into_arc();
+        mutex.raw.wait_from_read_to_write(cond);
+        unsafe { OwnedSharedMutexWriteGuard::new(mutex) }
+    }
+
+    /// Wait on the given condition variable, and resume with another read lock.
+    ///
+    /// The mutex is kept alive while waiting.
+    pub fn wait_for_read(self, cond: &Condvar) -> LockResult<Self> {
+        let mutex = self.into_arc();
+        mutex.raw.wait_from_read_to_read(cond);
+        unsafe { OwnedSharedMutexReadGuard::new(mutex) }
+    }
+
+    // Take the reference to the mutex without unlocking it.
+    fn into_arc(self) -> Arc<SharedMutex<T>> {
+        let mutex = unsafe { ptr::read(&self.mutex) };
+
+        // Don't double-unlock.
+        mem::forget(self);
+
+        mutex
+    }
+}
+
+impl<T: ?Sized> OwnedSharedMutexWriteGuard<T> {
+    #[inline]
+    unsafe fn new(mutex: Arc<SharedMutex<T>>) -> LockResult<Self> {
+        poison::map_result((&mut *mutex.data.get()).lock(), |mut guard| {
+            OwnedSharedMutexWriteGuard {
+                data: guard.get_mut() as *mut T,
+                // The Arc keeps the mutex, and so the poison flag, alive.
+                poison: ManuallyDrop::new(mem::transmute::<RawPoisonGuard, RawPoisonGuard<'static>>(guard.into_raw())),
+                mutex: mutex
+            }
+        })
+    }
+
+    /// Turn this guard into a guard which can be mapped to a sub-borrow.
+    pub fn into_mapped(self) -> OwnedMappedSharedMutexWriteGuard<T, T> {
+        let guard = OwnedMappedSharedMutexWriteGuard {
+            data: self.data,
+            poison: unsafe { ptr::read(&self.poison) },
+            mutex: unsafe { ptr::read(&self.mutex) }
+        };
+
+        // Don't double-unlock.
+        mem::forget(self);
+
+        guard
+    }
+
+    /// Release the lock and extract the data, if this guard holds the only
+    /// reference to the mutex.
+    ///
+    /// Only succeeds when there are no other `Arc` clones of the mutex and no
+    /// `Weak` references to it, since those could lock it again. Otherwise
+    /// the guard is returned unchanged. Poisoning is ignored, it was already
+    /// reported when this guard was acquired.
+    pub fn try_into_inner(mut self) -> Result<T, Self>
+    where T: Sized {
+        if Arc::strong_count(&self.mutex) != 1 || Arc::weak_count(&self.mutex) != 0 {
+            return Err(self)
+        }
+
+        // Release the lock as on drop, but keep the mutex.
+        unsafe { ManuallyDrop::drop(&mut self.poison) };
+        let mutex = self.into_arc();
+        mutex.raw.unlock_write();
+
+        match Arc::try_unwrap(mutex) {
+            Ok(mutex) => Ok(mutex.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner())),
+            Err(_) => unreachable!("nothing else can reference the mutex")
+        }
+    }
+
+    /// Wait on the given condition variable, and resume with another write lock.
+    ///
+    /// The mutex is kept alive while waiting.
+    pub fn wait_for_write(self, cond: &Condvar) -> LockResult<Self> {
+        let mutex = self.into_arc();
+        mutex.raw.wait_from_write_to_write(cond);
+        unsafe { OwnedSharedMutexWriteGuard::new(mutex) }
+    }
+
+    /// Wait on the given condition variable, and resume with a read lock.
+    ///
+    /// The mutex is kept alive while waiting.
+    pub fn wait_for_read(self, cond: &Condvar) -> LockResult<OwnedSharedMutexReadGuard<T>> {
+        let mutex = self.into_arc();
+        mutex.raw.wait_from_write_to_read(cond);
+        unsafe { OwnedSharedMutexReadGuard::new(mutex) }
+    }
+
+    // Take the reference to the mutex without unlocking it.
+    fn into_arc(self) -> Arc<SharedMutex<T>> {
+        let mutex = unsafe { ptr::read(&self.mutex) };
+
+        // Don't double-unlock. Not being in a panic, releasing the poison
+        // guard would be a no-op, and `try_into_inner` already did.
+        mem::forget(self);
+
+        mutex
+    }
+}
+
+impl<T: ?Sized, U: ?Sized> OwnedMappedSharedMutexReadGuard<T, U> {
+    /// Transform this guard into a sub-borrow of the original data.
+    #[inline]
+    pub fn map<V: ?Sized, F>(self, action: F) -> OwnedMappedSharedMutexReadGuard<T, V>
+    where F: FnOnce(&U) -> &V {
+        self.option_map(move |u| Some(action(u))).unwrap()
+    }
+
+    /// Conditionally transform this guard into a sub-borrow of the original data.
+    #[inline]
+    pub fn option_map<V: ?Sized, F>(self, action: F) -> Option<OwnedMappedSharedMutexReadGuard<T, V>>
+    where F: FnOnce(&U) -> Option<&V> {
+        self.result_map(move |u| action(u).ok_or(())).ok()
+    }
+
+    /// Conditionally transform this guard into a sub-borrow of the original data.
+    ///
+    /// If `action` returns `None`, returns the original guard.
+    #[inline]
+    pub fn try_map<V: ?Sized, F>(self, action: F) -> Result<OwnedMappedSharedMutexReadGuard<T, V>, Self>
+    where F: FnOnce(&U) -> Option<&V> {
+        self.result_map(move |u| action(u).ok_or(())).map_err(|(guard, ())| guard)
+    }
+
+    /// Conditionally transform this guard into a sub-borrow of the original data.
+    ///
+    /// If the transformation operation is aborted, returns the original guard.
+    #[inline]
+    pub fn result_map<V: ?Sized, E, F>(self, action: F)
+        -> Result<OwnedMappedSharedMutexReadGuard<T, V>, (Self, E)>
+    where F: FnOnce(&U) -> Result<&V, E> {
+        match action(unsafe { &*self.data }) {
+            Ok(new_data) => {
+                let guard = OwnedMappedSharedMutexReadGuard {
+                    data: new_data as *const V,
+                    mutex: unsafe { ptr::read(&self.mutex) }
+                };
+
+                // Don't double-unlock.
+                mem::forget(self);
+
+                Ok(guard)
+            },
+            Err(e) => { Err((self, e)) }
+        }
+    }
+}
+
+impl<T: ?Sized, U: ?Sized> OwnedMappedSharedMutexWriteGuard<T, U> {
+    /// Transform this guard into a sub-borrow of the original data.
+    #[inline]
+    pub fn map<V: ?Sized, F>(self, action: F) -> OwnedMappedSharedMutexWriteGuard<T, V>
+    where F: FnOnce(&mut U) -> &mut V {
+        self.option_map(move |u| Some(action(u))).unwrap()
+    }
+
+    /// Conditionally transform this guard into a sub-borrow of the original data.
+    #[inline]
+    pub fn option_map<V: ?Sized, F>(self, action: F) -> Option<OwnedMappedSharedMutexWriteGuard<T, V>>
+    where F: FnOnce(&mut U) -> Option<&mut V> {
+        self.result_map(move |u| action(u).ok_or(())).ok()
+    }
+
+    /// Conditionally transform this guard into a sub-borrow of the original data.
+    ///
+    /// If `action` returns `None`, returns the original guard.
+    #[inline]
+    pub fn try_map<V: ?Sized, F>(self, action: F) -> Result<OwnedMappedSharedMutexWriteGuard<T, V>, Self>
+    where F: FnOnce(&mut U) -> Option<&mut V> {
+        self.result_map(move |u| action(u).ok_or(())).map_err(|(guard, ())| guard)
+    }
+
+    /// Conditionally transform this guard into a sub-borrow of the original data.
+    ///
+    /// If the transformation operation is aborted, returns the original guard.
+    #[inline]
+    pub fn result_map<V: ?Sized, E, F>(self, action: F)
+        -> Result<OwnedMappedSharedMutexWriteGuard<T, V>, (Self, E)>
+    where F: FnOnce(&mut U) -> Result<&mut V, E> {
+        match action(unsafe { &mut *self.data }) {
+            Ok(new_data) => {
+                let guard = OwnedMappedSharedMutexWriteGuard {
+                    data: new_data as *mut V,
+                    poison: unsafe { ptr::read(&self.poison) },
+                    mutex: unsafe { ptr::read(&self.mutex) }
+                };
+
+                // Don't double-unlock.
+                mem::forget(self);
+
+                Ok(guard)
+            },
+            Err(e) => { Err((self, e)) }
+        }
+    }
+}
+
+/// Takes another read lock for the clone, so each clone can be released
+/// independently.
+///
+/// Cloning succeeds even if the mutex is poisoned: poisoning is reported when
+/// a lock is first acquired, and this guard already holds one.
+impl<T: ?Sized> Clone for OwnedSharedMutexReadGuard<T> {
+    fn clone(&self) -> Self {
+        self.mutex.raw.read_recursive();
+        OwnedSharedMutexReadGuard { mutex: self.mutex.clone() }
+    }
+}
+
+impl<T: ?Sized> Deref for OwnedSharedMutexReadGuard<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T { unsafe { self.mutex.data_unchecked() } }
+}
+
+impl<T: ?Sized> Deref for OwnedSharedMutexWriteGuard<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T { unsafe { &*self.data } }
+}
+
+impl<T: ?Sized> DerefMut for OwnedSharedMutexWriteGuard<T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T { unsafe { &mut *self.data } }
+}
+
+impl<T: ?Sized, U: ?Sized> Deref for OwnedMappedSharedMutexReadGuard<T, U> {
+    type Target = U;
+
+    #[inline]
+    fn deref(&self) -> &U { unsafe { &*self.data } }
+}
+
+impl<T: ?Sized, U: ?Sized> Deref for OwnedMappedSharedMutexWriteGuard<T, U> {
+    type Target = U;
+
+    #[inline]
+    fn deref(&self) -> &U { unsafe { &*self.data } }
+}
+
+impl<T: ?Sized, U: ?Sized> DerefMut for OwnedMappedSharedMutexWriteGuard<T, U> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut U { unsafe { &mut *self.data } }
+}
+
+impl<T: ?Sized> Drop for OwnedSharedMutexReadGuard<T> {
+    #[inline]
+    fn drop(&mut self) { self.mutex.raw.unlock_read() }
+}
+
+impl<T: ?Sized> Drop for OwnedSharedMutexWriteGuard<T> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.poison) };
+        self.mutex.raw.unlock_write()
+    }
+}
+
+impl<T: ?Sized, U: ?Sized> Drop for OwnedMappedSharedMutexReadGuard<T, U> {
+    #[inline]
+    fn drop(&mut self) { self.mutex.raw.unlock_read() }
+}
+
+impl<T: ?Sized, U: ?Sized> Drop for OwnedMappedSharedMutexWriteGuard<T, U> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.poison) };
+        self.mutex.raw.unlock_write()
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for OwnedSharedMutexReadGuard<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OwnedSharedMutexReadGuard")
+            .field("data", &&**self)
+            .finish()
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for OwnedSharedMutexWriteGuard<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OwnedSharedMutexWriteGuard")
+            .field("data", &&**self)
+            .finish()
+    }
+}
+
+impl<T: ?Sized, U: ?Sized + fmt::Debug> fmt::Debug for OwnedMappedSharedMutexReadGuard<T, U> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OwnedMappedSharedMutexReadGuard")
+            .field("data", &&**self)
+            .finish()
+    }
+}
+
+impl<T: ?Sized, U: ?Sized + fmt::Debug> fmt::Debug for OwnedMappedSharedMutexWriteGuard<T, U> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OwnedMappedSharedMutexWriteGuard")
+            .field("data", &&**self)
+            .finish()
+    }
+}
@@ -0,0 +1,81 @@
+//! Padding to keep locks on separate cache lines.
+//!
+//! A SharedMutex is small, so many of them in an array share cache lines, and
+//! threads using different locks may contend on the line holding both.
+//! `CachePadded` aligns a value to its own cache line, at the cost of
+//! rounding its size up to a whole line. Whether that pays off depends on the
+//! processor and the workload; the `false_sharing` benchmark compares both
+//! layouts on multi-core machines. `PaddedSharedMutexVec` is the padded
+//! variant of `SharedMutexVec`.
+//!
+//! ```
+//! use shared_mutex::PaddedSharedMutex;
+//!
+//! let counters: Vec<PaddedSharedMutex<u64>> = (0..4).map(|_| Default::default()).collect();
+//! *counters[1].write().unwrap() += 1;
+//! assert_eq!(*counters[1].read().unwrap(), 1);
+//! ```
+
+use std::ops::{Deref, DerefMut};
+use std::borrow::{Borrow, BorrowMut};
+use std::fmt;
+
+use SharedMutex;
+
+/// A SharedMutex on its own cache line.
+pub type PaddedSharedMutex<T> = CachePadded<SharedMutex<T>>;
+
+/// A value aligned to and padded to the size of a cache line.
+///
+/// The line size is assumed to be 64 bytes, which is right for most current
+/// processors.
+#[repr(align(64))]
+#[derive(Default)]
+pub struct CachePadded<T> {
+    value: T
+}
+
+impl<T> CachePadded<T> {
+    /// Pad the given value.
+    #[inline]
+    pub fn new(value: T) -> Self { CachePadded { value: value } }
+
+    /// Extract the padded value.
+    #[inline]
+    pub fn into_inner(self) -> T { self.value }
+}
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T { &self.value }
+}
+
+impl<T> DerefMut for CachePadded<T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T { &mut self.value }
+}
+
+impl<T> Borrow<T> for CachePadded<T> {
+    #[inline]
+    fn borrow(&self) -> &T { &self.value }
+}
+
+impl<T> BorrowMut<T> for CachePadded<T> {
+    #[inline]
+    fn borrow_mut(&mut self) -> &mut T { &mut self.value }
+}
+
+impl<T> From<T> for CachePadded<T> {
+    #[inline]
+    fn from(value: T) -> Self { CachePadded::new(value) }
+}
+
+impl<T: fmt::Debug> fmt::Debug for CachePadded<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CachePadded")
+            .field("value", &self.value)
+            .finish()
+    }
+}
@@ -1,291 +1,2078 @@
-use std::sync::{Mutex, Condvar, MutexGuard};
+use std::sync::{Arc, Mutex, Condvar, MutexGuard};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use std::{fmt, hint};
+use std::thread::{self, Thread};
+#[cfg(debug_assertions)]
+use std::thread::ThreadId;
+#[cfg(feature = "poison-backtrace")]
+use std::backtrace::Backtrace;
+
+#[cfg(feature = "async")]
+use std::task::Waker;
+
+#[cfg(debug_assertions)]
+use hierarchy;
 
 /// A raw lock providing both shared read locks and exclusive write locks.
 ///
 /// Used as a raw building block for other synchronization primitives. Most
 /// users should just use `SharedMutex<T>`, which takes care of tieing the lock
 /// to some data.
+///
+/// Destroying a mutex which is still locked, for example because a guard was
+/// leaked, is allowed. Tests which should never leave a mutex locked can
+/// check that with `assert_unlocked` from the `test-util` feature.
+///
+/// ## Building on the raw lock
+///
+/// The locking methods are safe to call: unlocking a lock which isn't held
+/// leaves the lock in an unspecified (but not undefined) state, and never
+/// causes memory unsafety by itself. The `unsafe` part is accessing the
+/// protected data, which is only sound between a successful `read` or
+/// `write` and the matching `unlock_read` or `unlock_write`. Guard types
+/// should pair them in their constructor and `Drop`:
+///
+/// ```
+/// use std::cell::UnsafeCell;
+/// use std::ops::Deref;
+/// use shared_mutex::RawSharedMutex;
+///
+/// struct Counter {
+///     lock: RawSharedMutex,
+///     value: UnsafeCell<u64>
+/// }
+///
+/// // The value is only accessed under the lock.
+/// unsafe impl Sync for Counter {}
+///
+/// struct CounterReadGuard {
+///     counter: &'static Counter
+/// }
+///
+/// impl Deref for CounterReadGuard {
+///     type Target = u64;
+///
+///     fn deref(&self) -> &u64 { unsafe { &*self.counter.value.get() } }
+/// }
+///
+/// impl Drop for CounterReadGuard {
+///     fn drop(&mut self) { self.counter.lock.unlock_read() }
+/// }
+///
+/// impl Counter {
+///     fn read(&'static self) -> CounterReadGuard {
+///         self.lock.read();
+///         CounterReadGuard { counter: self }
+///     }
+///
+///     fn increment(&self) {
+///         self.lock.write();
+///         unsafe { *self.value.get() += 1 }
+///         self.lock.unlock_write();
+///     }
+/// }
+///
+/// let counter: &'static Counter = Box::leak(Box::new(Counter {
+///     lock: RawSharedMutex::new(),
+///     value: UnsafeCell::new(0)
+/// }));
+///
+/// counter.increment();
+/// assert_eq!(*counter.read(), 1);
+/// ```
+///
+/// ## Size
+///
+/// The reader count and writer flag are packed into a single word, and all
+/// optional configuration (policy, name, callbacks) lives in a separate
+/// allocation which is only made when it is first used, as does the queue of
+/// threads blocked on the lock. On Linux a
+/// `RawSharedMutex` is 32 bytes in release builds, most of it the internal
+/// `Mutex<usize>`. Debug builds also track the writing thread.
+///
+/// The lock state is deliberately kept behind a `Mutex` rather than in an
+/// atomic: the `wait_from_*` methods hand that mutex to the user's `Condvar`,
+/// which is what lets them release the lock and start waiting without a
+/// window in which notifications are lost. A `compare_exchange` fast path
+/// which skipped the mutex when uncontended would reopen that window: a
+/// writer could take the lock, change the data and notify between a waiter
+/// releasing its lock and starting to wait. An uncontended acquisition or
+/// release costs one lock and unlock of that mutex, and releases only wake
+/// blocked threads if there are any; see the `uncontended` benchmark.
+///
+/// ## Metrics
+///
+/// With the `metrics` feature, lock usage is reported through the `metrics`
+/// facade, labeled with the name of the mutex if it has one:
+///
+/// - `shared_mutex.readers`: a gauge of the number of readers.
+/// - `shared_mutex.write_contended_total`: a counter of write locks which
+///   had to wait for the lock.
 pub struct RawSharedMutex {
     state: Mutex<State>,
     readers: Condvar,
-    both: Condvar,
+    both: Condvar
+}
+
+// Describe a mutex with the given name in a panic message.
+#[cfg(any(test, debug_assertions, feature = "test-util"))]
+fn describe(name: Option<&'static str>) -> String {
+    match name {
+        Some(name) => format!("mutex {:?}", name),
+        None => "mutex".to_string()
+    }
+}
+
+/// The default maximum number of spins between attempts to acquire a
+/// contended lock, see `RawSharedMutex::set_backoff_cap`.
+pub const DEFAULT_BACKOFF_CAP: u32 = 64;
+
+/// The strategy used by a `RawSharedMutex` to arbitrate between readers and
+/// writers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockPolicy {
+    /// A waiting writer blocks incoming readers, so readers cannot starve
+    /// writers. This is the default.
+    Neutral,
+
+    /// Incoming readers are never blocked by a waiting writer.
+    ///
+    /// Writers only acquire the lock once there are no readers at all, so a
+    /// continuous stream of readers can starve writers indefinitely. Only use
+    /// this for read-dominated workloads where read latency matters more than
+    /// writer progress.
+    ReaderPreference,
+
+    /// Like `ReaderPreference`, until a writer has waited for longer than the
+    /// given duration.
+    ///
+    /// From then on that writer blocks incoming readers as with `Neutral`,
+    /// so it acquires the lock as soon as the current readers are done. Once
+    /// it releases the lock, readers are preferred again. A duration too long
+    /// to add to the current time, such as `Duration::MAX`, behaves like
+    /// `ReaderPreference`.
+    Adaptive(Duration),
+
+    /// Readers and writers take turns in phases.
+    ///
+    /// Like `Neutral`, a waiting writer blocks incoming readers. In addition,
+    /// the readers blocked by a writer all get the lock as soon as it is
+    /// released, before any other waiting writer, so a stream of writers
+    /// can't starve readers either. Both kinds of waiters are let in after at
+    /// most one phase of the other kind.
+    PhaseFair,
+}
+
+impl Default for LockPolicy {
+    #[inline]
+    fn default() -> Self { LockPolicy::Neutral }
+}
+
+/// How threads wait for a `RawSharedMutex` when they have to block.
+///
+/// Only affects waiting for the lock itself, waiting on a user-supplied
+/// `Condvar` through the `wait_from_*` methods always uses that `Condvar`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Blocking {
+    /// Wait on condition variables. This is the default.
+    Condvar,
+
+    /// Park the thread, keeping its handle in a queue of waiting threads
+    /// which are unparked when the lock is released.
+    ///
+    /// Whether this is faster than condition variables depends on the
+    /// platform and workload, see the `contention` benchmark. Waiting threads
+    /// may see spurious wakeups from `thread::park` elsewhere in the program,
+    /// which are harmless.
+    Park,
+}
+
+impl Default for Blocking {
+    #[inline]
+    fn default() -> Self { Blocking::Condvar }
+}
+
+// The sets of threads which can be blocked on the lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Waiters {
+    // A writer or upgrading reader waiting for the readers to exit.
+    Readers,
+
+    // Everyone else.
+    Both
 }
 
 impl RawSharedMutex {
     /// Create a new RawSharedMutex
     #[inline]
     pub fn new() -> RawSharedMutex {
+        RawSharedMutex::with_policy(LockPolicy::default())
+    }
+
+    /// Create a new RawSharedMutex using the given policy.
+    #[inline]
+    pub fn with_policy(policy: LockPolicy) -> RawSharedMutex {
+        let mut state = State::new();
+        if policy != LockPolicy::default() { state.extras().policy = policy }
+
         RawSharedMutex {
-            state: Mutex::new(State::new()),
+            state: Mutex::new(state),
             readers: Condvar::new(),
             both: Condvar::new()
         }
     }
 
-    /// Checks if this mutex and the other are the same mutex.
+    /// Get the policy this mutex was created with.
+    #[inline]
+    pub fn policy(&self) -> LockPolicy { self.state.lock().unwrap().policy() }
+
+    /// Set the name used to identify this mutex in diagnostics.
+    #[inline]
+    pub fn set_name(&mut self, name: &'static str) {
+        self.state.get_mut().unwrap().extras().name = Some(name)
+    }
+
+    /// Get the name of this mutex, if it has one.
+    #[inline]
+    pub fn name(&self) -> Option<&'static str> { self.state.lock().unwrap().name() }
+
+    /// Set the level of this mutex in the lock hierarchy.
     ///
-    /// If `is` returns true, the two references point to the same
-    /// mutex, and they may be used interchangeably.
+    /// In debug builds, blocking on a leveled lock panics if the current
+    /// thread already holds a leveled lock with a higher level, since two
+    /// threads taking the same locks in different orders can deadlock. A lock
+    /// released on another thread than the one which acquired it stops
+    /// counting for the acquiring thread, and so does a lock whose guard was
+    /// leaked with `leak`. Locks acquired through `poll_read` and `poll_write`
+    /// aren't tracked.
+    ///
+    /// In release builds the level is only recorded.
     #[inline]
-    pub fn is(&self, other: &Self) -> bool {
-        self as *const Self == other as *const Self
+    pub fn set_level(&mut self, level: u32) {
+        self.state.get_mut().unwrap().extras().level = Some(level)
     }
 
-    /// Acquire a shared read lock.
+    /// Get the level of this mutex in the lock hierarchy, if it has one.
+    #[inline]
+    pub fn level(&self) -> Option<u32> { self.state.lock().unwrap().level() }
+
+    /// Set the maximum number of spins between attempts to acquire a
+    /// contended lock before blocking.
     ///
-    /// Blocks until a read lock can be acquired. The lock can be released
-    /// by calling `unlock_read`.
+    /// When `read` or `write` can't acquire the lock immediately, they retry
+    /// after spinning for 1, 2, 4, ... iterations up to `cap`, and only then
+    /// block on the lock. A cap of 0 blocks straight away. The default is
+    /// `DEFAULT_BACKOFF_CAP`.
     #[inline]
-    pub fn read(&self) {
-        self.read_from(self.state.lock().unwrap())
+    pub fn set_backoff_cap(&mut self, cap: u32) {
+        self.state.get_mut().unwrap().extras().backoff_cap = cap
     }
 
-    /// Get a read lock using the given state lock.
+    /// Get the maximum number of spins between attempts to acquire a
+    /// contended lock, see `set_backoff_cap`.
+    #[inline]
+    pub fn backoff_cap(&self) -> u32 { self.state.lock().unwrap().backoff_cap() }
+
+    /// Set how threads wait for this mutex when they have to block.
+    #[inline]
+    pub fn set_blocking(&mut self, blocking: Blocking) {
+        self.state.get_mut().unwrap().extras().blocking = blocking
+    }
+
+    /// Get how threads wait for this mutex when they have to block, see
+    /// `set_blocking`.
+    #[inline]
+    pub fn blocking(&self) -> Blocking { self.state.lock().unwrap().blocking() }
+
+    /// Run `hook` whenever this mutex is released after being held for longer
+    /// than `threshold`.
     ///
-    /// WARNING: The lock MUST be from self.state!!
-    fn read_from(&self, mut state_lock: MutexGuard<State>) {
-        // Wait for any writers to finish and for there to be space
-        // for another reader. (There are a max of 2^63 readers at any time)
-        while state_lock.is_writer_active() || state_lock.has_max_readers() {
-            state_lock = self.both.wait(state_lock).unwrap();
-        }
+    /// The hook receives the name of the mutex and how long it was held. For
+    /// write locks this is the time the writer held the lock; for read locks
+    /// it is the time the lock was continuously held by one or more readers.
+    ///
+    /// Like write unlock callbacks, the hook runs on the releasing thread
+    /// outside of the internal critical section. Releasing a lock as part of
+    /// waiting on a condition variable is not checked.
+    ///
+    /// Only available with the `watchdog` feature.
+    #[cfg(feature = "watchdog")]
+    pub fn warn_if_held_longer_than<F>(&self, threshold: Duration, hook: F)
+    where F: Fn(Option<&'static str>, Duration) + Send + Sync + 'static {
+        self.state.lock().unwrap().extras().watchdog.hook = Some((threshold, Arc::new(hook)));
+    }
 
-        // At this point there should be no writers and space
-        // for at least one more reader.
-        //
-        // Add ourselves as a reader.
-        state_lock.add_reader();
+    /// Register a callback to run whenever a write lock on this mutex is released.
+    ///
+    /// Callbacks run on the thread releasing the lock, right after the lock
+    /// is released and outside of the internal critical section, so they are
+    /// free to lock this mutex again. Another thread may already have acquired
+    /// the lock by the time a callback runs.
+    ///
+    /// Callbacks are run by `unlock_write` only; releasing a write lock as part
+    /// of waiting on a condition variable does not run them.
+    pub fn on_write_unlock<F>(&self, callback: F)
+    where F: Fn() + Send + Sync + 'static {
+        self.state.lock().unwrap().extras().write_unlock_callbacks.push(Arc::new(callback));
     }
 
-    /// Attempt to acquire a shared read lock without blocking.
+    /// Check whether the lock is currently held by any reader or writer.
     ///
-    /// Returns true if we succeeded and false if acquiring a read lock would
-    /// require blocking.
-    pub fn try_read(&self) -> bool {
-        let mut state_lock = self.state.lock().unwrap();
+    /// The result may be out of date as soon as it is returned, so it should
+    /// only be used for diagnostics and heuristics.
+    #[inline]
+    pub fn is_locked(&self) -> bool {
+        let state_lock = self.state.lock().unwrap();
+        state_lock.is_writer_active() || state_lock.readers() != 0
+    }
 
-        // If there isn't a waiting writer and there is space for another reader
-        // we can just take another read lock.
-        if !state_lock.is_writer_active() && !state_lock.has_max_readers() {
-            state_lock.add_reader();
+    /// Check whether the lock is currently held by a writer.
+    ///
+    /// Like `is_locked`, the result may be out of date immediately.
+    #[inline]
+    pub fn is_locked_exclusive(&self) -> bool {
+        let state_lock = self.state.lock().unwrap();
+        state_lock.is_writer_active() && state_lock.readers() == 0
+    }
 
-            // Success!
-            true
-        } else {
-            false // We would have to block
-        }
+    /// Get the number of readers currently holding the lock.
+    ///
+    /// The count is read under the internal state lock, so it is consistent
+    /// with `is_locked_exclusive` at that moment, but like `is_locked` it may
+    /// be out of date immediately. Readers still draining while a writer
+    /// waits for them are counted.
+    #[inline]
+    pub fn reader_count(&self) -> usize {
+        self.state.lock().unwrap().readers()
     }
 
-    /// Acquire an exclusive write lock.
+    /// Check whether a writer is currently blocked waiting for the lock.
     ///
-    /// Blocks until the write lock can be acquired. The lock can be released
-    /// by calling `unlock_write`.
+    /// Readers can use this as a hint to finish up quickly. Like `is_locked`,
+    /// the result may be out of date immediately. Pending `poll_write` calls
+    /// are not counted as waiting writers.
     #[inline]
-    pub fn write(&self) {
-        self.write_from(self.state.lock().unwrap())
+    pub fn has_waiting_writer(&self) -> bool {
+        self.state.lock().unwrap().waiting_writers() != 0
     }
 
-    /// Get a write lock using the given state lock.
+    /// Panic if the lock is currently held by any reader or writer.
     ///
-    /// WARNING: The lock MUST be from self.state!!
-    fn write_from(&self, mut state_lock: MutexGuard<State>) {
-        // First wait for any other writers to unlock.
-        while state_lock.is_writer_active() {
-            state_lock = self.both.wait(state_lock).unwrap();
-        }
+    /// Meant for tests, for example to catch leaked guards after a clean
+    /// shutdown. Only available with the `test-util` feature.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn assert_unlocked(&self) {
+        let state_lock = self.state.lock().unwrap();
 
-        // At this point there must be no writers, but there may be readers.
-        //
-        // We set the writer-active flag so that readers which try to
-        // acquire the lock from here on out will be queued after us, to
-        // prevent starvation.
-        state_lock.set_writer_active();
+        if state_lock.is_writer_active() || state_lock.readers() != 0 {
+            let message = format!("{} is locked: {:?}", describe(state_lock.name()), *state_lock);
 
-        // Now wait for all readers to exit.
-        //
-        // This will happen eventually since new readers are waiting on
-        // us because we set the writer-active flag.
-        while state_lock.readers() != 0 {
-            state_lock = self.readers.wait(state_lock).unwrap();
+            // Don't poison the state lock.
+            drop(state_lock);
+            panic!("{}", message)
         }
-
-        // At this point there should be one writer (us) and no readers.
-        debug_assert!(state_lock.is_writer_active() && state_lock.readers() == 0,
-                      "State not empty on write lock! State = {:?}", *state_lock);
     }
 
-    /// Attempt to acquire an exclusive write lock without blocking.
+    /// Panic if the lock is currently held by any reader.
     ///
-    /// Returns true if we succeeded and false if acquiring the write lock would
-    /// require blocking.
-    pub fn try_write(&self) -> bool {
-        let mut state_lock = self.state.lock().unwrap();
+    /// See `assert_unlocked` for more information.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn assert_no_readers(&self) {
+        let state_lock = self.state.lock().unwrap();
+        let readers = state_lock.readers();
 
-        // If there are no readers or writers we can just take the lock.
-        if !state_lock.is_writer_active() && state_lock.readers() == 0 {
-            state_lock.set_writer_active();
+        if readers != 0 {
+            let message = format!("{} has {} reader(s)", describe(state_lock.name()), readers);
 
-            // Success!
-            true
-        } else {
-            false // We would have to block
+            // Don't poison the state lock.
+            drop(state_lock);
+            panic!("{}", message)
         }
     }
 
-    /// Unlock a previously acquired read lock.
+    /// Get the backtrace captured the last time a write lock was released by a
+    /// panicking thread, which is what poisons a `SharedMutex`.
     ///
-    /// Behavior is unspecified (but not undefined) if `unlock_read` is called
-    /// without a previous accompanying `read`.
-    #[inline]
-    pub fn unlock_read(&self) {
-        let _ = self.unlock_read_to();
+    /// The backtrace is captured while unwinding, so it shows where the write
+    /// guard was dropped rather than where the panic started, which is enough
+    /// to tell which writer panicked. Only available with the
+    /// `poison-backtrace` feature.
+    #[cfg(feature = "poison-backtrace")]
+    pub fn poison_backtrace(&self) -> Option<Arc<Backtrace>> {
+        let state_lock = self.state.lock().unwrap();
+        state_lock.extras.as_ref().and_then(|extras| extras.poison_backtrace.clone())
     }
 
-    fn unlock_read_to(&self) -> MutexGuard<State> {
-        let mut state_lock = self.state.lock().unwrap();
+    // Check whether the data protected by this lock is poisoned, without
+    // acquiring the lock, using `poisoned` to read the poison flag.
+    //
+    // `poisoned` runs under the state lock and only while no writer holds the
+    // lock, so the flag can't change under it, and the state lock orders it
+    // after the writer which last set it.
+    pub(crate) fn check_poisoned<F>(&self, poisoned: F) -> bool
+    where F: FnOnce() -> bool {
+        let state_lock = self.state.lock().unwrap();
 
-        // First decrement the reader count.
-        state_lock.remove_reader();
+        // Only a writer released while panicking can poison the data.
+        if !state_lock.panicked() { return false }
 
-        // Now check if there is a writer waiting and
-        // we are the last reader.
-        if state_lock.is_writer_active() {
-            if state_lock.readers() == 0 {
-                // Wake up the waiting writer.
-                self.readers.notify_one();
-            }
-        // Check if we where at the max number of readers.
-        } else if state_lock.near_max_readers() {
-            // Wake up a reader to replace us.
-            self.both.notify_one()
-        }
+        // The flag can't be read while a writer holds the lock, but one was
+        // already released while panicking, which almost always poisons it.
+        if state_lock.is_writer_active() && state_lock.readers() == 0 { return true }
 
-        // Return the lock for potential further use.
-        state_lock
+        poisoned()
     }
 
-    /// Unlock a previously acquired write lock.
-    ///
-    /// Behavior is unspecified (but not undefined) if `unlock_write` is called
-    /// without a previous accompanying `write`.
-    #[inline]
-    pub fn unlock_write(&self) {
-        let _ = self.unlock_write_to();
+    // Note that a write lock was released while panicking, for a new mutex
+    // which starts out poisoned.
+    pub(crate) fn set_panicked(&mut self) {
+        self.state.get_mut().unwrap().extras().panicked = true
     }
 
-    #[inline]
-    fn unlock_write_to(&self) -> MutexGuard<State> {
+    // Forget that a write lock was released while panicking, once the data
+    // was healed.
+    pub(crate) fn clear_panicked(&self) {
         let mut state_lock = self.state.lock().unwrap();
-
-        // Writer locks are exclusive so we know we can just
-        // set the state to empty.
-        *state_lock = State::new();
-
-        // Wake any pending readers or writers.
-        self.both.notify_all();
-
-        state_lock
+        if let Some(ref mut extras) = state_lock.extras { extras.panicked = false }
     }
 
-    /// Wait on the given condition variable, resuming with a write lock.
-    ///
-    /// Behavior is unspecified if there was no previous accompanying `read`.
-    #[inline]
-    pub fn wait_from_read_to_write(&self, cond: &Condvar) {
-        let state_lock = self.unlock_read_to();
-        let state_lock = cond.wait(state_lock).unwrap();
-        self.write_from(state_lock);
+    // Stop counting a lock in the lock hierarchy whose guard was leaked, since
+    // it will never be released.
+    pub(crate) fn forget_level(&self) {
+        let state_lock = self.state.lock().unwrap();
+        self.exit_level(&state_lock);
     }
 
-    /// Wait on the given condition variable, resuming with another read lock.
+    /// Checks if this mutex and the other are the same mutex.
     ///
-    /// Behavior is unspecified if there was no previous accompanying `read`.
+    /// If `is` returns true, the two references point to the same
+    /// mutex, and they may be used interchangeably.
+    ///
+    /// Guards built on a `RawSharedMutex` can use this to check that they
+    /// belong to a given mutex, like `MappedSharedMutexReadGuard::recover`.
     #[inline]
-    pub fn wait_from_read_to_read(&self, cond: &Condvar) {
-        let state_lock = self.unlock_read_to();
-        let state_lock = cond.wait(state_lock).unwrap();
-        self.read_from(state_lock);
+    pub fn is(&self, other: &Self) -> bool {
+        self as *const Self == other as *const Self
     }
 
-    /// Wait on the given condition variable, resuming with a read lock.
+    /// Acquire a shared read lock.
     ///
-    /// Behavior is unspecified if there was no previous accompanying `write`.
+    /// Blocks until a read lock can be acquired. The lock can be released
+    /// by calling `unlock_read`.
+    ///
+    /// The number of readers can't overflow: once 2^32 - 1 readers hold the
+    /// lock (2^16 - 1 on 32 bit platforms), further readers wait for one of
+    /// them to exit, and `try_read` fails. A thread which holds every one of
+    /// those read locks itself would wait forever, so code which can hold
+    /// that many, for example through recursive reads or cloned guards,
+    /// should use `try_read_checked` instead.
     #[inline]
-    pub fn wait_from_write_to_read(&self, cond: &Condvar) {
-        let state_lock = self.unlock_write_to();
-        let state_lock = cond.wait(state_lock).unwrap();
-        self.read_from(state_lock);
+    pub fn read(&self) {
+        let state_lock = self.back_off(State::can_read);
+        let state_lock = self.check_level(state_lock);
+        self.read_from(state_lock)
     }
 
-    /// Wait on the given condition variable, resuming with another write lock.
+    /// Get a read lock using the given state lock.
     ///
-    /// Behavior is unspecified if there was no previous accompanying `write`.
+    /// WARNING: The lock MUST be from self.state!!
     #[inline]
-    pub fn wait_from_write_to_write(&self, cond: &Condvar) {
-        let state_lock = self.unlock_write_to();
-        let state_lock = cond.wait(state_lock).unwrap();
-        self.write_from(state_lock);
+    fn read_from(&self, state_lock: MutexGuard<State>) {
+        self.read_until(state_lock, State::can_read, None);
     }
-}
 
-/// Internal State of the SharedMutex.
-///
-/// The high bit indicates if a writer is active.
-///
-/// The lower bits are used to count the number of readers.
-#[derive(Debug)]
-struct State(usize);
+    // Get a read lock using the given state lock, giving up once `deadline`
+    // passes if there is one, or once `ready` returns true while the lock
+    // still can't be read. Returns true if we got the lock.
+    fn read_until<'a>(&'a self, mut state_lock: MutexGuard<'a, State>, ready: fn(&State) -> bool,
+                      deadline: Option<Instant>) -> bool {
+        let mut handed_over = false;
 
-#[cfg(target_pointer_width = "64")]
-const USIZE_BITS: u8 = 64;
+        // Wait for any writers to finish and for there to be space
+        // for another reader. (There are a max of 2^32 - 1 readers at any
+        // time on 64 bit platforms)
+        if !state_lock.can_read() {
+            let phase = state_lock.enter_phase();
+            let (lock, granted) = self.wait_queued(state_lock, Want::Read, ready, deadline);
+            state_lock = lock;
+            handed_over = granted;
 
-#[cfg(target_pointer_width = "32")]
-const USIZE_BITS: u8 = 32;
+            // Let writers in once all readers admitted ahead of them are.
+            if phase.map_or(false, |phase| state_lock.leave_phase(phase)) {
+                state_lock.wake_async();
+                self.notify_all(&mut state_lock, Waiters::Both);
+            }
 
-// Only the high bit is set.
-//
-// We can mask the State with this to see if the
-// high bit is set, which would indicate a writer
-// is active.
-const WRITER_ACTIVE: usize = 1 << USIZE_BITS - 1;
+            // We timed out or gave up without taking up any space.
+            if !handed_over && !state_lock.can_read() { return false }
+        }
 
-// All the low bits are set, the high bit is not set.
-//
-// We can mask the State with this to see how many
-// readers there are.
-//
-// Also the maximum number of readers.
-const READERS_MASK: usize = !WRITER_ACTIVE;
+        // At this point there should be no writers and space
+        // for at least one more reader.
+        //
+        // Add ourselves as a reader, unless a fair unlock already did.
+        if !handed_over { state_lock.add_reader() }
+        state_lock.start_hold();
+        self.enter_level(&state_lock);
+        true
+    }
 
-impl State {
-    #[inline]
-    fn new() -> Self { State(0) }
+    // Wait on `both` until `ready` returns true or `deadline` passes, queued
+    // as a thread wanting `want` in case of a fair unlock.
+    //
+    // Returns the state lock, and whether the lock was handed to us by a fair
+    // unlock, in which case it was already taken on our behalf. Otherwise
+    // `ready` returns false if we timed out.
+    fn wait_queued<'a>(&'a self, mut state_lock: MutexGuard<'a, State>, want: Want,
+                       ready: fn(&State) -> bool,
+                       deadline: Option<Instant>) -> (MutexGuard<'a, State>, bool) {
+        let ticket = state_lock.enqueue(want);
 
-    #[inline]
-    fn is_writer_active(&self) -> bool { self.0 & WRITER_ACTIVE != 0 }
+        loop {
+            let (lock, timed_out) = self.wait_until(Waiters::Both, state_lock, deadline);
+            state_lock = lock;
 
-    #[inline]
-    fn set_writer_active(&mut self) { self.0 |= WRITER_ACTIVE }
+            if state_lock.take_grant(ticket) {
+                return (state_lock, true);
+            }
 
-    #[inline]
-    fn readers(&self) -> usize { self.0 & READERS_MASK }
+            if timed_out || ready(&state_lock) {
+                state_lock.dequeue(ticket);
+                return (state_lock, false);
+            }
+        }
+    }
 
+    // Block as one of `waiters` until notified.
+    //
+    // Like any wait this may return spuriously.
     #[inline]
-    fn has_max_readers(&self) -> bool { self.readers() == READERS_MASK }
+    fn wait<'a>(&'a self, waiters: Waiters, state_lock: MutexGuard<'a, State>) -> MutexGuard<'a, State> {
+        self.wait_until(waiters, state_lock, None).0
+    }
 
-    #[inline]
-    fn near_max_readers(&self) -> bool { self.readers() == READERS_MASK - 1 }
+    // Block as one of `waiters` until notified, at most until `deadline` if
+    // there is one.
+    //
+    // Returns the state lock, and true without waiting at all if the deadline
+    // has already passed. Like any wait this may return spuriously.
+    fn wait_until<'a>(&'a self, waiters: Waiters, mut state_lock: MutexGuard<'a, State>,
+                      deadline: Option<Instant>) -> (MutexGuard<'a, State>, bool) {
+        let timeout = match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline { return (state_lock, true) }
+                Some(deadline - now)
+            },
+            None => None
+        };
 
-    #[inline]
-    fn add_reader(&mut self) { self.0 += 1 }
+        match state_lock.blocking() {
+            Blocking::Condvar => {
+                let cond = match waiters {
+                    Waiters::Readers => &self.readers,
+                    Waiters::Both => &self.both
+                };
 
-    #[inline]
-    fn remove_reader(&mut self) { self.0 -= 1 }
+                *state_lock.blocked(waiters) += 1;
+
+                let mut state_lock = match timeout {
+                    Some(timeout) => cond.wait_timeout(state_lock, timeout).unwrap().0,
+                    None => cond.wait(state_lock).unwrap()
+                };
+
+                *state_lock.blocked(waiters) -= 1;
+                (state_lock, false)
+            },
+            Blocking::Park => {
+                // Registering under the state lock means a notification
+                // can't be missed: an unpark before we park is remembered.
+                let current = thread::current();
+                state_lock.parked(waiters).push_back(current.clone());
+                drop(state_lock);
+
+                match timeout {
+                    Some(timeout) => thread::park_timeout(timeout),
+                    None => thread::park()
+                }
+
+                // Forget our handle, unless it was used to unpark us.
+                let mut state_lock = self.state.lock().unwrap();
+                let id = current.id();
+                state_lock.parked(waiters).retain(|thread| thread.id() != id);
+
+                (state_lock, false)
+            }
+        }
+    }
+
+    // Wake one thread blocked as one of `waiters`, if there is any.
+    #[inline]
+    fn notify_one(&self, state: &mut State, waiters: Waiters) {
+        match state.blocking() {
+            Blocking::Condvar => match waiters {
+                _ if !state.has_blocked(waiters) => {},
+                Waiters::Readers => self.readers.notify_one(),
+                Waiters::Both => self.both.notify_one()
+            },
+            Blocking::Park => {
+                if let Some(thread) = state.parked(waiters).pop_front() { thread.unpark() }
+            }
+        }
+    }
+
+    // Wake all threads blocked as one of `waiters`.
+    #[inline]
+    fn notify_all(&self, state: &mut State, waiters: Waiters) {
+        match state.blocking() {
+            Blocking::Condvar => match waiters {
+                _ if !state.has_blocked(waiters) => {},
+                Waiters::Readers => self.readers.notify_all(),
+                Waiters::Both => self.both.notify_all()
+            },
+            Blocking::Park => {
+                for thread in state.parked(waiters).drain(..) { thread.unpark() }
+            }
+        }
+    }
+
+    /// Acquire an upgradable read lock.
+    ///
+    /// An upgradable read lock shares the lock with plain readers, but
+    /// excludes writers and other upgradable readers, so that it can later be
+    /// turned into a write lock with `upgrade` without letting any other
+    /// writer in between.
+    ///
+    /// Blocks until the lock can be acquired. The lock can be released by
+    /// calling `unlock_upgradable_read`.
+    pub fn upgradable_read(&self) {
+        let mut state_lock = self.check_level(self.state.lock().unwrap());
+
+        if state_lock.can_upgradable_read() {
+            state_lock.set_upgradable();
+            state_lock.add_reader();
+        } else {
+            let (lock, granted) = self.wait_queued(state_lock, Want::Upgradable,
+                                                   State::can_upgradable_read, None);
+            state_lock = lock;
+
+            if !granted {
+                state_lock.set_upgradable();
+                state_lock.add_reader();
+            }
+        }
+
+        state_lock.start_hold();
+        self.enter_level(&state_lock);
+    }
+
+    /// Upgrade a previously acquired upgradable read lock to a write lock.
+    ///
+    /// Blocks new readers and waits for the current ones to release the lock.
+    /// The write lock can be released by calling `unlock_write`.
+    ///
+    /// Behavior is unspecified (but not undefined) if `upgrade` is called
+    /// without a previous accompanying `upgradable_read`.
+    pub fn upgrade(&self) {
+        let mut state_lock = self.state.lock().unwrap();
+
+        // Writers don't set the writer-active flag while there is an
+        // upgradable reader, so it is ours to set.
+        state_lock.set_writer_active();
+
+        // Wait for all other readers to exit.
+        while state_lock.readers() != 1 {
+            state_lock = self.wait(Waiters::Readers, state_lock);
+        }
+
+        // Trade our read lock for the write lock.
+        state_lock.remove_reader();
+        state_lock.clear_upgradable();
+    }
+
+    /// Attempt to upgrade a previously acquired upgradable read lock to a
+    /// write lock without blocking.
+    ///
+    /// Returns true if the lock was upgraded, in which case it can be released
+    /// by calling `unlock_write`, and false if other readers still hold the
+    /// lock, in which case the upgradable read lock is still held.
+    ///
+    /// Behavior is unspecified (but not undefined) if `try_upgrade` is called
+    /// without a previous accompanying `upgradable_read`.
+    pub fn try_upgrade(&self) -> bool {
+        let mut state_lock = self.state.lock().unwrap();
+
+        if state_lock.readers() != 1 { return false }
+
+        // See `upgrade`.
+        state_lock.set_writer_active();
+        state_lock.remove_reader();
+        state_lock.clear_upgradable();
+        true
+    }
+
+    /// Attempt to upgrade a previously acquired upgradable read lock to a
+    /// write lock, waiting at most `timeout` for the other readers to exit.
+    ///
+    /// Returns true if the lock was upgraded, in which case it can be released
+    /// by calling `unlock_write`. On timeout the upgradable read lock is still
+    /// held, and readers blocked during the attempt are let in again.
+    ///
+    /// Behavior is unspecified (but not undefined) if `try_upgrade_for` is
+    /// called without a previous accompanying `upgradable_read`.
+    pub fn try_upgrade_for(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now().checked_add(timeout);
+        let mut state_lock = self.state.lock().unwrap();
+
+        // See `upgrade`.
+        state_lock.set_writer_active();
+
+        while state_lock.readers() != 1 {
+            let (lock, timed_out) = self.wait_until(Waiters::Readers, state_lock, deadline);
+            state_lock = lock;
+
+            if timed_out {
+                // Back off, keeping our upgradable read lock.
+                state_lock.clear_writer_active();
+                state_lock.wake_async();
+                self.notify_all(&mut state_lock, Waiters::Both);
+                return false;
+            }
+        }
+
+        state_lock.remove_reader();
+        state_lock.clear_upgradable();
+        true
+    }
+
+    /// Trade a previously acquired read lock for a write lock.
+    ///
+    /// If this is the only reader and no writer is waiting for it to leave,
+    /// the lock is switched to a write lock directly. Otherwise the read lock
+    /// is released and a write lock acquired as with `write`, so other
+    /// writers may get in first, but readers upgrading at the same time
+    /// can't deadlock. The write lock can be released by calling
+    /// `unlock_write`.
+    ///
+    /// Behavior is unspecified (but not undefined) if `upgrade_read` is called
+    /// without a previous accompanying `read`.
+    pub fn upgrade_read(&self) {
+        let mut state_lock = self.state.lock().unwrap();
+
+        if state_lock.readers() == 1 && !state_lock.is_writer_active()
+            && !state_lock.has_admitted_readers() {
+            state_lock.remove_reader();
+            state_lock.set_writer_active();
+            return;
+        }
+
+        let state_lock = self.release_read(state_lock);
+        self.write_from(state_lock);
+    }
+
+    /// Unlock a previously acquired upgradable read lock.
+    ///
+    /// Behavior is unspecified (but not undefined) if `unlock_upgradable_read`
+    /// is called without a previous accompanying `upgradable_read`.
+    pub fn unlock_upgradable_read(&self) {
+        let mut state_lock = self.state.lock().unwrap();
+        state_lock.clear_upgradable();
+
+        // Wake any writers or upgradable readers waiting for us.
+        self.notify_all(&mut state_lock, Waiters::Both);
+
+        let state_lock = self.release_read(state_lock);
+        self.check_hold(state_lock);
+    }
+
+    /// Acquire another shared read lock while already holding one.
+    ///
+    /// Unlike `read`, this does not queue behind writers waiting for the lock,
+    /// which would deadlock since they are waiting for the read lock already
+    /// held. Each call must be paired with a call to `unlock_read`.
+    ///
+    /// Behavior is unspecified (but not undefined) if `read_recursive` is
+    /// called without holding a read lock.
+    pub fn read_recursive(&self) {
+        let mut state_lock = self.state.lock().unwrap();
+
+        // We can only have to wait for space for another reader.
+        while state_lock.has_max_readers() {
+            state_lock = self.wait(Waiters::Both, state_lock);
+        }
+
+        state_lock.add_reader();
+        self.enter_level(&state_lock);
+    }
+
+    /// Attempt to acquire a shared read lock without blocking.
+    ///
+    /// Returns true if we succeeded and false if acquiring a read lock would
+    /// require blocking.
+    ///
+    /// Never fails spuriously: the state is inspected while holding the
+    /// internal mutex rather than with a weak compare-and-swap, so false
+    /// always means the lock was genuinely contended.
+    pub fn try_read(&self) -> bool {
+        let mut state_lock = self.state.lock().unwrap();
+
+        // If there isn't a waiting writer and there is space for another reader
+        // we can just take another read lock.
+        if state_lock.can_read() {
+            state_lock.add_reader();
+            state_lock.start_hold();
+            self.enter_level(&state_lock);
+
+            // Success!
+            true
+        } else {
+            false // We would have to block
+        }
+    }
+
+    /// Acquire a shared read lock, unless the maximum number of readers
+    /// already hold it.
+    ///
+    /// Blocks like `read` while a writer holds or is waiting for the lock,
+    /// but returns false instead of waiting for one of the readers to exit
+    /// once there are 2^32 - 1 of them (2^16 - 1 on 32 bit platforms), even
+    /// if that only happens while blocked. See `read`.
+    pub fn try_read_checked(&self) -> bool {
+        let state_lock = self.back_off(State::can_read);
+        let state_lock = self.check_level(state_lock);
+
+        if state_lock.has_max_readers() { return false }
+        self.read_until(state_lock, State::can_read_or_full, None)
+    }
+
+    /// Acquire an exclusive write lock.
+    ///
+    /// Blocks until the write lock can be acquired. The lock can be released
+    /// by calling `unlock_write`.
+    ///
+    /// In debug builds, panics instead of deadlocking if the current thread
+    /// already holds the write lock. A write lock counts as held by the
+    /// thread which acquired it, even if its guard was sent elsewhere.
+    #[inline]
+    pub fn write(&self) {
+        let state_lock = self.back_off(State::can_write);
+        let state_lock = self.check_recursive_write(state_lock);
+        let state_lock = self.check_level(state_lock);
+        self.write_from(state_lock)
+    }
+
+    // Panic if the current thread already holds the write lock, since
+    // blocking would never return. Only checked in debug builds.
+    #[inline]
+    fn check_recursive_write<'a>(&self, state_lock: MutexGuard<'a, State>) -> MutexGuard<'a, State> {
+        #[cfg(debug_assertions)]
+        {
+            let current = thread::current();
+
+            if state_lock.writer == Some(current.id()) {
+                let name = state_lock.name();
+
+                // Release the state lock first so it isn't poisoned.
+                drop(state_lock);
+
+                let thread = current.name().map(String::from)
+                    .unwrap_or_else(|| format!("{:?}", current.id()));
+                match name {
+                    Some(name) => panic!("recursive write lock of {} on thread {}", name, thread),
+                    None => panic!("recursive write lock on thread {}", thread)
+                }
+            }
+        }
+
+        state_lock
+    }
+
+    // Panic if the current thread holds a lock with a higher level than this
+    // one, see `set_level`. Only checked in debug builds.
+    #[inline]
+    fn check_level<'a>(&self, state_lock: MutexGuard<'a, State>) -> MutexGuard<'a, State> {
+        #[cfg(debug_assertions)]
+        {
+            if let Some(level) = state_lock.level() {
+                if let Some((held, held_name)) = hierarchy::violation(level) {
+                    let name = state_lock.name();
+
+                    // Release the state lock first so it isn't poisoned.
+                    drop(state_lock);
+
+                    panic!("lock order violation: acquiring {} (level {}) while holding {} (level {})",
+                           describe(name), level, describe(held_name), held);
+                }
+            }
+        }
+
+        state_lock
+    }
+
+    // Note that the current thread acquired this lock, for `check_level`.
+    #[inline]
+    fn enter_level(&self, _state: &State) {
+        #[cfg(debug_assertions)]
+        {
+            if let Some(level) = _state.level() {
+                hierarchy::push(self as *const Self as usize, level, _state.name())
+            }
+        }
+    }
+
+    // Note that the current thread released this lock, for `check_level`.
+    #[inline]
+    fn exit_level(&self, _state: &State) {
+        #[cfg(debug_assertions)]
+        {
+            if _state.level().is_some() { hierarchy::pop(self as *const Self as usize) }
+        }
+    }
+
+    // Lock the state, spinning with exponential backoff while `ready`
+    // returns false and the backoff cap isn't reached.
+    //
+    // Returns the state lock, after which the caller blocks as usual if the
+    // state still isn't ready.
+    #[inline]
+    fn back_off(&self, ready: fn(&State) -> bool) -> MutexGuard<State> {
+        let mut state_lock = self.state.lock().unwrap();
+        let cap = state_lock.backoff_cap() as u64;
+        let mut spins = 1;
+
+        // Only contended acquisitions pay for backing off.
+        while !ready(&state_lock) && spins <= cap {
+            drop(state_lock);
+            for _ in 0..spins { hint::spin_loop() }
+            spins *= 2;
+
+            state_lock = self.state.lock().unwrap();
+        }
+
+        state_lock
+    }
+
+    /// Get a write lock using the given state lock.
+    ///
+    /// WARNING: The lock MUST be from self.state!!
+    #[inline]
+    fn write_from(&self, state_lock: MutexGuard<State>) {
+        self.write_until(state_lock, None);
+    }
+
+    // Get a write lock using the given state lock, giving up once `deadline`
+    // passes if there is one. Returns true if we got the lock.
+    fn write_until<'a>(&'a self, mut state_lock: MutexGuard<'a, State>, deadline: Option<Instant>) -> bool {
+        if state_lock.can_write() {
+            state_lock.set_writer_active();
+        } else {
+            // Wait for space to count ourselves as a waiting writer.
+            while state_lock.has_max_waiting_writers() {
+                let (lock, timed_out) = self.wait_until(Waiters::Both, state_lock, deadline);
+                state_lock = lock;
+                if timed_out { return false }
+            }
+
+            state_lock.add_waiting_writer();
+
+            #[cfg(feature = "metrics")]
+            state_lock.record_write_contended();
+
+            let ticket = state_lock.enqueue(Want::Write);
+            let (lock, acquired) = self.write_contended(state_lock, ticket, deadline);
+            state_lock = lock;
+            state_lock.dequeue(ticket);
+            state_lock.remove_waiting_writer();
+
+            // Check if we were at the max number of waiting writers.
+            if state_lock.near_max_waiting_writers() {
+                self.notify_all(&mut state_lock, Waiters::Both)
+            }
+
+            if !acquired { return false }
+        }
+
+        // At this point there should be one writer (us) and no readers.
+        debug_assert!(state_lock.is_writer_active() && state_lock.readers() == 0,
+                      "State not empty on write lock! State = {:?}", *state_lock);
+
+        state_lock.start_hold();
+        self.enter_level(&state_lock);
+        true
+    }
+
+    // Wait for the write lock according to the policy, and take it, unless
+    // it is handed to `ticket` by a fair unlock meanwhile.
+    //
+    // Gives up once `deadline` passes if there is one. Returns the state
+    // lock, and whether we got the write lock.
+    fn write_contended<'a>(&'a self, mut state_lock: MutexGuard<'a, State>, ticket: u64,
+                           deadline: Option<Instant>) -> (MutexGuard<'a, State>, bool) {
+        match state_lock.policy() {
+            LockPolicy::ReaderPreference => {
+                // Wait for the lock to be completely free without announcing
+                // ourselves, so readers can keep acquiring the lock meanwhile.
+                while !state_lock.can_write() {
+                    let (lock, timed_out) = self.wait_until(Waiters::Both, state_lock, deadline);
+                    state_lock = lock;
+                    if state_lock.take_grant(ticket) { return (state_lock, true) }
+                    if timed_out { return (state_lock, false) }
+                }
+
+                state_lock.set_writer_active();
+                return (state_lock, true);
+            },
+            LockPolicy::Adaptive(patience) => {
+                // Prefer readers like above, but only for so long. Patience
+                // beyond what an `Instant` can represent never runs out.
+                let patient_until = Instant::now().checked_add(patience);
+
+                while !state_lock.can_write() {
+                    if patient_until.map_or(false, |until| Instant::now() >= until) { break }
+
+                    let until = match (deadline, patient_until) {
+                        (Some(deadline), Some(patient)) if deadline < patient => Some(deadline),
+                        (Some(deadline), None) => Some(deadline),
+                        _ => patient_until
+                    };
+                    let (lock, timed_out) = self.wait_until(Waiters::Both, state_lock, until);
+                    state_lock = lock;
+                    if state_lock.take_grant(ticket) { return (state_lock, true) }
+                    if timed_out && until != patient_until { return (state_lock, false) }
+                }
+
+                if state_lock.can_write() {
+                    state_lock.set_writer_active();
+                    return (state_lock, true);
+                }
+
+                // We have waited too long, fall back to blocking new readers.
+            },
+            LockPolicy::Neutral | LockPolicy::PhaseFair => {}
+        }
+
+        // First wait for any other writers to unlock, for any upgradable
+        // reader, which may want to upgrade without waiting for us, and for
+        // any readers admitted ahead of us by a phase-fair unlock.
+        while state_lock.is_writer_active() || state_lock.is_upgradable()
+            || state_lock.has_admitted_readers() {
+            let (lock, timed_out) = self.wait_until(Waiters::Both, state_lock, deadline);
+            state_lock = lock;
+            if state_lock.take_grant(ticket) { return (state_lock, true) }
+            if timed_out { return (state_lock, false) }
+        }
+
+        // At this point there must be no writers, but there may be readers.
+        //
+        // We set the writer-active flag so that readers which try to
+        // acquire the lock from here on out will be queued after us, to
+        // prevent starvation.
+        state_lock.set_writer_active();
+
+        // Now wait for all readers to exit.
+        //
+        // This will happen eventually since new readers are waiting on
+        // us because we set the writer-active flag.
+        while state_lock.readers() != 0 {
+            let (lock, timed_out) = self.wait_until(Waiters::Readers, state_lock, deadline);
+            state_lock = lock;
+
+            if timed_out {
+                // Let in the readers we have been blocking.
+                state_lock.clear_writer_active();
+                state_lock.wake_async();
+                self.notify_all(&mut state_lock, Waiters::Both);
+                return (state_lock, false);
+            }
+        }
+
+        (state_lock, true)
+    }
+
+    /// Attempt to acquire an exclusive write lock without blocking.
+    ///
+    /// Returns true if we succeeded and false if acquiring the write lock would
+    /// require blocking.
+    ///
+    /// Like `try_read`, this never fails spuriously.
+    pub fn try_write(&self) -> bool {
+        let mut state_lock = self.state.lock().unwrap();
+
+        // If there are no readers or writers we can just take the lock.
+        if state_lock.can_write() {
+            state_lock.set_writer_active();
+            state_lock.start_hold();
+            self.enter_level(&state_lock);
+
+            // Success!
+            true
+        } else {
+            false // We would have to block
+        }
+    }
+
+    /// Attempt to acquire an exclusive write lock, reporting what blocked it.
+    ///
+    /// Like `try_write`, but on failure returns the number of readers holding
+    /// the lock at the time, or zero if a writer held it instead.
+    pub fn try_write_detailed(&self) -> Result<(), usize> {
+        let mut state_lock = self.state.lock().unwrap();
+
+        if state_lock.can_write() {
+            state_lock.set_writer_active();
+            state_lock.start_hold();
+            self.enter_level(&state_lock);
+            Ok(())
+        } else if state_lock.is_writer_active() {
+            // A writer waiting for readers to exit is blocked by them too.
+            Err(state_lock.readers())
+        } else {
+            // Count readers admitted by a phase-fair unlock, which are about
+            // to enter.
+            Err(state_lock.readers() + state_lock.admitted_readers())
+        }
+    }
+
+    /// Attempt to acquire a shared read lock, blocking for at most `timeout`.
+    ///
+    /// Returns true if we succeeded. A reader which times out leaves no trace
+    /// in the lock. Unlike `read`, this doesn't spin before blocking, so a
+    /// zero timeout behaves like `try_read`.
+    pub fn try_read_for(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now().checked_add(timeout);
+        self.read_until(self.state.lock().unwrap(), State::can_read, deadline)
+    }
+
+    /// Attempt to acquire a shared read lock, which may already be held by
+    /// the current thread, blocking for at most `timeout`.
+    ///
+    /// Like `read_recursive`, this doesn't queue behind writers waiting for
+    /// the lock while there are readers: the writer-active flag which blocks
+    /// `read` under the `Neutral` policy is ignored as long as the readers
+    /// haven't drained, so a thread which already holds a read lock succeeds
+    /// without waiting. Only a writer actually holding the lock, or the
+    /// maximum number of readers, makes this wait. Unlike `read_recursive`,
+    /// it is also fine to call without holding a read lock.
+    ///
+    /// Returns true if we succeeded.
+    pub fn try_read_recursive_for(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now().checked_add(timeout);
+        let mut state_lock = self.state.lock().unwrap();
+
+        while !state_lock.can_read_recursive() {
+            let (lock, timed_out) = self.wait_until(Waiters::Both, state_lock, deadline);
+            state_lock = lock;
+            if timed_out { return false }
+        }
+
+        state_lock.add_reader();
+        state_lock.start_hold();
+        self.enter_level(&state_lock);
+        true
+    }
+
+    /// Attempt to acquire an exclusive write lock, blocking for at most
+    /// `timeout`.
+    ///
+    /// Returns true if we succeeded. Readers blocked behind this writer while
+    /// it waited are let in again if it times out. See `try_read_for` for
+    /// more information.
+    pub fn try_write_for(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now().checked_add(timeout);
+        self.write_until(self.state.lock().unwrap(), deadline)
+    }
+
+    /// Attempt to acquire a shared read lock, blocking until `deadline` at
+    /// the latest.
+    ///
+    /// Like `try_read_for`, but the time left is recomputed from `deadline`
+    /// after every wakeup. A deadline in the past behaves like `try_read`.
+    pub fn try_read_until(&self, deadline: Instant) -> bool {
+        self.read_until(self.state.lock().unwrap(), State::can_read, Some(deadline))
+    }
+
+    /// Attempt to acquire an exclusive write lock, blocking until `deadline`
+    /// at the latest.
+    ///
+    /// See `try_read_until` and `try_write_for` for more information.
+    pub fn try_write_until(&self, deadline: Instant) -> bool {
+        self.write_until(self.state.lock().unwrap(), Some(deadline))
+    }
+
+    /// Attempt to acquire a shared read lock, retrying up to `attempts` more
+    /// times if that fails.
+    ///
+    /// Spins between attempts instead of yielding to the scheduler, so this
+    /// only suits locks which are held very briefly. Returns true if we
+    /// succeeded.
+    pub fn try_read_spin(&self, attempts: u32) -> bool {
+        self.try_spin(attempts, RawSharedMutex::try_read)
+    }
+
+    /// Attempt to acquire an exclusive write lock, retrying up to `attempts`
+    /// more times if that fails.
+    ///
+    /// See `try_read_spin` for more information.
+    pub fn try_write_spin(&self, attempts: u32) -> bool {
+        self.try_spin(attempts, RawSharedMutex::try_write)
+    }
+
+    #[inline]
+    fn try_spin(&self, attempts: u32, try_lock: fn(&Self) -> bool) -> bool {
+        for _ in 0..attempts {
+            if try_lock(self) { return true }
+            hint::spin_loop()
+        }
+
+        try_lock(self)
+    }
+
+    /// Attempt to acquire a shared read lock, registering `waker` to be woken
+    /// when the lock is next released if that fails.
+    ///
+    /// Returns true if the read lock was acquired. This is the building block
+    /// for futures which acquire the lock; see `try_read` for the conditions
+    /// under which acquiring fails.
+    ///
+    /// Wakers are woken while the mutex's internal state lock is held, so they
+    /// must not poll the future inline.
+    ///
+    /// Only available with the `async` feature.
+    #[cfg(feature = "async")]
+    pub fn poll_read(&self, waker: &Waker) -> bool {
+        let mut state_lock = self.state.lock().unwrap();
+
+        if state_lock.can_read() {
+            state_lock.add_reader();
+            state_lock.start_hold();
+            true
+        } else {
+            state_lock.register_waker(waker);
+            false
+        }
+    }
+
+    /// Attempt to acquire an exclusive write lock, registering `waker` to be
+    /// woken when the lock is next released if that fails.
+    ///
+    /// Returns true if the write lock was acquired. Unlike `write`, a pending
+    /// poll does not block incoming readers, so a continuous stream of readers
+    /// can starve asynchronous writers.
+    ///
+    /// Only available with the `async` feature.
+    #[cfg(feature = "async")]
+    pub fn poll_write(&self, waker: &Waker) -> bool {
+        let mut state_lock = self.state.lock().unwrap();
+
+        if state_lock.can_write() {
+            state_lock.set_writer_active();
+            state_lock.start_hold();
+            true
+        } else {
+            state_lock.register_waker(waker);
+            false
+        }
+    }
+
+    /// Unlock a previously acquired read lock.
+    ///
+    /// Behavior is unspecified (but not undefined) if `unlock_read` is called
+    /// without a previous accompanying `read`.
+    #[inline]
+    pub fn unlock_read(&self) {
+        let state_lock = self.unlock_read_to();
+        self.check_hold(state_lock);
+    }
+
+    /// Unlock a previously acquired read lock, handing the lock directly to
+    /// the longest waiting thread if this was the last reader.
+    ///
+    /// See `unlock_write_fair` for more information.
+    pub fn unlock_read_fair(&self) {
+        let mut state_lock = self.unlock_read_to();
+
+        if !state_lock.is_writer_active() && state_lock.readers() == 0
+            && state_lock.grant_next() {
+            self.notify_all(&mut state_lock, Waiters::Both);
+        }
+
+        self.check_hold(state_lock);
+    }
+
+    fn unlock_read_to(&self) -> MutexGuard<State> {
+        self.release_read(self.state.lock().unwrap())
+    }
+
+    // Release a read lock using the given state lock.
+    fn release_read<'a>(&self, mut state_lock: MutexGuard<'a, State>) -> MutexGuard<'a, State> {
+        // First decrement the reader count.
+        state_lock.remove_reader();
+
+        let released = state_lock.readers() == 0;
+        state_lock.finish_hold(released);
+        self.exit_level(&state_lock);
+
+        if released || state_lock.near_max_readers() {
+            state_lock.wake_async();
+        }
+
+        // Now check if there is a writer waiting and
+        // we are the last reader, or the last besides an upgrading reader.
+        if state_lock.is_writer_active() {
+            if state_lock.readers() == 0 || (state_lock.readers() == 1 && state_lock.is_upgradable()) {
+                // Wake up the waiting writer.
+                self.notify_one(&mut state_lock, Waiters::Readers);
+            }
+        } else if let LockPolicy::ReaderPreference | LockPolicy::Adaptive(_) = state_lock.policy() {
+            // Waiting writers don't set the writer-active flag under these
+            // policies, they wait on `both` for the readers to drain.
+            if state_lock.readers() == 0 || state_lock.near_max_readers() {
+                self.notify_all(&mut state_lock, Waiters::Both)
+            }
+        // Check if we where at the max number of readers.
+        } else if state_lock.near_max_readers() {
+            // Wake up a reader to replace us.
+            self.notify_one(&mut state_lock, Waiters::Both)
+        }
+
+        // Return the lock for potential further use.
+        state_lock
+    }
+
+    /// Unlock a previously acquired write lock.
+    ///
+    /// Behavior is unspecified (but not undefined) if `unlock_write` is called
+    /// without a previous accompanying `write`.
+    #[inline]
+    pub fn unlock_write(&self) {
+        let state_lock = self.unlock_write_to();
+        self.finish_unlock_write(state_lock)
+    }
+
+    /// Unlock a previously acquired write lock, handing the lock directly to
+    /// the longest waiting thread.
+    ///
+    /// With `unlock_write`, the woken threads race for the lock with each
+    /// other and with threads which are just arriving, so an unlucky thread
+    /// can keep losing. A fair unlock instead takes the lock on behalf of the
+    /// thread which has been blocked the longest, bounding how long threads
+    /// wait. This costs throughput: the lock stays unused until that thread
+    /// has woken up, instead of going to a thread which is already running.
+    ///
+    /// Threads waiting for a read lock are handed the lock one at a time,
+    /// while other waiting readers acquire it as usual once woken.
+    pub fn unlock_write_fair(&self) {
+        let mut state_lock = self.unlock_write_to();
+
+        // The waiting threads were just woken up, but they can't take the
+        // lock before we release the state lock.
+        state_lock.grant_next();
+
+        self.finish_unlock_write(state_lock)
+    }
+
+    // Finish releasing a write lock, after the state was updated.
+    fn finish_unlock_write(&self, state_lock: MutexGuard<State>) {
+        let callbacks = state_lock.extras.as_ref()
+            .map_or_else(Vec::new, |extras| extras.write_unlock_callbacks.clone());
+        self.check_hold(state_lock);
+
+        // Run the callbacks outside of the critical section.
+        for callback in callbacks { callback() }
+    }
+
+    #[inline]
+    fn unlock_write_to(&self) -> MutexGuard<State> {
+        // Capture the backtrace before locking the state, it can take a while.
+        #[cfg(feature = "poison-backtrace")]
+        let backtrace = if thread::panicking() { Some(Arc::new(Backtrace::force_capture())) } else { None };
+
+        let mut state_lock = self.state.lock().unwrap();
+
+        #[cfg(feature = "poison-backtrace")]
+        {
+            if backtrace.is_some() { state_lock.extras().poison_backtrace = backtrace }
+        }
+
+        if thread::panicking() { state_lock.extras().panicked = true }
+
+        // Writer locks are exclusive so we know there are no
+        // readers, we can just clear the writer flag.
+        state_lock.clear_writer_active();
+        state_lock.end_write_phase();
+        state_lock.finish_hold(true);
+        self.exit_level(&state_lock);
+        state_lock.wake_async();
+
+        // Wake any pending readers or writers.
+        self.notify_all(&mut state_lock, Waiters::Both);
+
+        state_lock
+    }
+
+    // Run the watchdog hook if the hold which was just finished took too long.
+    //
+    // Consumes the state lock, since the hook must run outside of it.
+    #[cfg(feature = "watchdog")]
+    fn check_hold(&self, mut state_lock: MutexGuard<State>) {
+        let name = state_lock.name();
+        let warning = state_lock.extras.as_mut()
+            .and_then(|extras| extras.watchdog.take_warning());
+        drop(state_lock);
+
+        if let Some((hook, held)) = warning {
+            hook(name, held)
+        }
+    }
+
+    #[cfg(not(feature = "watchdog"))]
+    #[inline]
+    fn check_hold(&self, _: MutexGuard<State>) {}
+
+    /// Wait on the given condition variable, resuming with a write lock.
+    ///
+    /// Behavior is unspecified if there was no previous accompanying `read`.
+    #[inline]
+    pub fn wait_from_read_to_write(&self, cond: &Condvar) {
+        let state_lock = self.unlock_read_to();
+        let state_lock = cond.wait(state_lock).unwrap();
+        self.write_from(state_lock);
+    }
+
+    /// Wait on the given condition variable, resuming with another read lock.
+    ///
+    /// Behavior is unspecified if there was no previous accompanying `read`.
+    #[inline]
+    pub fn wait_from_read_to_read(&self, cond: &Condvar) {
+        let state_lock = self.unlock_read_to();
+        let state_lock = cond.wait(state_lock).unwrap();
+        self.read_from(state_lock);
+    }
+
+    /// Wait on the given condition variable, resuming with a read lock.
+    ///
+    /// Behavior is unspecified if there was no previous accompanying `write`.
+    #[inline]
+    pub fn wait_from_write_to_read(&self, cond: &Condvar) {
+        let state_lock = self.unlock_write_to();
+        let state_lock = cond.wait(state_lock).unwrap();
+        self.read_from(state_lock);
+    }
+
+    /// Wait on the given condition variable, resuming with another write lock.
+    ///
+    /// Behavior is unspecified if there was no previous accompanying `write`.
+    #[inline]
+    pub fn wait_from_write_to_write(&self, cond: &Condvar) {
+        let state_lock = self.unlock_write_to();
+        let state_lock = cond.wait(state_lock).unwrap();
+        self.write_from(state_lock);
+    }
+
+    /// Wait on the given condition variable for at most `timeout`, resuming
+    /// with a write lock.
+    ///
+    /// Returns true if the wait timed out. The write lock is acquired either
+    /// way, which may take longer than `timeout`.
+    ///
+    /// Behavior is unspecified if there was no previous accompanying `read`.
+    #[inline]
+    pub fn wait_timeout_from_read_to_write(&self, cond: &Condvar, timeout: Duration) -> bool {
+        let state_lock = self.unlock_read_to();
+        let (state_lock, result) = cond.wait_timeout(state_lock, timeout).unwrap();
+        self.write_from(state_lock);
+        result.timed_out()
+    }
+
+    /// Wait on the given condition variable for at most `timeout`, resuming
+    /// with another read lock.
+    ///
+    /// Returns true if the wait timed out. The read lock is reacquired either
+    /// way, as exactly one reader, which may take longer than `timeout`.
+    ///
+    /// Behavior is unspecified if there was no previous accompanying `read`.
+    #[inline]
+    pub fn wait_timeout_from_read_to_read(&self, cond: &Condvar, timeout: Duration) -> bool {
+        let state_lock = self.unlock_read_to();
+        let (state_lock, result) = cond.wait_timeout(state_lock, timeout).unwrap();
+        self.read_from(state_lock);
+        result.timed_out()
+    }
+
+    /// Wait on the given condition variable for at most `timeout`, resuming
+    /// with a read lock.
+    ///
+    /// Returns true if the wait timed out. The read lock is acquired either
+    /// way, which may take longer than `timeout`.
+    ///
+    /// Behavior is unspecified if there was no previous accompanying `write`.
+    #[inline]
+    pub fn wait_timeout_from_write_to_read(&self, cond: &Condvar, timeout: Duration) -> bool {
+        let state_lock = self.unlock_write_to();
+        let (state_lock, result) = cond.wait_timeout(state_lock, timeout).unwrap();
+        self.read_from(state_lock);
+        result.timed_out()
+    }
+
+    /// Wait on the given condition variable for at most `timeout`, resuming
+    /// with another write lock.
+    ///
+    /// Returns true if the wait timed out. The write lock is reacquired either
+    /// way, which may take longer than `timeout`.
+    ///
+    /// Behavior is unspecified if there was no previous accompanying `write`.
+    #[inline]
+    pub fn wait_timeout_from_write_to_write(&self, cond: &Condvar, timeout: Duration) -> bool {
+        let state_lock = self.unlock_write_to();
+        let (state_lock, result) = cond.wait_timeout(state_lock, timeout).unwrap();
+        self.write_from(state_lock);
+        result.timed_out()
+    }
+}
+
+/// Internal State of the SharedMutex.
+struct State {
+    // The high bit indicates if a writer is active, the next bit if
+    // there is an upgradable reader.
+    //
+    // The rest of the upper half counts the number of writers waiting for
+    // the lock, the lower half counts the number of readers, including any
+    // upgradable reader.
+    bits: usize,
+
+    // Rarely used configuration, allocated on first use to keep the
+    // mutex small.
+    extras: Option<Box<Extras>>,
+
+    // The thread which set the writer-active flag, to detect recursive
+    // write locks.
+    #[cfg(debug_assertions)]
+    writer: Option<ThreadId>
+}
+
+struct Extras {
+    policy: LockPolicy,
+
+    // The maximum number of spins between attempts to acquire the lock.
+    backoff_cap: u32,
+
+    // How to wait for the lock, and the parked threads if parking.
+    blocking: Blocking,
+    parked_readers: VecDeque<Thread>,
+    parked_both: VecDeque<Thread>,
+
+    // The number of threads waiting on each condition variable, so that
+    // releasing an uncontended lock doesn't have to notify them.
+    blocked_readers: usize,
+    blocked_both: usize,
+
+    // The name used to identify the mutex in diagnostics.
+    name: Option<&'static str>,
+
+    // The level of the mutex in the lock hierarchy.
+    level: Option<u32>,
+
+    // Callbacks to run after a write lock is released.
+    write_unlock_callbacks: Vec<Arc<Fn() + Send + Sync>>,
+
+    // Threads blocked waiting for the lock in arrival order, by ticket.
+    queue: VecDeque<(u64, Want)>,
+    next_ticket: u64,
+
+    // A thread which was handed the lock by a fair unlock, but hasn't
+    // claimed it yet.
+    granted: Option<(u64, Want)>,
+
+    // Whether a write lock was ever released by a panicking thread.
+    panicked: bool,
+
+    // The number of write phases ended so far, the readers blocked during
+    // the current one, and the readers admitted by the last one which haven't
+    // taken the lock yet. Only used by the phase-fair policy.
+    write_phase: u64,
+    phase_readers: usize,
+    admitted_readers: usize,
+
+    // Where the last write lock released while panicking was released.
+    #[cfg(feature = "poison-backtrace")]
+    poison_backtrace: Option<Arc<Backtrace>>,
+
+    // Tracks how long the lock is held for.
+    #[cfg(feature = "watchdog")]
+    watchdog: Watchdog,
+
+    // Tasks waiting for the lock to be released.
+    #[cfg(feature = "async")]
+    wakers: Vec<Waker>
+}
+
+impl Default for Extras {
+    fn default() -> Self {
+        Extras {
+            policy: LockPolicy::default(),
+            backoff_cap: DEFAULT_BACKOFF_CAP,
+            blocking: Blocking::default(),
+            parked_readers: VecDeque::new(),
+            parked_both: VecDeque::new(),
+            blocked_readers: 0,
+            blocked_both: 0,
+            name: None,
+            level: None,
+            write_unlock_callbacks: Vec::new(),
+            queue: VecDeque::new(),
+            next_ticket: 0,
+            granted: None,
+            panicked: false,
+            write_phase: 0,
+            phase_readers: 0,
+            admitted_readers: 0,
+            #[cfg(feature = "poison-backtrace")]
+            poison_backtrace: None,
+            #[cfg(feature = "watchdog")]
+            watchdog: Watchdog::default(),
+            #[cfg(feature = "async")]
+            wakers: Vec::new()
+        }
+    }
+}
+
+// The kind of lock a queued thread is waiting for.
+#[derive(Debug, Clone, Copy)]
+enum Want {
+    Read,
+    Upgradable,
+    Write
+}
+
+#[cfg(feature = "watchdog")]
+#[derive(Default)]
+struct Watchdog {
+    // The threshold and hook to run when a hold exceeds it.
+    hook: Option<(Duration, Arc<Fn(Option<&'static str>, Duration) + Send + Sync>)>,
+
+    // When the current hold on the lock started.
+    locked_at: Option<Instant>,
+
+    // How long the most recently released hold lasted, if the last
+    // unlock released the lock.
+    finished: Option<Duration>
+}
+
+#[cfg(feature = "watchdog")]
+impl Watchdog {
+    fn take_warning(&mut self) -> Option<(Arc<Fn(Option<&'static str>, Duration) + Send + Sync>, Duration)> {
+        match (self.finished.take(), &self.hook) {
+            (Some(held), &Some((threshold, ref hook))) if held > threshold => {
+                Some((hook.clone(), held))
+            },
+            _ => None
+        }
+    }
+}
+
+#[cfg(target_pointer_width = "64")]
+const USIZE_BITS: u8 = 64;
+
+#[cfg(target_pointer_width = "32")]
+const USIZE_BITS: u8 = 32;
+
+// Only the high bit is set.
+//
+// We can mask the State with this to see if the
+// high bit is set, which would indicate a writer
+// is active.
+const WRITER_ACTIVE: usize = 1 << USIZE_BITS - 1;
+
+// Only the second highest bit is set, indicating that
+// one of the readers is upgradable.
+const UPGRADABLE: usize = 1 << USIZE_BITS - 2;
+
+// All the bits of the lower half are set.
+//
+// We can mask the State with this to see how many
+// readers there are.
+//
+// Also the maximum number of readers.
+const READERS_MASK: usize = (1 << USIZE_BITS / 2) - 1;
+
+// The lowest bit of the upper half, one waiting writer.
+const WAITING_WRITER: usize = READERS_MASK + 1;
+
+// All the bits of the upper half are set, except for the two high bits.
+//
+// We can mask the State with this to see how many
+// writers are waiting.
+const WAITING_WRITERS_MASK: usize = !WRITER_ACTIVE & !UPGRADABLE & !READERS_MASK;
+
+impl State {
+    #[inline]
+    fn new() -> Self {
+        State {
+            bits: 0,
+            extras: None,
+            #[cfg(debug_assertions)]
+            writer: None
+        }
+    }
+
+    #[inline]
+    fn extras(&mut self) -> &mut Extras {
+        self.extras.get_or_insert_with(Default::default)
+    }
+
+    #[inline]
+    fn policy(&self) -> LockPolicy {
+        self.extras.as_ref().map_or(LockPolicy::default(), |extras| extras.policy)
+    }
+
+    #[inline]
+    fn backoff_cap(&self) -> u32 {
+        self.extras.as_ref().map_or(DEFAULT_BACKOFF_CAP, |extras| extras.backoff_cap)
+    }
+
+    #[inline]
+    fn name(&self) -> Option<&'static str> {
+        self.extras.as_ref().and_then(|extras| extras.name)
+    }
+
+    #[inline]
+    fn level(&self) -> Option<u32> {
+        self.extras.as_ref().and_then(|extras| extras.level)
+    }
+
+    #[inline]
+    fn panicked(&self) -> bool {
+        self.extras.as_ref().map_or(false, |extras| extras.panicked)
+    }
+
+    #[inline]
+    fn blocking(&self) -> Blocking {
+        self.extras.as_ref().map_or(Blocking::default(), |extras| extras.blocking)
+    }
+
+    // The threads parked as one of `waiters`.
+    #[inline]
+    fn parked(&mut self, waiters: Waiters) -> &mut VecDeque<Thread> {
+        let extras = self.extras();
+        match waiters {
+            Waiters::Readers => &mut extras.parked_readers,
+            Waiters::Both => &mut extras.parked_both
+        }
+    }
+
+    // The number of threads waiting on the condition variable for `waiters`.
+    #[inline]
+    fn blocked(&mut self, waiters: Waiters) -> &mut usize {
+        let extras = self.extras();
+        match waiters {
+            Waiters::Readers => &mut extras.blocked_readers,
+            Waiters::Both => &mut extras.blocked_both
+        }
+    }
+
+    #[inline]
+    fn has_blocked(&self, waiters: Waiters) -> bool {
+        self.extras.as_ref().map_or(false, |extras| match waiters {
+            Waiters::Readers => extras.blocked_readers != 0,
+            Waiters::Both => extras.blocked_both != 0
+        })
+    }
+
+    // Join the queue of blocked threads, returning our ticket.
+    fn enqueue(&mut self, want: Want) -> u64 {
+        let extras = self.extras();
+        let ticket = extras.next_ticket;
+        extras.next_ticket += 1;
+        extras.queue.push_back((ticket, want));
+        ticket
+    }
+
+    // Leave the queue of blocked threads, if we are still in it.
+    fn dequeue(&mut self, ticket: u64) {
+        let queue = &mut self.extras().queue;
+        if let Some(index) = queue.iter().position(|&(queued, _)| queued == ticket) {
+            queue.remove(index);
+        }
+    }
+
+    // Claim the lock if a fair unlock handed it to `ticket`.
+    fn take_grant(&mut self, ticket: u64) -> bool {
+        match self.extras().granted {
+            Some((granted, want)) if granted == ticket => {
+                self.extras().granted = None;
+
+                // Record the actual owner of the write lock.
+                if let Want::Write = want { self.set_writer_active() }
+
+                true
+            },
+            _ => false
+        }
+    }
+
+    // Take the free lock on behalf of the longest waiting thread.
+    //
+    // Returns false if no thread is waiting.
+    fn grant_next(&mut self) -> bool {
+        let next = self.extras.as_mut().and_then(|extras| extras.queue.pop_front());
+
+        match next {
+            Some((ticket, want)) => {
+                debug_assert!(self.extras().granted.is_none(), "Lock handed over twice!");
+
+                match want {
+                    Want::Read => self.add_reader(),
+                    Want::Upgradable => {
+                        self.set_upgradable();
+                        self.add_reader()
+                    },
+                    // Only the flag: the writer is recorded by `take_grant`
+                    // on the thread which actually wakes up with the lock.
+                    Want::Write => self.bits |= WRITER_ACTIVE
+                }
+
+                self.extras().granted = Some((ticket, want));
+                true
+            },
+            None => false
+        }
+    }
+
+    // Note that the lock is held, unless it already was.
+    #[inline]
+    fn start_hold(&mut self) {
+        #[cfg(feature = "watchdog")]
+        {
+            if let Some(ref mut extras) = self.extras {
+                if extras.watchdog.hook.is_some() && extras.watchdog.locked_at.is_none() {
+                    extras.watchdog.locked_at = Some(Instant::now());
+                }
+            }
+        }
+    }
+
+    // Note the end of the current hold on the lock, if it was released.
+    #[inline]
+    fn finish_hold(&mut self, _released: bool) {
+        #[cfg(feature = "watchdog")]
+        {
+            if let Some(ref mut extras) = self.extras {
+                extras.watchdog.finished = if _released {
+                    extras.watchdog.locked_at.take().map(|at| at.elapsed())
+                } else {
+                    None
+                };
+            }
+        }
+    }
+
+    #[cfg(feature = "async")]
+    fn register_waker(&mut self, waker: &Waker) {
+        let wakers = &mut self.extras().wakers;
+
+        if !wakers.iter().any(|registered| registered.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+    }
+
+    // Wake all tasks waiting for the lock, they will race to acquire it.
+    #[inline]
+    fn wake_async(&mut self) {
+        #[cfg(feature = "async")]
+        {
+            if let Some(ref mut extras) = self.extras {
+                for waker in extras.wakers.drain(..) { waker.wake() }
+            }
+        }
+    }
+
+    // Whether a read lock can be taken without waiting.
+    #[inline]
+    fn can_read(&self) -> bool { !self.is_writer_active() && !self.has_max_readers() }
+
+    // Whether a reader which gives up at the reader limit can stop waiting.
+    #[inline]
+    fn can_read_or_full(&self) -> bool { self.can_read() || self.has_max_readers() }
+
+    // Whether a read lock can be taken without waiting, ignoring writers which
+    // are only waiting for the current readers to exit.
+    #[inline]
+    fn can_read_recursive(&self) -> bool {
+        // A writer holding the lock means there are no readers.
+        self.can_read() || (self.readers() != 0 && !self.has_max_readers())
+    }
+
+    // Whether a write lock can be taken without waiting.
+    #[inline]
+    fn can_write(&self) -> bool {
+        !self.is_writer_active() && self.readers() == 0 && !self.has_admitted_readers()
+    }
+
+    // Whether an upgradable read lock can be taken without waiting.
+    #[inline]
+    fn can_upgradable_read(&self) -> bool { self.can_read() && !self.is_upgradable() }
+
+    #[inline]
+    fn is_upgradable(&self) -> bool { self.bits & UPGRADABLE != 0 }
+
+    #[inline]
+    fn set_upgradable(&mut self) { self.bits |= UPGRADABLE }
+
+    #[inline]
+    fn clear_upgradable(&mut self) { self.bits &= !UPGRADABLE }
+
+    #[inline]
+    fn is_writer_active(&self) -> bool { self.bits & WRITER_ACTIVE != 0 }
+
+    #[inline]
+    fn set_writer_active(&mut self) {
+        self.bits |= WRITER_ACTIVE;
+
+        #[cfg(debug_assertions)]
+        { self.writer = Some(thread::current().id()) }
+    }
+
+    #[inline]
+    fn clear_writer_active(&mut self) {
+        self.bits &= !WRITER_ACTIVE;
+
+        #[cfg(debug_assertions)]
+        { self.writer = None }
+    }
+
+    #[inline]
+    fn readers(&self) -> usize { self.bits & READERS_MASK }
+
+    // The number of readers admitted by a phase-fair unlock which haven't
+    // taken the lock yet.
+    #[inline]
+    fn admitted_readers(&self) -> usize {
+        self.extras.as_ref().map_or(0, |extras| extras.admitted_readers)
+    }
+
+    #[inline]
+    fn has_admitted_readers(&self) -> bool { self.admitted_readers() != 0 }
+
+    // Note that a reader is about to block, returning the write phase it
+    // waits for the end of under the phase-fair policy.
+    fn enter_phase(&mut self) -> Option<u64> {
+        if self.policy() != LockPolicy::PhaseFair || !self.is_writer_active() { return None }
+
+        let extras = self.extras();
+        extras.phase_readers += 1;
+        Some(extras.write_phase)
+    }
+
+    // Note that a reader which blocked during `phase` stopped waiting.
+    //
+    // Returns true if it was the last reader admitted ahead of the waiting
+    // writers.
+    fn leave_phase(&mut self, phase: u64) -> bool {
+        let extras = self.extras();
+
+        if extras.write_phase == phase {
+            extras.phase_readers -= 1;
+            false
+        } else {
+            extras.admitted_readers -= 1;
+            extras.admitted_readers == 0
+        }
+    }
+
+    // End the current write phase under the phase-fair policy, admitting the
+    // readers which blocked during it ahead of any waiting writer.
+    fn end_write_phase(&mut self) {
+        if self.policy() != LockPolicy::PhaseFair { return }
+
+        let extras = self.extras();
+        extras.write_phase += 1;
+        extras.admitted_readers += extras.phase_readers;
+        extras.phase_readers = 0;
+    }
+
+    #[inline]
+    fn has_max_readers(&self) -> bool { self.readers() == READERS_MASK }
+
+    #[inline]
+    fn near_max_readers(&self) -> bool { self.readers() == READERS_MASK - 1 }
+
+    #[inline]
+    fn add_reader(&mut self) {
+        // Callers wait for space first, so overflowing into the waiting
+        // writer count would be a bug in this module.
+        assert!(!self.has_max_readers(), "Too many readers!");
+        self.bits += 1;
+
+        #[cfg(feature = "metrics")]
+        self.record_readers();
+    }
+
+    #[inline]
+    fn remove_reader(&mut self) {
+        self.bits -= 1;
+
+        #[cfg(feature = "metrics")]
+        self.record_readers();
+    }
+
+    #[cfg(feature = "metrics")]
+    fn record_readers(&self) {
+        let readers = self.readers() as f64;
+
+        match self.name() {
+            Some(name) => ::metrics::gauge!("shared_mutex.readers", "lock" => name).set(readers),
+            None => ::metrics::gauge!("shared_mutex.readers").set(readers)
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    fn record_write_contended(&self) {
+        match self.name() {
+            Some(name) => ::metrics::counter!("shared_mutex.write_contended_total", "lock" => name).increment(1),
+            None => ::metrics::counter!("shared_mutex.write_contended_total").increment(1)
+        }
+    }
+
+    #[inline]
+    fn waiting_writers(&self) -> usize { (self.bits & WAITING_WRITERS_MASK) / WAITING_WRITER }
+
+    #[inline]
+    fn has_max_waiting_writers(&self) -> bool {
+        self.bits & WAITING_WRITERS_MASK == WAITING_WRITERS_MASK
+    }
+
+    #[inline]
+    fn near_max_waiting_writers(&self) -> bool {
+        self.bits & WAITING_WRITERS_MASK == WAITING_WRITERS_MASK - WAITING_WRITER
+    }
+
+    #[inline]
+    fn add_waiting_writer(&mut self) { self.bits += WAITING_WRITER }
+
+    #[inline]
+    fn remove_waiting_writer(&mut self) { self.bits -= WAITING_WRITER }
+}
+
+impl fmt::Debug for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("State")
+            .field("writer_active", &self.is_writer_active())
+            .field("upgradable", &self.is_upgradable())
+            .field("readers", &self.readers())
+            .field("waiting_writers", &self.waiting_writers())
+            .finish()
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use raw::RawSharedMutex;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::{panic, thread};
+    use std::time::{Duration, Instant};
+
+    use raw::{RawSharedMutex, LockPolicy, Blocking, DEFAULT_BACKOFF_CAP};
 
     #[test]
     fn test_raw_is() {
@@ -295,5 +2082,419 @@ mod test {
         assert!(mutex1.is(&mutex1));
         assert!(!mutex1.is(&mutex2));
     }
+
+    #[test]
+    fn test_has_waiting_writer() {
+        for &policy in &[LockPolicy::Neutral, LockPolicy::ReaderPreference] {
+            let mutex = Arc::new(RawSharedMutex::with_policy(policy));
+            assert!(!mutex.has_waiting_writer());
+
+            mutex.read();
+            let writer = {
+                let mutex = mutex.clone();
+                thread::spawn(move || { mutex.write(); mutex.unlock_write() })
+            };
+
+            while !mutex.has_waiting_writer() { thread::yield_now() }
+
+            mutex.unlock_read();
+            writer.join().unwrap();
+            assert!(!mutex.has_waiting_writer());
+        }
+    }
+
+    #[test]
+    fn test_destroyed_while_locked() {
+        let mutex = RawSharedMutex::new();
+
+        // Leaking a lock is safe, and so is destroying the mutex afterwards.
+        mutex.read();
+        drop(mutex);
+
+        let mutex = RawSharedMutex::new();
+        mutex.write();
+        drop(mutex);
+    }
+
+    #[test]
+    fn test_try_read_recursive_for() {
+        let mutex = Arc::new(RawSharedMutex::new());
+
+        mutex.read();
+        let writer = {
+            let mutex = mutex.clone();
+            thread::spawn(move || { mutex.write(); mutex.unlock_write() })
+        };
+        while !mutex.has_waiting_writer() { thread::yield_now() }
+
+        // Recursing doesn't deadlock behind the queued writer.
+        assert!(!mutex.try_read_for(Duration::from_millis(10)));
+        assert!(mutex.try_read_recursive_for(Duration::from_secs(10)));
+        mutex.unlock_read();
+        mutex.unlock_read();
+        writer.join().unwrap();
+
+        mutex.write();
+        assert!(!mutex.try_read_recursive_for(Duration::from_millis(10)));
+        mutex.unlock_write();
+        assert!(mutex.try_read_recursive_for(Duration::from_millis(0)));
+        mutex.unlock_read();
+    }
+
+    #[test]
+    fn test_park() {
+        for &policy in &[LockPolicy::Neutral, LockPolicy::ReaderPreference,
+                         LockPolicy::Adaptive(Duration::from_millis(1)), LockPolicy::PhaseFair] {
+            let mut mutex = RawSharedMutex::with_policy(policy);
+            mutex.set_blocking(Blocking::Park);
+            mutex.set_backoff_cap(0);
+            assert_eq!(mutex.blocking(), Blocking::Park);
+
+            let mutex = Arc::new(mutex);
+            let writers = Arc::new(AtomicUsize::new(0));
+
+            let threads = (0..8).map(|i| {
+                let (mutex, writers) = (mutex.clone(), writers.clone());
+                thread::spawn(move || {
+                    for j in 0..500 {
+                        match (i + j) % 4 {
+                            0 => {
+                                mutex.write();
+                                assert_eq!(writers.fetch_add(1, Ordering::SeqCst), 0);
+                                writers.fetch_sub(1, Ordering::SeqCst);
+                                mutex.unlock_write_fair();
+                            },
+                            1 => {
+                                mutex.upgradable_read();
+                                mutex.upgrade();
+                                assert_eq!(writers.fetch_add(1, Ordering::SeqCst), 0);
+                                writers.fetch_sub(1, Ordering::SeqCst);
+                                mutex.unlock_write();
+                            },
+                            _ => {
+                                mutex.read();
+                                assert_eq!(writers.load(Ordering::SeqCst), 0);
+                                mutex.unlock_read();
+                            }
+                        }
+                    }
+                })
+            }).collect::<Vec<_>>();
+
+            for thread in threads { thread.join().unwrap() }
+            assert!(!mutex.is_locked());
+        }
+    }
+
+    #[test]
+    fn test_upgrade_with_waiting_writer() {
+        for &policy in &[LockPolicy::Neutral, LockPolicy::ReaderPreference, LockPolicy::PhaseFair] {
+            let mutex = Arc::new(RawSharedMutex::with_policy(policy));
+
+            mutex.upgradable_read();
+            assert!(mutex.try_read());
+            mutex.unlock_read();
+
+            let writer = {
+                let mutex = mutex.clone();
+                thread::spawn(move || { mutex.write(); mutex.unlock_write() })
+            };
+
+            while !mutex.has_waiting_writer() { thread::yield_now() }
+
+            // The waiting writer doesn't get in before the upgrade.
+            mutex.upgrade();
+            assert!(mutex.is_locked_exclusive());
+            mutex.unlock_write();
+
+            writer.join().unwrap();
+            assert!(!mutex.is_locked());
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "recursive write lock of test on thread")]
+    fn test_recursive_write() {
+        let mut mutex = RawSharedMutex::new();
+        mutex.set_name("test");
+
+        mutex.write();
+        mutex.write();
+    }
+
+    #[test]
+    fn test_unlock_write_fair() {
+        let mutex = Arc::new(RawSharedMutex::new());
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        mutex.write();
+
+        let spawn_writer = |id| {
+            let (mutex, order) = (mutex.clone(), order.clone());
+            thread::spawn(move || {
+                mutex.write();
+                order.lock().unwrap().push(id);
+                mutex.unlock_write_fair();
+            })
+        };
+
+        let first = spawn_writer(1);
+        while !mutex.has_waiting_writer() { thread::yield_now() }
+        let second = spawn_writer(2);
+        thread::sleep(Duration::from_millis(10));
+
+        // The lock goes straight to the first writer, so it can't be taken
+        // before that writer is done.
+        mutex.unlock_write_fair();
+        if mutex.try_read() {
+            assert_eq!(order.lock().unwrap()[0], 1);
+            mutex.unlock_read();
+        }
+
+        first.join().unwrap();
+        second.join().unwrap();
+        assert_eq!(*order.lock().unwrap(), [1, 2]);
+        assert!(!mutex.is_locked());
+    }
+
+    #[test]
+    fn test_relock_after_unlock_write_fair() {
+        let mutex = Arc::new(RawSharedMutex::new());
+        let written = Arc::new(AtomicBool::new(false));
+
+        mutex.write();
+        let writer = {
+            let (mutex, written) = (mutex.clone(), written.clone());
+            thread::spawn(move || {
+                mutex.write();
+                written.store(true, Ordering::SeqCst);
+                mutex.unlock_write()
+            })
+        };
+
+        while !mutex.has_waiting_writer() { thread::yield_now() }
+
+        // The lock belongs to the waiting writer now, even if it hasn't
+        // woken up yet, so locking again just waits for it.
+        mutex.unlock_write_fair();
+        mutex.write();
+        assert!(written.load(Ordering::SeqCst));
+        mutex.unlock_write();
+
+        writer.join().unwrap();
+        assert!(!mutex.is_locked());
+    }
+
+    #[test]
+    fn test_unlock_read_fair() {
+        let mutex = Arc::new(RawSharedMutex::with_policy(LockPolicy::ReaderPreference));
+
+        let written = Arc::new(AtomicBool::new(false));
+
+        mutex.read();
+        let writer = {
+            let (mutex, written) = (mutex.clone(), written.clone());
+            thread::spawn(move || {
+                mutex.write();
+                written.store(true, Ordering::SeqCst);
+                mutex.unlock_write()
+            })
+        };
+
+        while !mutex.has_waiting_writer() { thread::yield_now() }
+
+        // New readers would normally keep the waiting writer out.
+        mutex.unlock_read_fair();
+        if mutex.try_read() {
+            assert!(written.load(Ordering::SeqCst));
+            mutex.unlock_read();
+        }
+
+        writer.join().unwrap();
+        assert!(!mutex.is_locked());
+    }
+
+    #[test]
+    fn test_assert_unlocked() {
+        let mut mutex = RawSharedMutex::new();
+        mutex.set_name("test");
+        mutex.assert_unlocked();
+        mutex.assert_no_readers();
+
+        mutex.write();
+        mutex.assert_no_readers();
+        mutex.unlock_write();
+
+        mutex.read();
+        let unlocked = panic::catch_unwind(|| mutex.assert_unlocked());
+        let no_readers = panic::catch_unwind(|| mutex.assert_no_readers());
+        mutex.unlock_read();
+
+        assert_eq!(*no_readers.unwrap_err().downcast::<String>().unwrap(),
+                   "mutex \"test\" has 1 reader(s)");
+        assert!(unlocked.unwrap_err().downcast::<String>().unwrap().starts_with("mutex \"test\" is locked"));
+        mutex.assert_unlocked();
+    }
+
+    #[test]
+    fn test_try_spin() {
+        let mutex = RawSharedMutex::new();
+
+        assert!(mutex.try_write_spin(0));
+        assert!(!mutex.try_read_spin(100));
+        assert!(!mutex.try_write_spin(100));
+        mutex.unlock_write();
+
+        assert!(mutex.try_read_spin(0));
+        assert!(mutex.try_read_spin(10));
+        assert!(!mutex.try_write_spin(10));
+        mutex.unlock_read();
+        mutex.unlock_read();
+    }
+
+    #[test]
+    fn test_try_not_spurious() {
+        let mutex = Arc::new(RawSharedMutex::new());
+
+        // Concurrent readers never make each other's try_read fail.
+        let threads = (0..4).map(|_| {
+            let mutex = mutex.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    assert!(mutex.try_read());
+                    mutex.unlock_read();
+                }
+            })
+        }).collect::<Vec<_>>();
+        for thread in threads { thread.join().unwrap() }
+
+        // A free lock can always be taken, however often it is tried.
+        for _ in 0..1000 {
+            assert!(mutex.try_write());
+            mutex.unlock_write();
+        }
+    }
+
+    #[test]
+    fn test_max_readers() {
+        let mutex = RawSharedMutex::new();
+
+        // Pretend all but one of the possible readers hold the lock.
+        mutex.state.lock().unwrap().bits = super::READERS_MASK - 1;
+
+        assert!(mutex.try_read_checked());
+        assert!(!mutex.try_read());
+        assert!(!mutex.try_read_checked());
+        assert!(!mutex.try_read_for(Duration::from_millis(10)));
+        assert!(!mutex.try_read_recursive_for(Duration::from_millis(10)));
+        mutex.unlock_read();
+
+        mutex.read_recursive();
+        assert!(!mutex.try_read());
+        mutex.unlock_read();
+
+        mutex.state.lock().unwrap().bits = 0;
+        assert!(mutex.try_write());
+        mutex.unlock_write();
+
+        // A reader blocked behind a writer gives up if the lock fills up
+        // with readers in the meantime.
+        let mutex = Arc::new(RawSharedMutex::new());
+        mutex.write();
+
+        let reader = {
+            let mutex = mutex.clone();
+            thread::spawn(move || mutex.try_read_checked())
+        };
+
+        thread::sleep(Duration::from_millis(10));
+        mutex.state.lock().unwrap().bits = super::READERS_MASK;
+        mutex.both.notify_all();
+        assert!(!reader.join().unwrap());
+    }
+
+    #[test]
+    fn test_try_for() {
+        let mutex = Arc::new(RawSharedMutex::new());
+
+        mutex.read();
+        assert!(mutex.try_read_for(Duration::from_millis(0)));
+        mutex.unlock_read();
+
+        // A writer times out while readers hold on, then lets readers in.
+        assert!(!mutex.try_write_for(Duration::from_millis(10)));
+        assert!(mutex.try_read());
+        mutex.unlock_read();
+
+        // The writer gets in once the readers leave within the timeout.
+        let reader = {
+            let mutex = mutex.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                mutex.unlock_read();
+            })
+        };
+        assert!(mutex.try_write_for(Duration::from_secs(10)));
+        reader.join().unwrap();
+
+        assert!(!mutex.try_read_for(Duration::from_millis(10)));
+        assert!(!mutex.try_write_for(Duration::from_millis(0)));
+        mutex.unlock_write();
+
+        assert!(mutex.try_write_for(Duration::from_millis(0)));
+        mutex.unlock_write();
+        mutex.assert_unlocked();
+    }
+
+    #[test]
+    fn test_try_until() {
+        let mutex = RawSharedMutex::new();
+        let past = Instant::now();
+
+        assert!(mutex.try_write_until(past));
+        assert!(!mutex.try_read_until(past));
+        assert!(!mutex.try_read_until(Instant::now() + Duration::from_millis(10)));
+        assert!(!mutex.try_write_until(past));
+        mutex.unlock_write();
+
+        assert!(mutex.try_read_until(past));
+        let start = Instant::now();
+        assert!(!mutex.try_write_until(start + Duration::from_millis(10)));
+        assert!(start.elapsed() >= Duration::from_millis(10));
+        mutex.unlock_read();
+        mutex.assert_unlocked();
+    }
+
+    #[test]
+    fn test_backoff_cap() {
+        let mut mutex = RawSharedMutex::new();
+        assert_eq!(mutex.backoff_cap(), DEFAULT_BACKOFF_CAP);
+
+        mutex.set_backoff_cap(0);
+        assert_eq!(mutex.backoff_cap(), 0);
+
+        // Contended acquisitions still block once the backoff is exhausted.
+        for &cap in &[0, 4, DEFAULT_BACKOFF_CAP] {
+            let mut mutex = RawSharedMutex::new();
+            mutex.set_backoff_cap(cap);
+            let mutex = Arc::new(mutex);
+
+            mutex.write();
+            let writer = {
+                let mutex = mutex.clone();
+                thread::spawn(move || { mutex.write(); mutex.unlock_write() })
+            };
+            let reader = {
+                let mutex = mutex.clone();
+                thread::spawn(move || { mutex.read(); mutex.unlock_read() })
+            };
+
+            thread::sleep(Duration::from_millis(10));
+            mutex.unlock_write();
+            writer.join().unwrap();
+            reader.join().unwrap();
+        }
+    }
 }
 
@@ -0,0 +1,138 @@
+//! A `parking_lot`-style `RwLock` built on SharedMutex.
+//!
+//! Eases migration from `parking_lot::RwLock`: replacing
+//! `use parking_lot::RwLock` with `use shared_mutex::RwLock` should mostly
+//! just work, and the guards gain the ability to wait on a `Condvar`.
+//!
+//! Differences from `parking_lot::RwLock`:
+//!
+//!  - Poisoning is ignored rather than absent. A panic while holding a write
+//!    lock still poisons the underlying SharedMutex, but `RwLock` always hands
+//!    out the data regardless. Waiting on a `Condvar` through the guards still
+//!    reports poisoning, since those are the regular SharedMutex guards.
+//!  - The guards are `Send`, so they can be released on a different thread
+//!    than the one which acquired them.
+//!  - `RwLock::new` is not a `const fn`.
+
+use std::sync::TryLockError;
+use std::fmt;
+
+use {SharedMutex, SharedMutexReadGuard, SharedMutexWriteGuard};
+
+/// A reader-writer lock which ignores poisoning.
+///
+/// See the module documentation for how it differs from
+/// `parking_lot::RwLock`.
+pub struct RwLock<T: ?Sized> {
+    // Where the data lives inside the poison cell, so `data_ptr` can find it
+    // without going through the cell while another thread may hold it.
+    offset: usize,
+    mutex: SharedMutex<T>
+}
+
+/// A shared read guard on an `RwLock`.
+pub type RwLockReadGuard<'mutex, T> = SharedMutexReadGuard<'mutex, T>;
+
+/// An exclusive write guard on an `RwLock`.
+pub type RwLockWriteGuard<'mutex, T> = SharedMutexWriteGuard<'mutex, T>;
+
+impl<T> RwLock<T> {
+    /// Create a new RwLock protecting the given value.
+    #[inline]
+    pub fn new(value: T) -> Self {
+        let mut lock = RwLock { offset: 0, mutex: SharedMutex::new(value) };
+        lock.offset = lock.get_mut() as *mut T as usize - lock.mutex.data.get() as usize;
+        lock
+    }
+
+    /// Extract the data from the lock and destroy the lock.
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.mutex.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Acquire a shared read lock on the data.
+    #[inline]
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        self.mutex.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Acquire an exclusive write lock on the data.
+    #[inline]
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        self.mutex.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Attempt to acquire a shared read lock on the data without blocking.
+    #[inline]
+    pub fn try_read(&self) -> Option<RwLockReadGuard<T>> {
+        match self.mutex.try_read() {
+            Ok(guard) => Some(guard),
+            Err(TryLockError::Poisoned(poisoned)) => Some(poisoned.into_inner()),
+            Err(TryLockError::WouldBlock) => None
+        }
+    }
+
+    /// Attempt to acquire an exclusive write lock on the data without blocking.
+    #[inline]
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>> {
+        match self.mutex.try_write() {
+            Ok(guard) => Some(guard),
+            Err(TryLockError::Poisoned(poisoned)) => Some(poisoned.into_inner()),
+            Err(TryLockError::WouldBlock) => None
+        }
+    }
+
+    /// Get a mutable reference to the data without locking.
+    ///
+    /// Safe since it requires a unique reference to the lock.
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.mutex.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Get a raw pointer to the data.
+    ///
+    /// The pointer is only safe to use while holding a lock of the
+    /// appropriate kind.
+    #[inline]
+    pub fn data_ptr(&self) -> *mut T {
+        (self.mutex.data.get() as *mut T).wrapping_byte_add(self.offset)
+    }
+
+    /// Check whether the lock is currently held by any reader or writer.
+    #[inline]
+    pub fn is_locked(&self) -> bool { self.mutex.raw.is_locked() }
+
+    /// Check whether the lock is currently held by a writer.
+    #[inline]
+    pub fn is_locked_exclusive(&self) -> bool { self.mutex.raw.is_locked_exclusive() }
+
+    /// Get the underlying SharedMutex, for example to lock it with
+    /// poisoning reported.
+    #[inline]
+    pub fn as_shared_mutex(&self) -> &SharedMutex<T> { &self.mutex }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    #[inline]
+    fn default() -> Self { RwLock::new(T::default()) }
+}
+
+impl<T> From<T> for RwLock<T> {
+    #[inline]
+    fn from(value: T) -> Self { RwLock::new(value) }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut writer = f.debug_struct("RwLock");
+
+        match self.try_read() {
+            Some(l) => writer.field("data", &&*l),
+            None => writer.field("data", &"{{ locked }}")
+        }.finish()
+    }
+}
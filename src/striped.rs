@@ -0,0 +1,151 @@
+//! A fixed set of SharedMutexes, for striped locking.
+//!
+//! Striped locking splits some data into parts which are each protected by
+//! their own lock, such as the buckets of a hash map, so that threads working
+//! on different parts don't contend. `SharedMutexVec` takes care of indexing
+//! into the locks, and of locking all of them in a consistent order for bulk
+//! operations, so that those can't deadlock with each other.
+//!
+//! `PaddedSharedMutexVec` keeps each lock on its own cache line, see the
+//! `padded` module.
+
+use std::sync::{LockResult, PoisonError};
+use std::borrow::Borrow;
+use std::marker::PhantomData;
+use std::fmt;
+
+use {SharedMutex, SharedMutexReadGuard, SharedMutexWriteGuard, read_poisoned};
+use padded::PaddedSharedMutex;
+
+/// A fixed number of SharedMutexes, each protecting one part of some data.
+///
+/// The locks are stored as `L`, which is either a plain `SharedMutex`, or a
+/// `PaddedSharedMutex` for `PaddedSharedMutexVec`.
+pub struct SharedMutexVec<T, L = SharedMutex<T>> {
+    locks: Box<[L]>,
+    data: PhantomData<T>
+}
+
+/// A SharedMutexVec which keeps each lock on its own cache line.
+pub type PaddedSharedMutexVec<T> = SharedMutexVec<T, PaddedSharedMutex<T>>;
+
+impl<T> SharedMutexVec<T> {
+    /// Create a new SharedMutexVec with a lock for each of the given values.
+    pub fn new(values: Vec<T>) -> Self { SharedMutexVec::from_values(values) }
+
+    /// Create a new SharedMutexVec with `len` locks, protecting the values
+    /// returned by `init` for each index.
+    pub fn from_fn<F>(len: usize, init: F) -> Self
+    where F: FnMut(usize) -> T {
+        SharedMutexVec::new((0..len).map(init).collect())
+    }
+}
+
+impl<T> PaddedSharedMutexVec<T> {
+    /// Create a new SharedMutexVec with a lock on its own cache line for each
+    /// of the given values.
+    pub fn new_padded(values: Vec<T>) -> Self { SharedMutexVec::from_values(values) }
+
+    /// Create a new SharedMutexVec with `len` locks on their own cache lines,
+    /// protecting the values returned by `init` for each index.
+    pub fn from_fn_padded<F>(len: usize, init: F) -> Self
+    where F: FnMut(usize) -> T {
+        SharedMutexVec::new_padded((0..len).map(init).collect())
+    }
+}
+
+impl<T, L: From<SharedMutex<T>>> SharedMutexVec<T, L> {
+    fn from_values(values: Vec<T>) -> Self {
+        SharedMutexVec {
+            locks: values.into_iter().map(|value| L::from(SharedMutex::new(value)))
+                .collect::<Vec<_>>().into_boxed_slice(),
+            data: PhantomData
+        }
+    }
+}
+
+impl<T, L: Borrow<SharedMutex<T>>> SharedMutexVec<T, L> {
+    /// Get the number of locks.
+    #[inline]
+    pub fn len(&self) -> usize { self.locks.len() }
+
+    /// Check whether there are no locks at all.
+    #[inline]
+    pub fn is_empty(&self) -> bool { self.locks.is_empty() }
+
+    /// Get the lock at `index`.
+    ///
+    /// Panics if `index` is out of bounds.
+    #[inline]
+    pub fn get(&self, index: usize) -> &SharedMutex<T> { self.locks[index].borrow() }
+
+    /// Get all the locks.
+    #[inline]
+    pub fn as_slice(&self) -> &[L] { &self.locks }
+
+    /// Acquire a shared Read lock on the part at `index`.
+    ///
+    /// Panics if `index` is out of bounds.
+    #[inline]
+    pub fn read(&self, index: usize) -> LockResult<SharedMutexReadGuard<T>> {
+        self.get(index).read()
+    }
+
+    /// Acquire an exclusive Write lock on the part at `index`.
+    ///
+    /// Panics if `index` is out of bounds.
+    #[inline]
+    pub fn write(&self, index: usize) -> LockResult<SharedMutexWriteGuard<T>> {
+        self.get(index).write()
+    }
+
+    /// Read-lock all parts, then run `action` on each of them with its index.
+    ///
+    /// Locks are always taken in index order, so concurrent bulk operations
+    /// don't deadlock, and `action` sees a consistent snapshot of all parts.
+    /// All locks are held until every part has been visited.
+    ///
+    /// `action` runs even if some of the locks are poisoned, in which case a
+    /// poisoned error is returned.
+    pub fn for_each_read<F>(&self, mut action: F) -> LockResult<()>
+    where F: FnMut(usize, &T) {
+        let mut poisoned = false;
+        let guards = self.locks.iter().map(|lock| {
+            let (guard, lock_poisoned) = read_poisoned(lock.borrow().read());
+            poisoned |= lock_poisoned;
+            guard
+        }).collect::<Vec<_>>();
+
+        for (index, guard) in guards.iter().enumerate() { action(index, guard) }
+
+        if poisoned { Err(PoisonError::new(())) } else { Ok(()) }
+    }
+
+    /// Write-lock all parts, then run `action` on each of them with its index.
+    ///
+    /// See `for_each_read` for more information.
+    pub fn for_each_write<F>(&self, mut action: F) -> LockResult<()>
+    where F: FnMut(usize, &mut T) {
+        let mut poisoned = false;
+        let mut guards = self.locks.iter().map(|lock| {
+            let (guard, lock_poisoned) = read_poisoned(lock.borrow().write());
+            poisoned |= lock_poisoned;
+            guard
+        }).collect::<Vec<_>>();
+
+        for (index, guard) in guards.iter_mut().enumerate() { action(index, guard) }
+
+        if poisoned { Err(PoisonError::new(())) } else { Ok(()) }
+    }
+}
+
+impl<T, L: From<SharedMutex<T>>> From<Vec<T>> for SharedMutexVec<T, L> {
+    #[inline]
+    fn from(values: Vec<T>) -> Self { SharedMutexVec::from_values(values) }
+}
+
+impl<T: fmt::Debug, L: Borrow<SharedMutex<T>>> fmt::Debug for SharedMutexVec<T, L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.locks.iter().map(Borrow::<SharedMutex<T>>::borrow)).finish()
+    }
+}